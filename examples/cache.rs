@@ -1,3 +1,5 @@
+// the DagCbor derive of Node relies on the never type falling back to ()
+#![allow(dependency_on_unit_never_type_fallback)]
 use ipfs_sqlite_block_store::{
     cache::{AsyncCacheTracker, Spawner, SqliteCacheTracker},
     BlockStore, Config,
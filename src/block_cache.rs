@@ -0,0 +1,115 @@
+use hashlink::LinkedHashMap;
+use libipld::Cid;
+use parking_lot::Mutex;
+use std::{fmt, sync::Arc};
+
+/// the payloads of the most recently read blocks, see
+/// [`with_block_cache_size`](crate::Config::with_block_cache_size)
+///
+/// Shared by all handles to the same database file within this process, like the events.
+#[derive(Clone, Default)]
+pub(crate) struct BlockCache(Option<Arc<Mutex<Lru>>>);
+
+struct Lru {
+    // least recently used first
+    entries: LinkedHashMap<Cid, (i64, Arc<[u8]>)>,
+    size: u64,
+    budget: u64,
+    // bumped whenever blocks are removed, see `generation`
+    generation: u64,
+}
+
+impl fmt::Debug for BlockCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut f = f.debug_struct("BlockCache");
+        if let Some(lru) = &self.0 {
+            let lru = lru.lock();
+            f.field("blocks", &lru.entries.len())
+                .field("size", &lru.size)
+                .field("budget", &lru.budget);
+        }
+        f.finish()
+    }
+}
+
+impl BlockCache {
+    /// a cache holding at most `budget` bytes of block data, or none at all for 0
+    pub(crate) fn new(budget: u64) -> Self {
+        Self((budget > 0).then(|| {
+            Arc::new(Mutex::new(Lru {
+                entries: LinkedHashMap::new(),
+                size: 0,
+                budget,
+                generation: 0,
+            }))
+        }))
+    }
+
+    /// the id and data of a cached block, marking it as recently used
+    pub(crate) fn get(&self, cid: &Cid) -> Option<(i64, Arc<[u8]>)> {
+        let mut lru = self.0.as_ref()?.lock();
+        lru.entries.to_back(cid).cloned()
+    }
+
+    /// to be taken before reading a block, and passed to [`insert`](Self::insert)
+    ///
+    /// This way a block deleted by another connection while it was read is not cached.
+    pub(crate) fn generation(&self) -> u64 {
+        self.0
+            .as_ref()
+            .map(|lru| lru.lock().generation)
+            .unwrap_or_default()
+    }
+
+    /// add a block that was just read, evicting the least recently used ones to stay in budget
+    ///
+    /// Nothing is added if blocks were removed since `generation`.
+    pub(crate) fn insert(&self, cid: Cid, id: i64, data: &[u8], generation: u64) {
+        let mut lru = match &self.0 {
+            Some(lru) => lru.lock(),
+            None => return,
+        };
+        if lru.generation != generation {
+            return;
+        }
+        let len = data.len() as u64;
+        // a block this large would evict everything else
+        if len > lru.budget / 2 {
+            return;
+        }
+        if let Some((_, old)) = lru.entries.insert(cid, (id, data.into())) {
+            lru.size -= old.len() as u64;
+        }
+        lru.size += len;
+        while lru.size > lru.budget {
+            match lru.entries.pop_front() {
+                Some((_, (_, data))) => lru.size -= data.len() as u64,
+                None => break,
+            }
+        }
+    }
+
+    /// forget blocks whose data has been deleted
+    pub(crate) fn remove<'a>(&self, cids: impl IntoIterator<Item = &'a Cid>) {
+        let mut lru = match &self.0 {
+            Some(lru) => lru.lock(),
+            None => return,
+        };
+        lru.generation += 1;
+        for cid in cids {
+            if let Some((_, data)) = lru.entries.remove(cid) {
+                lru.size -= data.len() as u64;
+            }
+        }
+    }
+
+    /// forget everything, e.g. after the database file was replaced
+    pub(crate) fn clear(&self) {
+        if let Some(lru) = &self.0 {
+            let mut lru = lru.lock();
+            lru.generation += 1;
+            lru.entries.clear();
+            lru.size = 0;
+        }
+    }
+}
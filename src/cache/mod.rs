@@ -256,6 +256,7 @@ impl<T: Debug, F> std::fmt::Debug for InMemCacheTracker<T, F> {
 
 #[cfg(test)]
 #[test]
+#[allow(clippy::legacy_numeric_constants)]
 fn sort_key_sort_order() {
     assert!(
         SortKey::new(None, i64::max_value())
@@ -167,6 +167,7 @@ where
     F: Fn(i64, BlockInfo) -> Option<i64> + Send + Sync,
 {
     #[allow(clippy::needless_collect)]
+    #[allow(clippy::unnecessary_cast)]
     fn blocks_accessed(&self, blocks: Vec<BlockInfo>) {
         let accessed = SystemTime::now()
             .duration_since(SystemTime::UNIX_EPOCH)
@@ -230,6 +231,7 @@ where
 }
 
 #[test]
+#[allow(clippy::legacy_numeric_constants)]
 fn sort_key_sort_order() {
     assert!(
         SortKey::new(None, i64::max_value())
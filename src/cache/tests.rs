@@ -3,6 +3,7 @@ use std::time::Duration;
 
 #[cfg(test)]
 #[test]
+#[allow(clippy::legacy_numeric_constants)]
 fn sort_key_sort_order() {
     assert!(
         SortKey::new(None, i64::max_value())
@@ -0,0 +1,200 @@
+use crate::Result;
+use fnv::FnvHashMap;
+use libipld::Cid;
+use std::{
+    fmt,
+    io::{self, Read, Seek, SeekFrom},
+};
+
+/// the first bytes of every CARv2 file
+const PRAGMA: [u8; 11] = [
+    0x0a, 0xa1, 0x67, 0x76, 0x65, 0x72, 0x73, 0x69, 0x6f, 0x6e, 0x02,
+];
+/// multicodec of an index keyed by digest only
+const INDEX_SORTED: u64 = 0x0400;
+/// multicodec of an index keyed by multihash code and digest
+const MULTIHASH_INDEX_SORTED: u64 = 0x0401;
+
+/// The index of a CARv2 file, for [`hydrate`](crate::BlockStore::hydrate)ing dags from it
+///
+/// Only the cid offsets are read, so a dataset can be mounted by aliasing its root and
+/// fetching blocks from the CAR data as they are needed, without ingesting it up front. The
+/// index is kept in memory; the store only learns about the blocks once they are fetched,
+/// together with their links.
+pub struct CarIndex {
+    data_offset: u64,
+    data_size: u64,
+    // keyed by multihash code and digest, or digest only for indexes lacking the code
+    offsets: FnvHashMap<(Option<u64>, Vec<u8>), u64>,
+}
+
+impl fmt::Debug for CarIndex {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CarIndex")
+            .field("data_offset", &self.data_offset)
+            .field("data_size", &self.data_size)
+            .field("entries", &self.offsets.len())
+            .finish()
+    }
+}
+
+impl CarIndex {
+    /// Read the index of a CARv2 file
+    ///
+    /// The blocks are then [`fetch`](Self::fetch)ed from the same file.
+    pub fn from_car<R: Read + Seek>(mut car: R) -> Result<Self> {
+        let mut pragma = [0u8; 11];
+        car.read_exact(&mut pragma).map_err(io_err)?;
+        if pragma != PRAGMA {
+            return Err(anyhow::anyhow!("not a CARv2 file").into());
+        }
+        // characteristics, then the data offset, data size and index offset
+        let mut header = [0u8; 40];
+        car.read_exact(&mut header).map_err(io_err)?;
+        let field = |i: usize| {
+            let mut buf = [0u8; 8];
+            buf.copy_from_slice(&header[16 + i * 8..24 + i * 8]);
+            u64::from_le_bytes(buf)
+        };
+        let (data_offset, data_size, index_offset) = (field(0), field(1), field(2));
+        if index_offset == 0 {
+            return Err(anyhow::anyhow!("CARv2 file has no index").into());
+        }
+        car.seek(SeekFrom::Start(index_offset)).map_err(io_err)?;
+        let mut index = Self::from_index(car)?;
+        index.data_offset = data_offset;
+        index.data_size = data_size;
+        Ok(index)
+    }
+
+    /// Read an index-only file, as written separately from the CAR data
+    ///
+    /// The blocks are then [`fetch`](Self::fetch)ed from the CARv1 data the index was built
+    /// for, since its offsets are relative to the start of that data.
+    pub fn from_index<R: Read>(mut index: R) -> Result<Self> {
+        let mut offsets = FnvHashMap::default();
+        match read_varint(&mut index)? {
+            INDEX_SORTED => read_buckets(&mut index, None, &mut offsets)?,
+            MULTIHASH_INDEX_SORTED => {
+                for _ in 0..read_u32(&mut index)? {
+                    let code = read_u64(&mut index)?;
+                    read_buckets(&mut index, Some(code), &mut offsets)?;
+                }
+            }
+            codec => {
+                return Err(anyhow::anyhow!("unsupported CAR index codec 0x{:x}", codec).into())
+            }
+        }
+        Ok(Self {
+            data_offset: 0,
+            data_size: u64::MAX,
+            offsets,
+        })
+    }
+
+    /// Number of indexed blocks
+    pub fn len(&self) -> usize {
+        self.offsets.len()
+    }
+
+    /// Whether the index is empty
+    pub fn is_empty(&self) -> bool {
+        self.offsets.is_empty()
+    }
+
+    /// Whether the index has an entry for `cid`
+    pub fn contains(&self, cid: &Cid) -> bool {
+        self.offset(cid).is_some()
+    }
+
+    fn offset(&self, cid: &Cid) -> Option<u64> {
+        let mut key = (Some(cid.hash().code()), cid.hash().digest().to_vec());
+        if let Some(offset) = self.offsets.get(&key) {
+            return Some(*offset);
+        }
+        key.0 = None;
+        self.offsets.get(&key).copied()
+    }
+
+    /// Read the data of `cid` from the CAR data, or `None` if it is not indexed
+    ///
+    /// `car` is the CARv2 file for an index read with [`from_car`](Self::from_car), or the
+    /// CARv1 data for one read with [`from_index`](Self::from_index). The data are not checked
+    /// against the cid, [`hydrate`](crate::BlockStore::hydrate) does that before storing them.
+    pub fn fetch<R: Read + Seek>(&self, mut car: R, cid: &Cid) -> Result<Option<Vec<u8>>> {
+        let offset = match self.offset(cid) {
+            Some(offset) => offset,
+            None => return Ok(None),
+        };
+        if offset >= self.data_size {
+            return Err(anyhow::anyhow!("CAR index offset {} out of bounds", offset).into());
+        }
+        car.seek(SeekFrom::Start(self.data_offset + offset))
+            .map_err(io_err)?;
+        let len = read_varint(&mut car)?;
+        if len > self.data_size - offset {
+            return Err(anyhow::anyhow!("CAR section of {} bytes out of bounds", len).into());
+        }
+        let mut section = car.take(len);
+        let found = Cid::read_bytes(&mut section).map_err(|e| anyhow::anyhow!(e))?;
+        if found.hash() != cid.hash() {
+            return Err(anyhow::anyhow!("CAR index points to {} instead of {}", found, cid).into());
+        }
+        let mut data = Vec::new();
+        section.read_to_end(&mut data).map_err(io_err)?;
+        if section.limit() > 0 {
+            return Err(io_err(io::ErrorKind::UnexpectedEof.into()));
+        }
+        Ok(Some(data))
+    }
+}
+
+/// the width buckets of an `IndexSorted` index, each entry a digest and a 64 bit offset
+fn read_buckets(
+    r: &mut impl Read,
+    code: Option<u64>,
+    offsets: &mut FnvHashMap<(Option<u64>, Vec<u8>), u64>,
+) -> Result<()> {
+    for _ in 0..read_u32(r)? {
+        let width = u64::from(read_u32(r)?);
+        let size = read_u64(r)?;
+        if width <= 8 || size % width != 0 {
+            return Err(anyhow::anyhow!("invalid CAR index bucket width {}", width).into());
+        }
+        for _ in 0..size / width {
+            let mut digest = vec![0u8; (width - 8) as usize];
+            r.read_exact(&mut digest).map_err(io_err)?;
+            offsets.insert((code, digest), read_u64(r)?);
+        }
+    }
+    Ok(())
+}
+
+fn read_u32(r: &mut impl Read) -> Result<u32> {
+    let mut buf = [0u8; 4];
+    r.read_exact(&mut buf).map_err(io_err)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_u64(r: &mut impl Read) -> Result<u64> {
+    let mut buf = [0u8; 8];
+    r.read_exact(&mut buf).map_err(io_err)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+fn read_varint(r: &mut impl Read) -> Result<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let mut byte = [0u8];
+        r.read_exact(&mut byte).map_err(io_err)?;
+        value |= u64::from(byte[0] & 0x7f) << shift;
+        if byte[0] & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(anyhow::anyhow!("varint too long").into())
+}
+
+fn io_err(e: io::Error) -> crate::BlockStoreError {
+    anyhow::Error::new(e).context("reading CAR").into()
+}
@@ -6,8 +6,10 @@ use rusqlite::{
 };
 use std::{convert::TryFrom, io::Cursor};
 
-/// This is sufficient for 32 byte hashes like sha2-256, but not for exotic hashes.
-const MAX_SIZE: usize = 39;
+/// Enough for any cid whose digest fits the 64 bytes of libipld's `Multihash`: the version,
+/// codec and hash code varints, the digest size and the digest. So the encoded cid may be
+/// longer than 64 bytes, unlike for `SqlCid`.
+const MAX_SIZE: usize = 1 + 10 + 10 + 1 + 64;
 
 /// a representation of a cid that implements AsRef<[u8]>
 #[derive(Clone, Copy, Debug, Hash, PartialEq, Eq, PartialOrd, Ord)]
@@ -60,7 +62,7 @@ impl TryFrom<&[u8]> for CidBytes {
 
     fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
         let mut res = CidBytes::default();
-        if value.len() < 64 {
+        if value.len() <= MAX_SIZE {
             res.size = value.len() as u8;
             res.data[0..value.len()].copy_from_slice(value);
             Ok(res)
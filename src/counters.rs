@@ -0,0 +1,92 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Counts of the operations on a store since it was opened, see
+/// [`counters`](crate::BlockStore::counters)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Counters {
+    pub(crate) block_reads: u64,
+    pub(crate) read_misses: u64,
+    pub(crate) block_cache_hits: u64,
+    pub(crate) missing_cache_hits: u64,
+    pub(crate) block_writes: u64,
+    pub(crate) dedup_hits: u64,
+    pub(crate) gc_runs: u64,
+    pub(crate) blocks_deleted: u64,
+}
+
+impl Counters {
+    /// Number of blocks whose data was read
+    pub fn block_reads(&self) -> u64 {
+        self.block_reads
+    }
+
+    /// Number of reads of a block whose data is not in the store
+    pub fn read_misses(&self) -> u64 {
+        self.read_misses
+    }
+
+    /// Number of block reads served from the [block cache](crate::Config::with_block_cache_size)
+    pub fn block_cache_hits(&self) -> u64 {
+        self.block_cache_hits
+    }
+
+    /// Number of [`has_block`](crate::BlockStore::has_block) calls answered by the
+    /// [missing cache](crate::Config::with_missing_cache)
+    pub fn missing_cache_hits(&self) -> u64 {
+        self.missing_cache_hits
+    }
+
+    /// Number of blocks whose data was added
+    pub fn block_writes(&self) -> u64 {
+        self.block_writes
+    }
+
+    /// Number of puts of blocks whose data was already in the store
+    pub fn dedup_hits(&self) -> u64 {
+        self.dedup_hits
+    }
+
+    /// Number of full or incremental gc runs
+    pub fn gc_runs(&self) -> u64 {
+        self.gc_runs
+    }
+
+    /// Number of blocks deleted by gc or a purge
+    pub fn blocks_deleted(&self) -> u64 {
+        self.blocks_deleted
+    }
+}
+
+/// the live counters, shared by all connections to the same store within this process
+#[derive(Debug, Default)]
+pub(crate) struct AtomicCounters {
+    pub(crate) block_reads: AtomicU64,
+    pub(crate) read_misses: AtomicU64,
+    pub(crate) block_cache_hits: AtomicU64,
+    pub(crate) missing_cache_hits: AtomicU64,
+    pub(crate) block_writes: AtomicU64,
+    pub(crate) dedup_hits: AtomicU64,
+    pub(crate) gc_runs: AtomicU64,
+    pub(crate) blocks_deleted: AtomicU64,
+}
+
+impl AtomicCounters {
+    pub(crate) fn add(counter: &AtomicU64, n: u64) {
+        // the counters are independent of each other, so no ordering is needed
+        counter.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub(crate) fn get(&self) -> Counters {
+        let get = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        Counters {
+            block_reads: get(&self.block_reads),
+            read_misses: get(&self.read_misses),
+            block_cache_hits: get(&self.block_cache_hits),
+            missing_cache_hits: get(&self.missing_cache_hits),
+            block_writes: get(&self.block_writes),
+            dedup_hits: get(&self.dedup_hits),
+            gc_runs: get(&self.gc_runs),
+            blocks_deleted: get(&self.blocks_deleted),
+        }
+    }
+}
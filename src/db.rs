@@ -6,11 +6,20 @@
 //! Tables:
 //! cids: mapping from cid (blob < 64 bytes) to id (u64)
 //! refs: m:n mapping from block ids to their children
+//! ref_counts: how often a child is linked from a parent, only for links that occur more than once
+//! pending_refs: children of cids without data, recorded ahead of the data and replaced by refs
+//!    once the data arrives
 //! blocks: the actual data for blocks, keyed by block id
 //!    cids can exist in the system without having data associated with them!
+//! block_seq: the order in which blocks were added, for incremental consumers
+//! changes: append-only log of puts, deletes and alias changes with a sequence number, for
+//!    replication and cache invalidation. Cids are stored as bytes, so entries outlive the blocks
+//! block_cid_info: the parts of the cid of each block, for finding a block regardless of cid
+//!    version and codec, and for statistics per codec
 //! alias: table that contains named pins for roots of graphs that should not be deleted by gc
 //!    you can alias incomplete or in fact non-existing data. It is not necessary for a pinned dag
 //!    to be complete.
+use fnv::FnvHashMap;
 use libipld::{Cid, DefaultParams};
 use rusqlite::{
     config::DbConfig,
@@ -18,12 +27,14 @@ use rusqlite::{
     types::FromSql,
     Connection,
     Error::{QueryReturnedNoRows, SqliteFailure},
-    ErrorCode::DatabaseBusy,
+    ErrorCode::{DatabaseBusy, DatabaseLocked, DiskFull, OperationInterrupted},
     OptionalExtension, ToSql, Transaction, TransactionBehavior,
 };
 use std::{
-    collections::{BTreeSet, HashSet},
+    collections::{BTreeSet, HashSet, VecDeque},
     convert::TryFrom,
+    path::Path,
+    sync::Arc,
     time::Duration,
     time::Instant,
 };
@@ -32,14 +43,13 @@ use crate::{
     cache::{BlockInfo, CacheTracker},
     cidbytes::CidBytes,
     error::Context,
-    BlockStoreError, SizeTargets, StoreStats, Synchronous,
+    BlockStat, BlockStoreError, CodecStats, ConstraintReport, DagStats, GcFilter, Limit, PinMode,
+    SizeTargets, StoreStats, StoreSummary, Synchronous,
 };
 use anyhow::Context as _;
 use itertools::Itertools;
 
 const PRAGMAS: &str = r#"
--- this must be done before creating the first table, otherwise it has no effect
-PRAGMA auto_vacuum = 2;
 -- this must be done before changing the database via the CLI!
 PRAGMA foreign_keys = ON;
 PRAGMA journal_mode = WAL;
@@ -72,6 +82,35 @@ const TABLES: &[(&str, &str)] = &[
               ON DELETE RESTRICT \
         )",
     ),
+    (
+        "ref_counts",
+        "CREATE TABLE ref_counts ( \
+            parent_id INTEGER NOT NULL, \
+            child_id INTEGER NOT NULL, \
+            count INTEGER NOT NULL, \
+            PRIMARY KEY(parent_id,child_id) \
+            CONSTRAINT fk_ref \
+              FOREIGN KEY (parent_id, child_id) \
+              REFERENCES refs(parent_id, child_id) \
+              ON DELETE CASCADE \
+        )",
+    ),
+    (
+        "pending_refs",
+        "CREATE TABLE pending_refs ( \
+            parent_id INTEGER NOT NULL, \
+            child_id INTEGER NOT NULL, \
+            PRIMARY KEY(parent_id,child_id) \
+            CONSTRAINT fk_parent_id \
+              FOREIGN KEY (parent_id) \
+              REFERENCES cids(id) \
+              ON DELETE CASCADE \
+            CONSTRAINT fk_child_id \
+              FOREIGN KEY (child_id) \
+              REFERENCES cids(id) \
+              ON DELETE RESTRICT \
+        )",
+    ),
     (
         "blocks",
         "CREATE TABLE blocks ( \
@@ -94,6 +133,21 @@ const TABLES: &[(&str, &str)] = &[
               ON DELETE CASCADE \
         )",
     ),
+    (
+        "alias_info",
+        "CREATE TABLE alias_info ( \
+            name blob NOT NULL PRIMARY KEY, \
+            created INTEGER NOT NULL, \
+            updated INTEGER NOT NULL, \
+            metadata BLOB, \
+            max_depth INTEGER, \
+            CONSTRAINT fk_name \
+              FOREIGN KEY (name) \
+              REFERENCES aliases(name) \
+              ON DELETE CASCADE \
+              ON UPDATE CASCADE \
+        )",
+    ),
     (
         "temp_pins",
         "CREATE TABLE temp_pins ( \
@@ -106,6 +160,65 @@ const TABLES: &[(&str, &str)] = &[
               ON DELETE RESTRICT \
         )",
     ),
+    (
+        "temp_pin_sessions",
+        "CREATE TABLE temp_pin_sessions ( \
+            id INTEGER PRIMARY KEY, \
+            session INTEGER NOT NULL \
+        )",
+    ),
+    (
+        "block_times",
+        "CREATE TABLE block_times ( \
+            block_id INTEGER PRIMARY KEY, \
+            added INTEGER NOT NULL, \
+            CONSTRAINT fk_block_id \
+              FOREIGN KEY (block_id) \
+              REFERENCES blocks(block_id) \
+              ON DELETE CASCADE \
+        )",
+    ),
+    (
+        "block_seq",
+        "CREATE TABLE block_seq ( \
+            seq INTEGER PRIMARY KEY AUTOINCREMENT, \
+            block_id INTEGER UNIQUE NOT NULL, \
+            CONSTRAINT fk_block_id \
+              FOREIGN KEY (block_id) \
+              REFERENCES blocks(block_id) \
+              ON DELETE CASCADE \
+        )",
+    ),
+    (
+        "block_cid_info",
+        "CREATE TABLE block_cid_info ( \
+            block_id INTEGER PRIMARY KEY, \
+            multihash BLOB NOT NULL, \
+            codec INTEGER NOT NULL, \
+            hash_code INTEGER NOT NULL, \
+            CONSTRAINT fk_block_id \
+              FOREIGN KEY (block_id) \
+              REFERENCES blocks(block_id) \
+              ON DELETE CASCADE \
+        )",
+    ),
+    (
+        "changes",
+        "CREATE TABLE changes ( \
+            seq INTEGER PRIMARY KEY AUTOINCREMENT, \
+            op INTEGER NOT NULL, \
+            cid BLOB, \
+            name BLOB, \
+            time INTEGER NOT NULL \
+        )",
+    ),
+    (
+        "store_id",
+        "CREATE TABLE store_id ( \
+            id INTEGER PRIMARY KEY CHECK (id = 0), \
+            uuid TEXT NOT NULL \
+        )",
+    ),
     (
         "stats",
         "CREATE TABLE stats ( \
@@ -115,23 +228,42 @@ const TABLES: &[(&str, &str)] = &[
     ),
 ];
 
+/// the version of the schema created by [`init_db`], stored as the user_version of the DB
+pub(crate) const SCHEMA_VERSION: u32 = 3;
+
+// the user_version must match SCHEMA_VERSION
 const INIT: &str = r#"
-PRAGMA user_version = 2;
+PRAGMA user_version = 3;
 
 CREATE INDEX IF NOT EXISTS idx_refs_child_id
 ON refs (child_id);
 
+CREATE INDEX IF NOT EXISTS idx_pending_refs_child_id
+ON pending_refs (child_id);
+
 CREATE INDEX IF NOT EXISTS idx_aliases_block_id
 ON aliases (block_id);
 
+CREATE INDEX IF NOT EXISTS idx_block_cid_info_multihash
+ON block_cid_info (multihash);
+
+CREATE INDEX IF NOT EXISTS idx_block_cid_info_codec
+ON block_cid_info (codec);
+
 CREATE INDEX IF NOT EXISTS idx_temp_pins_block_id
 ON temp_pins (block_id);
 "#;
 
-const CLEANUP_TEMP_PINS: &str = r#"
--- delete temp aliases that were not dropped because of crash
-DELETE FROM temp_pins;
-"#;
+/// fail if the DB was created by a newer version, for connections that do not run [`init_db`]
+pub(crate) fn check_schema_version(conn: &Connection) -> crate::Result<()> {
+    let version: u32 = conn
+        .pragma_query_value(None, "user_version", |row| row.get(0))
+        .ctx("getting user_version")?;
+    if version > SCHEMA_VERSION {
+        return Err(BlockStoreError::UnsupportedSchemaVersion(version));
+    }
+    Ok(())
+}
 
 fn user_version(txn: &Transaction) -> rusqlite::Result<u32> {
     Ok(txn
@@ -231,6 +363,66 @@ pub(crate) fn recompute_store_stats(conn: &mut Connection) -> crate::Result<()>
     Ok(())
 }
 
+/// check foreign keys, temp pins and store stats, optionally deleting offending rows and fixing
+/// the stats
+pub(crate) fn audit_constraints(
+    conn: &mut Connection,
+    fix: bool,
+) -> crate::Result<ConstraintReport> {
+    let _span = tracing::debug_span!("audit constraints", fix).entered();
+    in_txn(
+        conn,
+        Some(("audit constraints", Duration::from_secs(1))),
+        fix,
+        move |txn| {
+            let mut report = ConstraintReport::default();
+            let violations = c!("checking foreign keys" => txn
+            .prepare("SELECT \"table\", rowid FROM pragma_foreign_key_check")
+            .and_then(|mut stmt| {
+                stmt.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, Option<i64>>(1)?)))?
+                    .collect::<rusqlite::Result<Vec<_>>>()
+            }));
+            for (table, _) in violations.iter() {
+                *report
+                    .foreign_key_violations
+                    .entry(table.clone())
+                    .or_default() += 1;
+            }
+            // temp pins whose session row is gone can no longer be cleaned up by their session
+            report.leaked_temp_pins = c!("counting leaked temp_pins" => txn.query_row(
+                "SELECT COUNT(DISTINCT id) FROM temp_pins \
+                WHERE id NOT IN (SELECT id FROM temp_pin_sessions)",
+                [],
+                |row| row.get::<_, i64>(0),
+            )) as u64;
+            let stats = BlockStats::from(get_store_stats(txn)?);
+            if fix {
+                c!("deleting leaked temp_pins" => txn.execute(
+                    "DELETE FROM temp_pins WHERE id NOT IN (SELECT id FROM temp_pin_sessions)",
+                    [],
+                ));
+                for (table, rowid) in violations {
+                    // the table name has been reported by sqlite, but better be safe
+                    if let (Some(rowid), true) = (rowid, TABLES.iter().any(|(t, _)| *t == table)) {
+                        tracing::info!(%table, rowid, "deleting row violating foreign key");
+                        c!("deleting violating row" => txn
+                            .execute(&format!("DELETE FROM \"{}\" WHERE rowid = ?", table), [rowid]));
+                    }
+                }
+            }
+            let truth = compute_store_stats(txn)?;
+            report.stats_mismatch = stats != truth;
+            if fix && report.stats_mismatch {
+                tracing::info!("correcting usage stats from {:?} to {:?}", stats, truth);
+                c!("updating stats" => txn
+                    .execute("UPDATE stats SET count = ?, size = ?", [truth.count, truth.size]));
+            }
+            report.fixed = fix && !report.is_clean();
+            Ok(report)
+        },
+    )
+}
+
 /// returns the number and size of blocks, excluding orphaned blocks, from the stats table
 pub(crate) fn get_store_stats(txn: &Transaction) -> crate::Result<StoreStats> {
     let (count, size): (i64, i64) = txn
@@ -257,11 +449,599 @@ pub(crate) fn get_store_stats(txn: &Transaction) -> crate::Result<StoreStats> {
     Ok(result)
 }
 
+/// count everything that is cheap enough to count in one go
+pub(crate) fn get_store_summary(txn: &Transaction) -> crate::Result<StoreSummary> {
+    let stats = get_store_stats(txn)?;
+    let (cids, aliases, temp_pins): (i64, i64, i64) = c!("counting store contents" => txn.query_row(
+        "SELECT (SELECT COUNT(*) FROM cids), (SELECT COUNT(*) FROM aliases), \
+        (SELECT COUNT(DISTINCT id) FROM temp_pins)",
+        [],
+        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+    ));
+    Ok(StoreSummary {
+        blocks: stats.count(),
+        bytes: stats.size(),
+        cids: cids as u64,
+        orphans: count_orphaned(txn)?,
+        aliases: aliases as u64,
+        temp_pins: temp_pins as u64,
+    })
+}
+
 fn get_or_create_id(txn: &Transaction, cid: impl ToSql) -> rusqlite::Result<i64> {
+    // no RETURNING, since the sqlite bundled with SQLCipher is too old for it
+    let inserted = txn
+        .prepare_cached("INSERT OR IGNORE INTO cids (cid) VALUES (?)")?
+        .execute([&cid])?;
+    if inserted > 0 {
+        Ok(txn.last_insert_rowid())
+    } else {
+        txn.prepare_cached("SELECT id FROM cids WHERE cid = ?")?
+            .query_row([cid], |row| row.get(0))
+    }
+}
+
+/// bound on the number of ids kept by an [`IdCache`]
+const MAX_CACHED_IDS: usize = 1 << 16;
+
+/// the ids of the cids used by earlier write transactions, to save looking them up again
+///
+/// An id stays valid until its cid is deleted, after which it may even be given to another cid,
+/// since stores from before the cids table used AUTOINCREMENT still lack it. Other connections
+/// may delete cids, so the cache is dropped whenever `PRAGMA data_version` shows that they have
+/// committed something. Cids deleted on the same connection go unnoticed, so the cache must only
+/// be kept while nothing deletes cids, like over the lifetime of a
+/// [`Transaction`](crate::Transaction).
+#[derive(Debug, Clone, Default)]
+pub(crate) struct IdCache {
+    data_version: i64,
+    ids: Arc<FnvHashMap<CidBytes, i64>>,
+}
+
+impl IdCache {
+    /// start using the cache in a new transaction, see [`update`](Self::update)
+    pub(crate) fn begin(&self, txn: &Transaction) -> crate::Result<Ids> {
+        let data_version: i64 = c!("getting data version" =>
+            txn.query_row("PRAGMA data_version", [], |row| row.get(0)));
+        Ok(Ids {
+            cached: (data_version == self.data_version && !self.ids.is_empty())
+                .then(|| self.ids.clone()),
+            new: FnvHashMap::default(),
+            data_version,
+        })
+    }
+
+    /// add the ids resolved by a committed transaction
+    pub(crate) fn update(&mut self, ids: Ids) {
+        // cached holds a reference, which would make the update copy the whole map
+        let Ids {
+            cached,
+            new,
+            data_version,
+        } = ids;
+        drop(cached);
+        if data_version != self.data_version || self.ids.len() + new.len() > MAX_CACHED_IDS {
+            self.data_version = data_version;
+            self.ids = Default::default();
+        }
+        Arc::make_mut(&mut self.ids).extend(new);
+    }
+}
+
+/// the ids resolved within one transaction, optionally backed by an [`IdCache`]
+pub(crate) struct Ids {
+    cached: Option<Arc<FnvHashMap<CidBytes, i64>>>,
+    new: FnvHashMap<CidBytes, i64>,
+    data_version: i64,
+}
+
+impl Ids {
+    /// for transactions that do not use an [`IdCache`]
+    pub(crate) fn uncached() -> Self {
+        Self {
+            cached: None,
+            new: FnvHashMap::default(),
+            data_version: 0,
+        }
+    }
+
+    fn get_or_create(&mut self, txn: &Transaction, cid: &CidBytes) -> rusqlite::Result<i64> {
+        let cached = self.cached.as_ref().and_then(|ids| ids.get(cid));
+        if let Some(id) = cached.or_else(|| self.new.get(cid)) {
+            return Ok(*id);
+        }
+        let id = get_or_create_id(txn, cid)?;
+        if self.new.len() >= MAX_CACHED_IDS {
+            self.new.clear();
+        }
+        self.new.insert(*cid, id);
+        Ok(id)
+    }
+}
+
+/// the tables and triggers for keeping the gc marks up to date, see [`set_gc_marks`]
+///
+/// `gc_marks` holds ids that are reachable from a recursive alias or a temp pin, together with
+/// the generation of the update that marked them. The triggers queue the ids that may have
+/// become reachable in `gc_marks_pending`, and count the changes after which some marks may no
+/// longer be reachable in `gc_marks_state`.
+const GC_MARKS: &str = r#"
+CREATE TABLE IF NOT EXISTS gc_marks (
+    id INTEGER PRIMARY KEY,
+    generation INTEGER NOT NULL,
+    CONSTRAINT fk_id
+      FOREIGN KEY (id)
+      REFERENCES cids(id)
+      ON DELETE CASCADE
+);
+
+CREATE TABLE IF NOT EXISTS gc_marks_pending (
+    id INTEGER PRIMARY KEY
+);
+
+CREATE TABLE IF NOT EXISTS gc_marks_state (
+    id INTEGER PRIMARY KEY CHECK (id = 0),
+    stale INTEGER NOT NULL,
+    generation INTEGER NOT NULL
+);
+
+INSERT OR IGNORE INTO gc_marks_state (id, stale, generation) VALUES (0, 1, 0);
+
+CREATE TRIGGER IF NOT EXISTS gc_marks_alias_insert AFTER INSERT ON aliases BEGIN
+    INSERT OR IGNORE INTO gc_marks_pending (id) VALUES (NEW.block_id);
+END;
+
+CREATE TRIGGER IF NOT EXISTS gc_marks_alias_update AFTER UPDATE OF block_id ON aliases
+WHEN OLD.block_id != NEW.block_id BEGIN
+    INSERT OR IGNORE INTO gc_marks_pending (id) VALUES (NEW.block_id);
+    UPDATE gc_marks_state SET stale = stale + 1;
+END;
+
+CREATE TRIGGER IF NOT EXISTS gc_marks_alias_delete AFTER DELETE ON aliases BEGIN
+    UPDATE gc_marks_state SET stale = stale + 1;
+END;
+
+-- a depth limit only ever protects less than an unlimited alias
+CREATE TRIGGER IF NOT EXISTS gc_marks_depth_insert AFTER INSERT ON alias_info
+WHEN NEW.max_depth IS NOT NULL BEGIN
+    UPDATE gc_marks_state SET stale = stale + 1;
+END;
+
+CREATE TRIGGER IF NOT EXISTS gc_marks_depth_update AFTER UPDATE OF max_depth ON alias_info
+WHEN OLD.max_depth IS NOT NEW.max_depth BEGIN
+    INSERT OR IGNORE INTO gc_marks_pending (id)
+        SELECT block_id FROM aliases WHERE name = NEW.name AND NEW.max_depth IS NULL;
+    UPDATE gc_marks_state SET stale = stale + 1 WHERE NEW.max_depth IS NOT NULL;
+END;
+
+CREATE TRIGGER IF NOT EXISTS gc_marks_depth_delete AFTER DELETE ON alias_info
+WHEN OLD.max_depth IS NOT NULL BEGIN
+    INSERT OR IGNORE INTO gc_marks_pending (id) SELECT block_id FROM aliases WHERE name = OLD.name;
+END;
+
+CREATE TRIGGER IF NOT EXISTS gc_marks_temp_pin_insert AFTER INSERT ON temp_pins BEGIN
+    INSERT OR IGNORE INTO gc_marks_pending (id) VALUES (NEW.block_id);
+END;
+
+CREATE TRIGGER IF NOT EXISTS gc_marks_temp_pin_delete AFTER DELETE ON temp_pins BEGIN
+    UPDATE gc_marks_state SET stale = stale + 1;
+END;
+
+CREATE TRIGGER IF NOT EXISTS gc_marks_ref_insert AFTER INSERT ON refs
+WHEN NEW.parent_id IN (SELECT id FROM gc_marks) BEGIN
+    INSERT OR IGNORE INTO gc_marks_pending (id) VALUES (NEW.child_id);
+END;
+
+CREATE TRIGGER IF NOT EXISTS gc_marks_ref_delete AFTER DELETE ON refs
+WHEN OLD.parent_id IN (SELECT id FROM gc_marks) BEGIN
+    UPDATE gc_marks_state SET stale = stale + 1;
+END;
+"#;
+
+const DROP_GC_MARKS: &str = r#"
+DROP TRIGGER IF EXISTS gc_marks_alias_insert;
+DROP TRIGGER IF EXISTS gc_marks_alias_update;
+DROP TRIGGER IF EXISTS gc_marks_alias_delete;
+DROP TRIGGER IF EXISTS gc_marks_depth_insert;
+DROP TRIGGER IF EXISTS gc_marks_depth_update;
+DROP TRIGGER IF EXISTS gc_marks_depth_delete;
+DROP TRIGGER IF EXISTS gc_marks_temp_pin_insert;
+DROP TRIGGER IF EXISTS gc_marks_temp_pin_delete;
+DROP TRIGGER IF EXISTS gc_marks_ref_insert;
+DROP TRIGGER IF EXISTS gc_marks_ref_delete;
+DROP TABLE IF EXISTS gc_marks;
+DROP TABLE IF EXISTS gc_marks_pending;
+DROP TABLE IF EXISTS gc_marks_state;
+"#;
+
+/// install or remove the gc marks; newly installed marks are empty until [`rebuild_gc_marks`]
+pub(crate) fn set_gc_marks(txn: &Transaction, enabled: bool) -> crate::Result<()> {
+    if enabled {
+        c!("installing gc marks" => txn.execute_batch(GC_MARKS));
+    } else {
+        c!("removing gc marks" => txn.execute_batch(DROP_GC_MARKS));
+    }
+    Ok(())
+}
+
+/// returns whether [`rebuild_gc_marks`] may find fewer marks, or `None` without gc marks
+pub(crate) fn gc_marks_stale(txn: &Transaction) -> crate::Result<Option<bool>> {
+    Ok(gc_marks_state(txn)?.map(|(stale, _)| stale > 0))
+}
+
+/// the stale count and the current generation of the gc marks
+fn gc_marks_state(txn: &Transaction) -> crate::Result<Option<(i64, i64)>> {
+    if !c!("checking table `gc_marks_state`" => table_exists(txn, "gc_marks_state")) {
+        return Ok(None);
+    }
+    Ok(Some(c!("getting gc marks state" => txn.query_row(
+        "SELECT stale, generation FROM gc_marks_state",
+        [],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    ))))
+}
+
+/// mark the ids that became reachable since the marks were last updated
+///
+/// Already marked ids are not traversed again, so this takes time proportional to the number of
+/// newly reachable ids.
+fn update_gc_marks(txn: &Transaction) -> crate::Result<()> {
+    let _span = tracing::debug_span!("updating gc marks").entered();
+    c!("updating gc marks generation" =>
+        txn.execute_batch("UPDATE gc_marks_state SET generation = generation + 1"));
+    let marked = c!("updating gc marks" => txn.execute(
+        r#"
+        WITH RECURSIVE
+            -- the pending ids that are still pinned or linked from a marked block
+            roots(id) AS (
+                SELECT id FROM gc_marks_pending
+                WHERE id IN (SELECT block_id FROM temp_pins)
+                OR id IN (
+                    SELECT block_id FROM aliases LEFT JOIN alias_info USING (name)
+                        WHERE max_depth IS NULL
+                )
+                OR id IN (SELECT child_id FROM refs, gc_marks ON parent_id = gc_marks.id)
+            ),
+            -- the children of marked ids are marked or pending, so they need not be visited
+            reachable(id) AS (
+                SELECT id FROM roots WHERE id NOT IN (SELECT id FROM gc_marks)
+                UNION
+                SELECT child_id FROM refs, reachable ON reachable.id = parent_id
+                    WHERE child_id NOT IN (SELECT id FROM gc_marks)
+            )
+        INSERT OR IGNORE INTO gc_marks (id, generation)
+            SELECT id, (SELECT generation FROM gc_marks_state) FROM reachable
+        "#,
+        [],
+    ));
+    c!("clearing pending gc marks" => txn.execute_batch("DELETE FROM gc_marks_pending"));
+    tracing::debug!(marked, "gc marks updated");
+    Ok(())
+}
+
+/// mark the ids that became reachable, see [`update_gc_marks`], if the store has gc marks
+pub(crate) fn prepare_gc_marks(conn: &mut Connection) -> crate::Result<()> {
+    in_txn(
+        conn,
+        Some(("updating gc marks", Duration::from_secs(1))),
+        true,
+        |txn| match gc_marks_state(txn)? {
+            Some(_) => update_gc_marks(txn),
+            None => Ok(()),
+        },
+    )
+}
+
+/// compute the marks from scratch, dropping those that are no longer reachable
+///
+/// The reachable ids are computed in a read transaction, so writers are only blocked while
+/// the result is written. Marks added meanwhile belong to a later generation and are kept.
+pub(crate) fn rebuild_gc_marks(conn: &mut Connection) -> crate::Result<()> {
+    let _span = tracing::debug_span!("rebuilding gc marks").entered();
+    let (reachable, stale, generation) = in_txn(
+        conn,
+        Some(("computing gc marks", Duration::from_secs(3))),
+        false,
+        |txn| {
+            let (stale, generation) = match gc_marks_state(txn)? {
+                Some(state) => state,
+                None => return Ok((Vec::new(), 0, None)),
+            };
+            let reachable = txn
+                .prepare_cached(
+                    r#"
+                    WITH RECURSIVE
+                        reachable(id) AS (
+                            SELECT block_id FROM aliases LEFT JOIN alias_info USING (name)
+                                WHERE max_depth IS NULL
+                            UNION
+                            SELECT block_id FROM temp_pins
+                            UNION
+                            SELECT child_id FROM refs, reachable ON id = parent_id
+                        )
+                    SELECT id FROM reachable
+                    "#,
+                )
+                .ctx("computing gc marks (prep)")?
+                .query_map([], |row| row.get(0))
+                .ctx("computing gc marks")?
+                .collect::<rusqlite::Result<Vec<i64>>>()
+                .ctx("reading gc marks")?;
+            Ok((reachable, stale, Some(generation)))
+        },
+    )?;
+    let generation = match generation {
+        Some(generation) => generation,
+        None => return Ok(()),
+    };
+    in_txn(
+        conn,
+        Some(("writing gc marks", Duration::from_secs(1))),
+        true,
+        move |txn| {
+            c!("creating gc marks rebuild table" => txn.execute_batch(
+                "CREATE TEMP TABLE IF NOT EXISTS gc_marks_rebuild (id INTEGER PRIMARY KEY); \
+                DELETE FROM temp.gc_marks_rebuild;"
+            ));
+            {
+                let mut insert = txn
+                    .prepare_cached("INSERT OR IGNORE INTO temp.gc_marks_rebuild (id) VALUES (?)")
+                    .ctx("adding gc mark (prep)")?;
+                for id in &reachable {
+                    insert.execute([id]).ctx("adding gc mark")?;
+                }
+            }
+            let dropped = c!("dropping gc marks" => txn.execute(
+                "DELETE FROM gc_marks WHERE generation <= ? \
+                AND id NOT IN (SELECT id FROM temp.gc_marks_rebuild)",
+                [generation],
+            ));
+            // cids may have been deleted since the marks were computed
+            c!("writing gc marks" => txn.execute(
+                "INSERT OR IGNORE INTO gc_marks (id, generation) \
+                SELECT id, ? FROM temp.gc_marks_rebuild WHERE id IN (SELECT id FROM cids)",
+                [generation],
+            ));
+            c!("updating gc marks state" => txn.execute(
+                "UPDATE gc_marks_state SET stale = max(stale - ?, 0)",
+                [stale],
+            ));
+            c!("clearing gc marks rebuild table" =>
+                txn.execute_batch("DELETE FROM temp.gc_marks_rebuild"));
+            tracing::debug!(marked = reachable.len(), dropped, "gc marks rebuilt");
+            update_gc_marks(txn)
+        },
+    )
+}
+
+/// find all ids that are not pinned (directly or indirectly) and not younger than `grace_period`
+///
+/// The temp pins in `expired_pins` are treated as if they were already deleted.
+fn get_gc_candidates(
+    txn: &Transaction,
+    grace_period: Duration,
+    expired_pins: &[i64],
+) -> crate::Result<Vec<i64>> {
+    // a temp table avoids the limit on the number of parameters
+    c!("creating expired temp pins table" => txn.execute_batch(
+        "CREATE TEMP TABLE IF NOT EXISTS expired_temp_pins (id INTEGER PRIMARY KEY); \
+        DELETE FROM temp.expired_temp_pins;"
+    ));
+    if !expired_pins.is_empty() {
+        let mut insert = txn
+            .prepare_cached("INSERT OR IGNORE INTO temp.expired_temp_pins (id) VALUES (?)")
+            .ctx("adding expired temp pin (prep)")?;
+        for id in expired_pins {
+            insert.execute([id]).ctx("adding expired temp pin")?;
+        }
+    }
+    let mut id_query = txn
+        .prepare_cached(
+            r#"
+            WITH RECURSIVE
+                -- depth is the number of levels still protected below id, NULL for unlimited
+                descendant_of(id, depth) AS
+                (
+                    SELECT block_id, max_depth FROM aliases LEFT JOIN alias_info USING (name)
+                    UNION
+                    SELECT block_id, NULL FROM temp_pins
+                        WHERE id NOT IN (SELECT id FROM temp.expired_temp_pins)
+                    UNION
+                    SELECT child_id, depth - 1 FROM refs, descendant_of ON id = parent_id
+                        WHERE depth IS NULL OR depth > 0
+                )
+            SELECT id FROM cids
+            WHERE id NOT IN (SELECT id FROM descendant_of)
+            AND id NOT IN (SELECT block_id FROM block_times WHERE added > strftime('%s', 'now') - ?);
+            "#,
+        )
+        .ctx("finding GC blocks (prep)")?;
+    let ret = id_query
+        .query_map([grace_secs(grace_period)], |row| row.get(0))
+        .ctx("finding GC blocks")?
+        .collect::<rusqlite::Result<Vec<i64>>>()
+        .ctx("reading GC block ID")?;
+    Ok(ret)
+}
+
+/// like [`get_gc_candidates`], but only traversing from the pins that are not marked yet
+///
+/// Marks that are no longer reachable make this return fewer candidates than the full query,
+/// until the marks are rebuilt.
+fn get_marked_gc_candidates(txn: &Transaction, grace_period: Duration) -> crate::Result<Vec<i64>> {
     txn.prepare_cached(
-        "INSERT INTO cids (cid) VALUES (?) ON CONFLICT DO UPDATE SET cid=cid RETURNING id",
-    )?
-    .query_row([cid], |row| row.get(0))
+        r#"
+        WITH RECURSIVE
+            -- the aliases with a depth limit are not marked, since marks protect everything below
+            limited(id, depth) AS
+            (
+                SELECT block_id, max_depth FROM aliases JOIN alias_info USING (name)
+                    WHERE max_depth IS NOT NULL
+                UNION
+                SELECT child_id, depth - 1 FROM refs, limited ON id = parent_id
+                    WHERE depth > 0
+            ),
+            -- pins added since the marks were last updated
+            pending(id) AS
+            (
+                SELECT id FROM gc_marks_pending
+                UNION
+                SELECT child_id FROM refs, pending ON pending.id = parent_id
+                    WHERE child_id NOT IN (SELECT id FROM gc_marks)
+            )
+        SELECT id FROM cids
+        WHERE id NOT IN (SELECT id FROM gc_marks)
+        AND id NOT IN (SELECT id FROM pending)
+        AND id NOT IN (SELECT id FROM limited)
+        AND id NOT IN (SELECT block_id FROM block_times WHERE added > strftime('%s', 'now') - ?);
+        "#,
+    )
+    .ctx("finding marked GC blocks (prep)")?
+    .query_map([grace_secs(grace_period)], |row| row.get(0))
+    .ctx("finding marked GC blocks")?
+    .collect::<rusqlite::Result<Vec<i64>>>()
+    .ctx("reading marked GC block ID")
+}
+
+fn grace_secs(grace_period: Duration) -> i64 {
+    i64::try_from(grace_period.as_secs()).unwrap_or(i64::MAX)
+}
+
+/// find the blocks reachable from `root` that are not protected by any pin
+///
+/// with `force`, the aliases pointing directly at `root` are removed first and their names returned.
+pub(crate) fn get_purge_candidates(
+    txn: &Transaction,
+    root: impl ToSql,
+    force: bool,
+) -> crate::Result<(Vec<i64>, Vec<Vec<u8>>)> {
+    let root = match c!("getting purge root ID" => get_id(txn, root)) {
+        Some(id) => id,
+        None => return Ok(Default::default()),
+    };
+    let mut removed: Vec<Vec<u8>> = Vec::new();
+    if force {
+        removed = txn
+            .prepare_cached("SELECT name FROM aliases WHERE block_id = ?")
+            .ctx("getting purge root aliases (prep)")?
+            .query_map([root], |row| row.get(0))
+            .ctx("getting purge root aliases")?
+            .collect::<rusqlite::Result<_>>()
+            .ctx("getting purge root aliases (rows)")?;
+        c!("removing purge root alias info" => txn.execute(
+            "DELETE FROM alias_info WHERE name IN (SELECT name FROM aliases WHERE block_id = ?)",
+            [root],
+        ));
+        c!("removing purge root aliases" =>
+            txn.execute("DELETE FROM aliases WHERE block_id = ?", [root]));
+        for name in &removed {
+            log_change(txn, CHANGE_ALIAS_REMOVED, None, Some(name))?;
+        }
+    }
+    txn.prepare_cached(
+        r#"
+        WITH RECURSIVE
+            descendant_of(id) AS
+            (
+                SELECT ?
+                UNION
+                SELECT child_id FROM refs, descendant_of ON id = parent_id
+            ),
+            -- same as for gc, see get_gc_candidates
+            protected(id, depth) AS
+            (
+                SELECT block_id, max_depth FROM aliases LEFT JOIN alias_info USING (name)
+                UNION
+                SELECT block_id, NULL FROM temp_pins
+                UNION
+                SELECT child_id, depth - 1 FROM refs, protected ON id = parent_id
+                    WHERE depth IS NULL OR depth > 0
+            )
+        SELECT id FROM descendant_of, blocks ON id = block_id
+        WHERE id NOT IN (SELECT id FROM protected);
+        "#,
+    )
+    .ctx("finding purge blocks (prep)")?
+    .query_map([root], |row| row.get(0))
+    .ctx("finding purge blocks")?
+    .collect::<rusqlite::Result<Vec<i64>>>()
+    .ctx("reading purge block ID")
+    .map(|ids| (ids, removed))
+}
+
+/// number of temp pins and of blocks pinned by them, and the number of gc candidates
+pub(crate) fn diagnostics(
+    txn: &Transaction,
+    grace_period: Duration,
+) -> crate::Result<(u64, u64, u64)> {
+    let (pins, pinned): (i64, i64) = c!("counting temp pins" => txn.query_row(
+        "SELECT COUNT(DISTINCT id), COUNT(DISTINCT block_id) FROM temp_pins",
+        [],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    ));
+    let candidates = get_gc_candidates(txn, grace_period, &[])?.len();
+    Ok((pins as u64, pinned as u64, candidates as u64))
+}
+
+/// determine the blocks that [`incremental_gc`] would delete, without deleting anything
+///
+/// returns cid and size of each block, in the order in which gc would delete them.
+pub(crate) fn gc_preview(
+    conn: &mut Connection,
+    size_targets: SizeTargets,
+    cache_tracker: &impl CacheTracker,
+    gc_filter: &Option<GcFilter>,
+    grace_period: Duration,
+    expired_pins: Vec<i64>,
+) -> crate::Result<Vec<(CidBytes, u64)>> {
+    let _span = tracing::debug_span!("GC preview").entered();
+
+    let stats = in_txn(conn, None, false, get_store_stats)?;
+    if !size_targets.exceeded(&stats) {
+        return Ok(Vec::new());
+    }
+
+    let mut ids = in_txn(
+        conn,
+        Some(("getting unreferenced CIDs", Duration::from_secs(3))),
+        false,
+        move |txn| get_gc_candidates(txn, grace_period, &expired_pins),
+    )?;
+    cache_tracker.sort_ids(&mut ids);
+
+    let gc_filter = gc_filter.clone();
+    in_txn(
+        conn,
+        Some(("getting GC preview blocks", Duration::from_secs(1))),
+        false,
+        move |txn| {
+            let mut stmt = c!("getting GC preview block (prep)" => txn.prepare_cached(
+                "SELECT cid, LENGTH(block) FROM cids, blocks ON id = block_id WHERE id = ?"
+            ));
+            let mut stats = stats.clone();
+            let mut res = Vec::new();
+            for id in ids.iter() {
+                if !size_targets.exceeded(&stats) {
+                    break;
+                }
+                let block: Option<(CidBytes, i64)> = c!("getting GC preview block" => stmt
+                    .query_row([id], |row| Ok((row.get(0)?, row.get(1)?)))
+                    .optional());
+                if let Some((cid, size)) = block {
+                    if let Some(gc_filter) = gc_filter.as_ref() {
+                        if gc_filter.keep(&Cid::try_from(&cid)?) {
+                            continue;
+                        }
+                    }
+                    let size = c!("getting GC preview block size" => u64::try_from(size));
+                    stats.count -= 1;
+                    stats.size -= size;
+                    res.push((cid, size));
+                }
+            }
+            Ok(res)
+        },
+    )
 }
 
 // This is the plan:
@@ -271,18 +1051,21 @@ fn get_or_create_id(txn: &Transaction, cid: impl ToSql) -> rusqlite::Result<i64>
 // the CacheTracker. In a second step delete from least important upwards, block by block, in a
 // write transaction that first checks whether that particular block is still unreferenced. Then
 // at the end perform an incremental or full vacuum, depending on config or fragmentation state.
+#[allow(clippy::too_many_arguments)]
 pub(crate) fn incremental_gc(
     conn: &mut Connection,
     min_blocks: usize,
     max_duration: Duration,
     size_targets: SizeTargets,
     cache_tracker: &impl CacheTracker,
+    gc_filter: &Option<GcFilter>,
+    grace_period: Duration,
 ) -> crate::Result<bool> {
     let _span = tracing::debug_span!("GC", %min_blocks, ?max_duration).entered();
 
     // get the store stats from the stats table:
     // if we don't exceed any of the size targets, there is nothing to do
-    let mut stats = in_txn(conn, None, false, get_store_stats)?;
+    let stats = in_txn(conn, None, false, get_store_stats)?;
     if !size_targets.exceeded(&stats) {
         tracing::info!(
             blocks = display(stats.count),
@@ -293,35 +1076,60 @@ pub(crate) fn incremental_gc(
     }
 
     let t0 = Instant::now();
+    prepare_gc_marks(conn)?;
+    let mut ids = get_sorted_gc_candidates(conn, cache_tracker, grace_period, true)?;
+    let done = delete_gc_candidates(
+        conn,
+        &mut ids,
+        min_blocks,
+        max_duration.saturating_sub(t0.elapsed()),
+        size_targets,
+        cache_tracker,
+        gc_filter,
+        grace_period,
+    )?;
+    // only pay for the full traversal if the marks keep gc from reaching the targets
+    let stale = in_txn(conn, None, false, gc_marks_stale)? == Some(true);
+    if !done
+        || !stale
+        || t0.elapsed() > max_duration
+        || !size_targets.exceeded(&in_txn(conn, None, false, get_store_stats)?)
+    {
+        return Ok(done);
+    }
+    rebuild_gc_marks(conn)?;
+    let mut ids = get_sorted_gc_candidates(conn, cache_tracker, grace_period, true)?;
+    delete_gc_candidates(
+        conn,
+        &mut ids,
+        min_blocks,
+        max_duration.saturating_sub(t0.elapsed()),
+        size_targets,
+        cache_tracker,
+        gc_filter,
+        grace_period,
+    )
+}
 
+/// get the gc candidates, sorted by the cache tracker from least to most important
+///
+/// With `marked`, the gc marks are used if the store has them, see [`get_marked_gc_candidates`].
+pub(crate) fn get_sorted_gc_candidates(
+    conn: &mut Connection,
+    cache_tracker: &impl CacheTracker,
+    grace_period: Duration,
+    marked: bool,
+) -> crate::Result<VecDeque<i64>> {
     let mut ids = in_txn(
         conn,
         Some(("getting unreferenced CIDs", Duration::from_secs(3))),
         false,
-        |txn| {
-            // find all ids that are not pinned (directly or indirectly)
-            let mut id_query = txn
-                .prepare_cached(
-                    r#"
-                    WITH RECURSIVE
-                        descendant_of(id) AS
-                        (
-                            SELECT block_id FROM aliases UNION SELECT block_id FROM temp_pins
-                            UNION
-                            SELECT child_id FROM refs, descendant_of ON id = parent_id
-                        )
-                    SELECT id FROM cids
-                    WHERE id NOT IN descendant_of;
-                    "#,
-                )
-                .ctx("finding GC blocks (prep)")?;
-            // log execution time of the non-interruptible query that computes the set of ids to delete
-            let ret = id_query
-                .query_map([], |row| row.get(0))
-                .ctx("finding GC blocks")?
-                .collect::<rusqlite::Result<Vec<i64>>>()
-                .ctx("reading GC block ID")?;
-            Ok(ret)
+        move |txn| {
+            if marked && gc_marks_stale(txn)?.is_some() {
+                get_marked_gc_candidates(txn, grace_period)
+            } else {
+                get_gc_candidates(txn, grace_period, &[])
+            }
         },
     )?;
 
@@ -330,9 +1138,30 @@ pub(crate) fn incremental_gc(
     cache_tracker.sort_ids(&mut ids);
     drop(span);
 
+    Ok(ids.into())
+}
+
+/// delete blocks from the front of `ids` until the size targets are met, see [`incremental_gc`]
+///
+/// Each candidate is checked again for being pinned or young before deletion, so `ids` may be
+/// stale. Returns true if either the size targets are met or there are no candidates left.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn delete_gc_candidates(
+    conn: &mut Connection,
+    ids: &mut VecDeque<i64>,
+    min_blocks: usize,
+    max_duration: Duration,
+    size_targets: SizeTargets,
+    cache_tracker: &impl CacheTracker,
+    gc_filter: &Option<GcFilter>,
+    grace_period: Duration,
+) -> crate::Result<bool> {
+    let t0 = Instant::now();
+    let mut stats = in_txn(conn, None, false, get_store_stats)?;
     let mut n = 0;
     let mut ret_val = true;
-    for id in ids.into_iter() {
+    let grace_secs = grace_secs(grace_period);
+    while let Some(id) = ids.front().copied() {
         if n >= min_blocks && t0.elapsed() > max_duration {
             tracing::info!(removed = n, "stopping due to time constraint");
             ret_val = false;
@@ -342,6 +1171,7 @@ pub(crate) fn incremental_gc(
             tracing::info!(removed = n, "finished, target reached");
             break;
         }
+        let gc_filter = gc_filter.clone();
         let res = in_txn(
             conn,
             Some(("", Duration::from_millis(100))),
@@ -352,129 +1182,302 @@ pub(crate) fn incremental_gc(
                     r#"
                 WITH RECURSIVE
                     ancestor(id) AS (
-                        SELECT ?
+                        SELECT ?1
                         UNION -- must not use UNION ALL in case of pathologically linked dags
                         SELECT parent_id FROM refs, ancestor ON id = child_id
                     ),
-                    names AS (SELECT name FROM ancestor, aliases ON id = block_id)
-                SELECT LENGTH(block), cid, (SELECT count(*) FROM names)
-                    FROM cids, blocks ON id = block_id WHERE id = ?;
+                    -- ancestors up to the largest depth limit of any alias, with their distance
+                    near_ancestor(id, dist) AS (
+                        SELECT ?1, 0
+                        UNION
+                        SELECT parent_id, dist + 1 FROM refs, near_ancestor ON id = child_id
+                            WHERE dist < (SELECT MAX(max_depth) FROM alias_info)
+                    ),
+                    -- a temp pin may have been added since the candidates were computed
+                    pins AS (
+                        SELECT block_id FROM ancestor, aliases ON id = block_id
+                            LEFT JOIN alias_info USING (name) WHERE max_depth IS NULL
+                        UNION ALL
+                        SELECT block_id FROM near_ancestor, aliases ON id = block_id
+                            JOIN alias_info USING (name) WHERE dist <= max_depth
+                        UNION ALL
+                        SELECT block_id FROM ancestor, temp_pins ON ancestor.id = block_id
+                        UNION ALL
+                        -- the block may have been re-added since the candidates were computed
+                        SELECT block_id FROM block_times
+                            WHERE block_id = ?1 AND added > strftime('%s', 'now') - ?2
+                    )
+                SELECT LENGTH(block), cid, (SELECT count(*) FROM pins)
+                    FROM cids, blocks ON id = block_id WHERE id = ?1;
                 "#,
                 ));
                 let mut update_stats_stmt = c!("updating GC stats (prep)" =>
                 txn.prepare_cached("UPDATE stats SET count = count - 1, size = size - ?"));
                 let mut delete_stmt = c!("deleting GC block (prep)" => txn.prepare_cached("DELETE FROM blocks WHERE block_id = ?"));
 
-                tracing::trace!("deleting id {}", id);
+                tracing::trace!(id, "deleting block");
 
                 let block_size: Option<(i64, CidBytes, i64)> = block_size_stmt
-                    .query_row([id, id], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+                    .query_row([id, grace_secs], |row| {
+                        Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+                    })
                     .optional()
                     .ctx("getting GC block")?;
                 tracing::trace!(block_size = ?&block_size);
-                if let Some((block_size, cid, names)) = block_size {
-                    if names != 0 {
+                if let Some((block_size, cid, pins)) = block_size {
+                    if pins != 0 {
                         // block is referenced again
                         return Ok(None);
                     }
                     let cid = Cid::try_from(&cid)?;
+                    if gc_filter.as_ref().map_or(false, |f| f.keep(&cid)) {
+                        tracing::trace!("kept by gc filter");
+                        return Ok(None);
+                    }
                     let len = c!("getting GC block size" => usize::try_from(block_size));
                     c!("updating GC stats" => update_stats_stmt.execute([block_size]));
                     tracing::trace!("stats updated");
                     c!("deleting GC block" => delete_stmt.execute(params![id]));
+                    log_change(txn, CHANGE_DELETE, Some(id), None)?;
                     Ok(Some((block_size, cid, len)))
                 } else {
                     Ok(None)
                 }
             },
         )?;
+        ids.pop_front();
         if let Some((size, cid, len)) = res {
+            tracing::trace!(%cid, size, "deleted block");
             stats.count -= 1;
             stats.size -= size as u64;
             cache_tracker.blocks_deleted(vec![BlockInfo::new(id, &cid, len)]);
             n += 1;
         }
     }
+    tracing::debug!(deleted = n, remaining = ids.len(), "gc deletion done");
 
     if n > 0 {
         // the above only removed the blocks, now we need to clean up those cids that we don’t
         // need anymore
+        delete_orphaned_cids(conn, Duration::from_secs(u32::MAX.into()))?;
+    }
 
-        // doing this in one transaction may block the DB for too long, so get the IDs first and then
-        // remove them in batches
-        let ids = in_txn(
-            conn,
-            Some(("getting IDs to clean up", Duration::from_secs(5))),
-            false,
-            |txn| {
-                let mut stmt = c!("getting IDs (prep)" => txn.prepare_cached(
-                        // refs.parent_id is not a blocker because if we delete this it means that
-                        // the block is gone
-                        "SELECT id FROM cids WHERE \
-                        id NOT IN (SELECT block_id FROM blocks) AND \
-                        id NOT IN (SELECT block_id FROM aliases) AND \
-                        id NOT IN (SELECT child_id FROM refs) AND \
-                        id NOT IN (SELECT block_id FROM temp_pins)",
-                ));
-                let ids = c!("getting IDs" => stmt.query_map([], |row| row.get(0)));
-                ids.collect::<Result<Vec<i64>, _>>().ctx("ids")
-            },
-        )?;
+    Ok(ret_val)
+}
 
-        tracing::debug!("cleaning up {} IDs", ids.len());
-
-        // this number is linked to the prepared query below!
-        const BATCH_SIZE: usize = 10;
-        for ids in &ids.into_iter().chunks(BATCH_SIZE) {
-            let mut v = Vec::with_capacity(BATCH_SIZE);
-            v.extend(ids);
-            if v.len() == BATCH_SIZE {
-                in_txn(
-                    conn,
-                    Some(("cleaning up CIDs", Duration::from_millis(100))),
-                    false,
-                    move |txn| {
-                        let mut del_cid = c!("deleting CIDs (prep)" => txn.prepare_cached(
-                            "DELETE FROM cids WHERE \
-                                id in (VALUES (?), (?), (?), (?), (?), (?), (?), (?), (?), (?)) AND \
-                                id NOT IN (SELECT block_id FROM blocks) AND \
-                                id NOT IN (SELECT block_id FROM aliases) AND \
-                                id NOT IN (SELECT child_id FROM refs) AND \
-                                id NOT IN (SELECT block_id FROM temp_pins)"
-                        ));
-                        c!("deleting CIDs" => del_cid.execute(params_from_iter(v.iter())));
-                        Ok(())
-                    },
-                )?;
-            } else {
-                in_txn(conn, None, false, move |txn| {
-                    let mut stmt = c!("deleting CIDs (prep)" => txn.prepare_cached(
+/// delete cids that are not referenced by anything and do not have a block
+///
+/// Stops after `max_duration` has elapsed; returns the number of deleted cids and whether
+/// all orphans known at the start have been processed.
+pub(crate) fn delete_orphaned_cids(
+    conn: &mut Connection,
+    max_duration: Duration,
+) -> crate::Result<(usize, bool)> {
+    let t0 = Instant::now();
+
+    // doing this in one transaction may block the DB for too long, so get the IDs first and then
+    // remove them in batches
+    let ids = in_txn(
+        conn,
+        Some(("getting IDs to clean up", Duration::from_secs(5))),
+        false,
+        |txn| {
+            let mut stmt = c!("getting IDs (prep)" => txn.prepare_cached(
+                    // refs.parent_id is not a blocker because if we delete this it means that
+                    // the block is gone
+                    "SELECT id FROM cids WHERE \
+                    id NOT IN (SELECT block_id FROM blocks) AND \
+                    id NOT IN (SELECT block_id FROM aliases) AND \
+                    id NOT IN (SELECT child_id FROM refs) AND \
+                    id NOT IN (SELECT child_id FROM pending_refs) AND \
+                    id NOT IN (SELECT block_id FROM temp_pins)",
+            ));
+            let ids = c!("getting IDs" => stmt.query_map([], |row| row.get(0)));
+            ids.collect::<Result<Vec<i64>, _>>().ctx("ids")
+        },
+    )?;
+
+    tracing::debug!(ids = ids.len(), "cleaning up orphaned cids");
+
+    let mut deleted = 0;
+    // this number is linked to the prepared query below!
+    const BATCH_SIZE: usize = 10;
+    for ids in &ids.into_iter().chunks(BATCH_SIZE) {
+        if t0.elapsed() > max_duration {
+            tracing::info!(deleted, "stopping orphan cleanup due to time constraint");
+            return Ok((deleted, false));
+        }
+        if deleted > 0 {
+            // give other connections a chance to get the write lock
+            std::thread::yield_now();
+        }
+        let mut v = Vec::with_capacity(BATCH_SIZE);
+        v.extend(ids);
+        if v.len() == BATCH_SIZE {
+            deleted += in_txn(
+                conn,
+                Some(("cleaning up CIDs", Duration::from_millis(100))),
+                false,
+                move |txn| {
+                    let mut del_cid = c!("deleting CIDs (prep)" => txn.prepare_cached(
                         "DELETE FROM cids WHERE \
-                            id = ? AND \
+                            id in (VALUES (?), (?), (?), (?), (?), (?), (?), (?), (?), (?)) AND \
                             id NOT IN (SELECT block_id FROM blocks) AND \
                             id NOT IN (SELECT block_id FROM aliases) AND \
                             id NOT IN (SELECT child_id FROM refs) AND \
+                            id NOT IN (SELECT child_id FROM pending_refs) AND \
                             id NOT IN (SELECT block_id FROM temp_pins)"
                     ));
-                    for id in v.iter() {
-                        c!("deleting CIDs" => stmt.execute([id]));
-                    }
-                    Ok(())
-                })?;
-            }
+                    Ok(c!("deleting CIDs" => del_cid.execute(params_from_iter(v.iter()))))
+                },
+            )?;
+        } else {
+            deleted += in_txn(conn, None, false, move |txn| {
+                let mut stmt = c!("deleting CIDs (prep)" => txn.prepare_cached(
+                    "DELETE FROM cids WHERE \
+                        id = ? AND \
+                        id NOT IN (SELECT block_id FROM blocks) AND \
+                        id NOT IN (SELECT block_id FROM aliases) AND \
+                        id NOT IN (SELECT child_id FROM refs) AND \
+                        id NOT IN (SELECT child_id FROM pending_refs) AND \
+                        id NOT IN (SELECT block_id FROM temp_pins)"
+                ));
+                let mut deleted = 0;
+                for id in v.iter() {
+                    deleted += c!("deleting CIDs" => stmt.execute([id]));
+                }
+                Ok(deleted)
+            })?;
         }
     }
 
-    Ok(ret_val)
+    tracing::debug!(deleted, "orphan cleanup done");
+    Ok((deleted, true))
+}
+
+/// count the cids that are not referenced by anything and have no data
+pub(crate) fn count_orphaned(txn: &Transaction) -> crate::Result<u64> {
+    let n: i64 = c!("counting orphans" => txn.query_row(
+        "SELECT count(*) FROM cids WHERE \
+        id NOT IN (SELECT block_id FROM blocks) AND \
+        id NOT IN (SELECT block_id FROM aliases) AND \
+        id NOT IN (SELECT child_id FROM refs) AND \
+        id NOT IN (SELECT child_id FROM pending_refs) AND \
+        id NOT IN (SELECT block_id FROM temp_pins)",
+        [],
+        |row| row.get(0),
+    ));
+    Ok(n as u64)
+}
+
+/// return up to `pages` free pages to the file system, or all if `None`
+///
+/// returns the number of free pages left
+pub(crate) fn incremental_vacuum(conn: &mut Connection, pages: Option<u64>) -> crate::Result<u64> {
+    // 0 means all pages to sqlite
+    let sql = match pages {
+        Some(0) => None,
+        Some(n) => Some(format!(
+            "PRAGMA incremental_vacuum({})",
+            n.min(i64::MAX as u64)
+        )),
+        None => Some("PRAGMA incremental_vacuum".to_owned()),
+    };
+    in_txn(
+        conn,
+        Some(("incremental_vacuum", Duration::from_millis(500))),
+        false,
+        move |txn| {
+            if let Some(sql) = &sql {
+                // each step frees one page, so it must be run to completion
+                let mut stmt = txn.prepare(sql).ctx("incremental vacuum (prep)")?;
+                let mut rows = stmt.query([]).ctx("incremental vacuum")?;
+                while rows.next().ctx("incremental vacuum")?.is_some() {}
+            }
+            let free: i64 = txn
+                .pragma_query_value(None, "freelist_count", |r| r.get(0))
+                .ctx("getting freelist_count")?;
+            Ok(free as u64)
+        },
+    )
+}
+
+/// let sqlite update its query planner statistics where it deems this beneficial
+pub(crate) fn optimize(conn: &mut Connection) -> crate::Result<()> {
+    let _span = tracing::debug_span!("optimizing the db").entered();
+    conn.execute_batch("PRAGMA optimize")
+        .ctx("running PRAGMA optimize")
 }
 
 pub(crate) fn delete_temp_pin(txn: &Transaction, pin: i64) -> crate::Result<()> {
     let mut stmt =
         c!("deleting temp_pin (prep)" => txn.prepare_cached("DELETE FROM temp_pins WHERE id = ?"));
     c!("deleting temp_pin" => stmt.execute([pin]));
+    let mut stmt = c!("deleting temp_pin session (prep)" =>
+        txn.prepare_cached("DELETE FROM temp_pin_sessions WHERE id = ?"));
+    c!("deleting temp_pin session" => stmt.execute([pin]));
     Ok(())
 }
 
+/// record which session created a temp pin
+pub(crate) fn set_temp_pin_session(txn: &Transaction, pin: i64, session: i64) -> crate::Result<()> {
+    txn.prepare_cached("INSERT OR REPLACE INTO temp_pin_sessions (id, session) VALUES (?, ?)")
+        .ctx("setting temp_pin session (prep)")?
+        .execute([pin, session])
+        .ctx("setting temp_pin session")?;
+    Ok(())
+}
+
+/// the sessions that own temp pins
+pub(crate) fn temp_pin_sessions(txn: &Transaction) -> crate::Result<Vec<i64>> {
+    txn.prepare_cached("SELECT DISTINCT session FROM temp_pin_sessions")
+        .ctx("getting temp_pin sessions (prep)")?
+        .query_map([], |row| row.get(0))
+        .ctx("getting temp_pin sessions")?
+        .collect::<rusqlite::Result<Vec<i64>>>()
+        .ctx("reading temp_pin session")
+}
+
+/// delete the temp pins of the given sessions and those without a session, returning the
+/// number of pins removed
+pub(crate) fn clear_temp_pin_sessions(txn: &Transaction, sessions: &[i64]) -> crate::Result<u64> {
+    let mut removed = c!("counting temp_pins without session" => txn.query_row(
+        "SELECT COUNT(DISTINCT id) FROM temp_pins \
+        WHERE id NOT IN (SELECT id FROM temp_pin_sessions)",
+        [],
+        |row| row.get::<_, i64>(0),
+    ));
+    c!("deleting temp_pins without session" => txn.execute(
+        "DELETE FROM temp_pins WHERE id NOT IN (SELECT id FROM temp_pin_sessions)",
+        [],
+    ));
+    for session in sessions {
+        removed += c!("counting stale temp_pins" => txn.query_row(
+            "SELECT COUNT(DISTINCT id) FROM temp_pins \
+            WHERE id IN (SELECT id FROM temp_pin_sessions WHERE session = ?)",
+            [session],
+            |row| row.get::<_, i64>(0),
+        ));
+        c!("deleting stale temp_pins" => txn.execute(
+            "DELETE FROM temp_pins WHERE id IN (SELECT id FROM temp_pin_sessions WHERE session = ?)",
+            [session],
+        ));
+        c!("deleting stale temp_pin sessions" =>
+            txn.execute("DELETE FROM temp_pin_sessions WHERE session = ?", [session]));
+    }
+    Ok(removed as u64)
+}
+
+/// ids of all temp pins with the number of blocks each of them pins
+pub(crate) fn temp_pins(txn: &Transaction) -> crate::Result<Vec<(i64, u64)>> {
+    txn.prepare_cached("SELECT id, COUNT(*) FROM temp_pins GROUP BY id ORDER BY id")
+        .ctx("listing temp pins (prep)")?
+        .query_map([], |row| Ok((row.get(0)?, row.get::<_, i64>(1)? as u64)))
+        .ctx("listing temp pins")?
+        .collect::<rusqlite::Result<_>>()
+        .ctx("parsing temp pins")
+}
+
 pub(crate) fn extend_temp_pin(
     txn: &Transaction,
     mut id: i64,
@@ -498,14 +1501,16 @@ fn add_temp_pin(txn: &Transaction, block_id: i64, pin: i64) -> crate::Result<i64
         Ok(pin)
     } else {
         // we must not reuse IDs, but sqlite takes care of transactionality here
-        Ok(txn
-            .prepare_cached(
-                "INSERT INTO temp_pins (id, block_id) VALUES \
-                ((SELECT coalesce(max(id), 0) FROM temp_pins) + 1, ?) RETURNING id",
-            )
+        let id: i64 = txn
+            .prepare_cached("SELECT coalesce(max(id), 0) + 1 FROM temp_pins")
+            .ctx("creating new temp_pin (prep)")?
+            .query_row([], |row| row.get(0))
+            .ctx("creating new temp_pin")?;
+        txn.prepare_cached("INSERT INTO temp_pins (id, block_id) VALUES (?, ?)")
             .ctx("creating new temp_pin (prep)")?
-            .query_row([block_id], |row| row.get(0))
-            .ctx("creating new temp_pin")?)
+            .execute([id, block_id])
+            .ctx("creating new temp_pin")?;
+        Ok(id)
     }
 }
 
@@ -516,15 +1521,17 @@ pub(crate) struct PutBlockResult {
     pub(crate) block_exists: bool,
 }
 
-pub(crate) fn put_block<C: ToSql>(
+pub(crate) fn put_block(
     txn: &Transaction,
-    key: &C,
+    ids: &mut Ids,
+    key: &CidBytes,
+    cid: &Cid,
     data: &[u8],
-    links: impl IntoIterator<Item = C>,
+    links: impl IntoIterator<Item = (CidBytes, u32)>,
     mut pin: Option<i64>,
 ) -> crate::Result<(Option<i64>, PutBlockResult)> {
     // this is important: we need write lock on the table so that add_temp_pin is never rolled back
-    let block_id = c!("getting put_block ID" => get_or_create_id(txn, key));
+    let block_id = c!("getting put_block ID" => ids.get_or_create(txn, key));
     let block_exists = txn
         .prepare_cached("SELECT COUNT(*) FROM blocks WHERE block_id = ?")
         .ctx("checking put_block (prep)")?
@@ -536,6 +1543,19 @@ pub(crate) fn put_block<C: ToSql>(
             .ctx("adding put_block (prep)")?
             .execute(params![block_id, &data])
             .ctx("adding put_block")?;
+        txn.prepare_cached(
+            "INSERT OR REPLACE INTO block_times (block_id, added) \
+            VALUES (?, strftime('%s', 'now'))",
+        )
+        .ctx("adding put_block time (prep)")?
+        .execute([block_id])
+        .ctx("adding put_block time")?;
+        insert_cid_info(txn, block_id, cid)?;
+        txn.prepare_cached("INSERT OR REPLACE INTO block_seq (block_id) VALUES (?)")
+            .ctx("adding put_block sequence number (prep)")?
+            .execute([block_id])
+            .ctx("adding put_block sequence number")?;
+        log_change(txn, CHANGE_PUT, Some(block_id), None)?;
 
         // update the stats
         txn.prepare_cached("UPDATE stats SET count = count + 1, size = size + ?")
@@ -543,16 +1563,12 @@ pub(crate) fn put_block<C: ToSql>(
             .execute([data.len() as i64])
             .ctx("updating put_block stats")?;
 
-        // insert the links
-        let mut insert_ref = txn
-            .prepare_cached("INSERT INTO refs (parent_id, child_id) VALUES (?,?)")
-            .ctx("adding put_block link (prep)")?;
-        for link in links {
-            let child_id: i64 = c!("getting put_block link ID" => get_or_create_id(txn, link));
-            insert_ref
-                .execute([block_id, child_id])
-                .ctx("adding put_block link")?;
-        }
+        // the links decoded from the data take precedence over links recorded beforehand
+        txn.prepare_cached("DELETE FROM pending_refs WHERE parent_id = ?")
+            .ctx("deleting put_block pending links (prep)")?
+            .execute([block_id])
+            .ctx("deleting put_block pending links")?;
+        insert_links(txn, ids, block_id, links)?;
     }
     if let Some(pin) = pin.as_mut() {
         // create a temporary alias for the block, even if it already exists
@@ -568,6 +1584,88 @@ pub(crate) fn put_block<C: ToSql>(
     ))
 }
 
+fn insert_links(
+    txn: &Transaction,
+    ids: &mut Ids,
+    block_id: i64,
+    links: impl IntoIterator<Item = (CidBytes, u32)>,
+) -> crate::Result<()> {
+    let mut insert_ref = txn
+        .prepare_cached("INSERT INTO refs (parent_id, child_id) VALUES (?,?)")
+        .ctx("adding link (prep)")?;
+    let mut insert_count = txn
+        .prepare_cached("INSERT INTO ref_counts (parent_id, child_id, count) VALUES (?,?,?)")
+        .ctx("adding link count (prep)")?;
+    for (link, count) in links {
+        let child_id: i64 = c!("getting link ID" => ids.get_or_create(txn, &link));
+        insert_ref
+            .execute([block_id, child_id])
+            .ctx("adding link")?;
+        if count > 1 {
+            insert_count
+                .execute(params![block_id, child_id, count])
+                .ctx("adding link count")?;
+        }
+    }
+    Ok(())
+}
+
+/// replace the links stored for a block, returns false if there is no data for the cid
+///
+/// children that are no longer referenced are left for the orphan cleanup.
+pub(crate) fn set_links(
+    txn: &Transaction,
+    key: &CidBytes,
+    links: impl IntoIterator<Item = (CidBytes, u32)>,
+) -> crate::Result<bool> {
+    let block_id: Option<i64> = txn
+        .prepare_cached("SELECT block_id FROM cids, blocks ON id = block_id WHERE cid = ?")
+        .ctx("getting set_links ID (prep)")?
+        .query_row([key], |row| row.get(0))
+        .optional()
+        .ctx("getting set_links ID")?;
+    let block_id = match block_id {
+        Some(id) => id,
+        None => return Ok(false),
+    };
+    // this cascades to ref_counts
+    txn.prepare_cached("DELETE FROM refs WHERE parent_id = ?")
+        .ctx("deleting old links (prep)")?
+        .execute([block_id])
+        .ctx("deleting old links")?;
+    insert_links(txn, &mut Ids::uncached(), block_id, links)?;
+    Ok(true)
+}
+
+/// record the links of a cid for which there is no data yet, returns false if there is data
+pub(crate) fn add_links_only<C: ToSql>(
+    txn: &Transaction,
+    key: &C,
+    links: impl IntoIterator<Item = C>,
+) -> crate::Result<bool> {
+    let id = c!("getting add_links_only ID" => get_or_create_id(txn, key));
+    let block_exists = txn
+        .prepare_cached("SELECT COUNT(*) FROM blocks WHERE block_id = ?")
+        .ctx("checking add_links_only (prep)")?
+        .query_row([id], |row| Ok(row.get::<_, i64>(0)? == 1))
+        .ctx("checking add_links_only")?;
+    if block_exists {
+        return Ok(false);
+    }
+    txn.prepare_cached("DELETE FROM pending_refs WHERE parent_id = ?")
+        .ctx("deleting pending links (prep)")?
+        .execute([id])
+        .ctx("deleting pending links")?;
+    let mut insert = txn
+        .prepare_cached("INSERT OR IGNORE INTO pending_refs (parent_id, child_id) VALUES (?,?)")
+        .ctx("adding pending link (prep)")?;
+    for link in links {
+        let child_id: i64 = c!("getting pending link ID" => get_or_create_id(txn, link));
+        insert.execute([id, child_id]).ctx("adding pending link")?;
+    }
+    Ok(true)
+}
+
 /// Get a block
 pub(crate) fn get_block(
     txn: &Transaction,
@@ -586,6 +1684,117 @@ pub(crate) fn get_block(
     })
 }
 
+/// get the id and size of a block for which there is data
+pub(crate) fn get_block_id(
+    txn: &Transaction,
+    cid: impl ToSql,
+) -> crate::Result<Option<(i64, usize)>> {
+    txn.prepare_cached("SELECT id, LENGTH(block) FROM cids, blocks ON id = block_id WHERE cid = ?")
+        .ctx("getting block ID (prep)")?
+        .query_row([cid], |row| {
+            Ok((row.get(0)?, row.get::<_, i64>(1)? as usize))
+        })
+        .optional()
+        .ctx("getting block ID")
+}
+
+/// open the data of a block for incremental reading
+pub(crate) fn open_block_blob(
+    conn: &Connection,
+    block_id: i64,
+) -> crate::Result<rusqlite::blob::Blob<'_>> {
+    conn.blob_open(
+        rusqlite::DatabaseName::Main,
+        "blocks",
+        "block",
+        block_id,
+        true,
+    )
+    .ctx("opening block blob")
+}
+
+/// copy the data of a block into `buf`, replacing its contents; returns the block id
+pub(crate) fn get_block_into(
+    txn: &Transaction,
+    cid: impl ToSql,
+    buf: &mut Vec<u8>,
+) -> crate::Result<Option<i64>> {
+    buf.clear();
+    txn.prepare_cached("SELECT id, block FROM cids, blocks ON id = block_id WHERE cid = ?")
+        .ctx("getting get_block_into (prep)")?
+        .query_row([cid], |row| {
+            buf.extend_from_slice(row.get_ref(1)?.as_blob()?);
+            row.get(0)
+        })
+        .optional()
+        .ctx("getting get_block_into")
+}
+
+/// get the direct children of a block from the refs table
+pub(crate) fn get_links<C: FromSql>(txn: &Transaction, id: i64) -> crate::Result<Vec<C>> {
+    txn.prepare_cached("SELECT cid FROM refs, cids ON child_id = id WHERE parent_id = ?")
+        .ctx("getting links (prep)")?
+        .query_map([id], |row| row.get(0))
+        .ctx("getting links")?
+        .collect::<rusqlite::Result<Vec<C>>>()
+        .ctx("parsing links")
+}
+
+/// get the direct children of a block together with the number of times each is linked
+///
+/// returns None if there is no data for the block, since then the links are not known.
+pub(crate) fn get_links_counted<C: ToSql + FromSql>(
+    txn: &Transaction,
+    cid: C,
+) -> crate::Result<Option<Vec<(C, u32)>>> {
+    let id: Option<i64> = txn
+        .prepare_cached("SELECT block_id FROM blocks, cids ON block_id = id WHERE cid = ?")
+        .ctx("getting counted links ID (prep)")?
+        .query_row([cid], |row| row.get(0))
+        .optional()
+        .ctx("getting counted links ID")?;
+    let id = match id {
+        Some(id) => id,
+        None => return Ok(None),
+    };
+    let links = txn
+        .prepare_cached(
+            "SELECT cid, COALESCE(count, 1) FROM refs, cids ON refs.child_id = id \
+            LEFT JOIN ref_counts USING (parent_id, child_id) WHERE parent_id = ?",
+        )
+        .ctx("getting counted links (prep)")?
+        .query_map([id], |row| Ok((row.get(0)?, row.get(1)?)))
+        .ctx("getting counted links")?
+        .collect::<rusqlite::Result<Vec<_>>>()
+        .ctx("parsing counted links")?;
+    Ok(Some(links))
+}
+
+/// cid, data and links of a block
+pub(crate) type BlockWithLinks<C> = (C, Vec<u8>, Vec<C>);
+
+/// get a random sample of blocks together with their links from the refs table
+pub(crate) fn sample_blocks<C: FromSql>(
+    txn: &Transaction,
+    n: usize,
+) -> crate::Result<Vec<BlockWithLinks<C>>> {
+    let blocks = txn
+        .prepare_cached(
+            "SELECT id, cid, block FROM cids, blocks ON id = block_id ORDER BY random() LIMIT ?",
+        )
+        .ctx("sampling blocks (prep)")?
+        .query_map([n as i64], |row| {
+            Ok((row.get::<_, i64>(0)?, row.get(1)?, row.get(2)?))
+        })
+        .ctx("sampling blocks")?
+        .collect::<rusqlite::Result<Vec<(i64, C, Vec<u8>)>>>()
+        .ctx("parsing sampled blocks")?;
+    blocks
+        .into_iter()
+        .map(|(id, cid, data)| Ok((cid, data, get_links(txn, id)?)))
+        .collect()
+}
+
 /// Check if we have a block
 pub(crate) fn has_block(txn: &Transaction, cid: impl ToSql) -> crate::Result<bool> {
     Ok(txn
@@ -615,7 +1824,35 @@ pub(crate) fn get_descendants<C: ToSql + FromSql>(
     txn: &Transaction,
     cid: C,
 ) -> crate::Result<Vec<C>> {
-    let res = txn
+    Ok(get_descendants_limited(txn, cid, Limit::default())?.0)
+}
+
+/// read cids from the first column until the limit is hit, returns whether the result is truncated
+///
+/// this stops stepping the statement early, so recursive queries are not computed to the end.
+fn collect_limited<C: FromSql>(
+    mut rows: rusqlite::Rows,
+    limit: Limit,
+) -> rusqlite::Result<(Vec<C>, bool)> {
+    let mut res = Vec::new();
+    let mut bytes = 0usize;
+    while let Some(row) = rows.next()? {
+        let len = row.get_ref(0)?.as_blob().map_or(0, <[u8]>::len);
+        if res.len() >= limit.max_results || bytes.saturating_add(len) > limit.max_bytes {
+            return Ok((res, true));
+        }
+        bytes += len;
+        res.push(row.get(0)?);
+    }
+    Ok((res, false))
+}
+
+pub(crate) fn get_descendants_limited<C: ToSql + FromSql>(
+    txn: &Transaction,
+    cid: C,
+    limit: Limit,
+) -> crate::Result<(Vec<C>, bool)> {
+    let mut stmt = txn
         .prepare_cached(
             r#"
             WITH RECURSIVE
@@ -629,12 +1866,133 @@ pub(crate) fn get_descendants<C: ToSql + FromSql>(
                 SELECT cid from cids, descendant_of USING (id);
             "#,
         )
-        .ctx("getting descendants (prep)")?
-        .query_map([cid], |row| row.get(0))
-        .ctx("getting descendants")?
-        .collect::<rusqlite::Result<Vec<C>>>()
-        .ctx("parsing descendants")?;
-    Ok(res)
+        .ctx("getting descendants (prep)")?;
+    let rows = stmt.query([cid]).ctx("getting descendants")?;
+    collect_limited(rows, limit).ctx("parsing descendants")
+}
+
+/// the blocks below a root with their size (None without data), the stored links between
+/// them and the aliases pointing at them, all by id
+pub(crate) struct RefGraph<C> {
+    pub(crate) nodes: Vec<(i64, C, Option<i64>)>,
+    pub(crate) edges: Vec<(i64, i64)>,
+    pub(crate) aliases: Vec<(Vec<u8>, i64)>,
+}
+
+pub(crate) fn ref_graph<C: ToSql + FromSql>(
+    txn: &Transaction,
+    root: C,
+) -> crate::Result<RefGraph<C>> {
+    const DESCENDANTS: &str = r#"
+        WITH RECURSIVE
+            descendant_of(id) AS
+            (
+                SELECT id FROM cids WHERE cid = ?
+                UNION
+                SELECT child_id FROM refs, descendant_of ON id = parent_id
+            )
+    "#;
+    let nodes = txn
+        .prepare(&format!(
+            "{} SELECT id, cid, LENGTH(block) FROM descendant_of JOIN cids USING (id) \
+            LEFT JOIN blocks ON id = block_id ORDER BY id",
+            DESCENDANTS
+        ))
+        .ctx("getting graph nodes (prep)")?
+        .query_map([&root], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+        .ctx("getting graph nodes")?
+        .collect::<rusqlite::Result<Vec<_>>>()
+        .ctx("parsing graph nodes")?;
+    let edges = txn
+        .prepare(&format!(
+            "{} SELECT parent_id, child_id FROM refs \
+            WHERE parent_id IN (SELECT id FROM descendant_of) ORDER BY parent_id, child_id",
+            DESCENDANTS
+        ))
+        .ctx("getting graph edges (prep)")?
+        .query_map([&root], |row| Ok((row.get(0)?, row.get(1)?)))
+        .ctx("getting graph edges")?
+        .collect::<rusqlite::Result<Vec<_>>>()
+        .ctx("parsing graph edges")?;
+    let aliases = txn
+        .prepare(&format!(
+            "{} SELECT name, block_id FROM aliases \
+            WHERE block_id IN (SELECT id FROM descendant_of) ORDER BY name",
+            DESCENDANTS
+        ))
+        .ctx("getting graph aliases (prep)")?
+        .query_map([&root], |row| Ok((row.get(0)?, row.get(1)?)))
+        .ctx("getting graph aliases")?
+        .collect::<rusqlite::Result<Vec<_>>>()
+        .ctx("parsing graph aliases")?;
+    Ok(RefGraph {
+        nodes,
+        edges,
+        aliases,
+    })
+}
+
+/// get the blocks linking directly to a cid
+pub(crate) fn get_referrers<C: ToSql + FromSql>(
+    txn: &Transaction,
+    cid: C,
+) -> crate::Result<Vec<C>> {
+    txn.prepare_cached(
+        "SELECT parent.cid FROM cids child, refs, cids parent \
+        ON child.id = child_id AND parent_id = parent.id WHERE child.cid = ?",
+    )
+    .ctx("getting referrers (prep)")?
+    .query_map([cid], |row| row.get(0))
+    .ctx("getting referrers")?
+    .collect::<rusqlite::Result<Vec<C>>>()
+    .ctx("parsing referrers")
+}
+
+/// get all blocks from which a cid can be reached, excluding the cid itself
+pub(crate) fn get_ancestors<C: ToSql + FromSql>(
+    txn: &Transaction,
+    cid: C,
+) -> crate::Result<Vec<C>> {
+    txn.prepare_cached(
+        r#"
+        WITH RECURSIVE
+            ancestor_of(id) AS
+            (
+                SELECT parent_id FROM cids, refs ON id = child_id WHERE cid = ?
+                UNION
+                SELECT parent_id FROM refs, ancestor_of ON id = child_id
+            )
+        SELECT cid FROM cids, ancestor_of USING (id);
+        "#,
+    )
+    .ctx("getting ancestors (prep)")?
+    .query_map([cid], |row| row.get(0))
+    .ctx("getting ancestors")?
+    .collect::<rusqlite::Result<Vec<C>>>()
+    .ctx("parsing ancestors")
+}
+
+/// check whether there is data for the cid and all its descendants
+///
+/// the traversal stops at the first missing block.
+pub(crate) fn is_complete(txn: &Transaction, cid: impl ToSql) -> crate::Result<bool> {
+    txn.prepare_cached(
+        r#"
+        WITH RECURSIVE
+            desc(id) AS
+            (
+                SELECT id FROM cids WHERE cid = ?1
+                UNION
+                SELECT child_id FROM refs, desc ON id = parent_id
+            )
+        SELECT EXISTS (SELECT 1 FROM cids WHERE cid = ?1) AND NOT EXISTS (
+            SELECT 1 FROM desc LEFT JOIN blocks ON id = block_id WHERE block_id IS NULL
+        );
+        "#,
+    )
+    .ctx("checking completeness (prep)")?
+    .query_row([cid], |row| row.get(0))
+    .ctx("checking completeness")
 }
 
 /// get the set of descendants of an id for which we do not have the data yet.
@@ -644,16 +2002,29 @@ pub(crate) fn get_missing_blocks<C: ToSql + FromSql>(
     txn: &Transaction,
     cid: C,
 ) -> crate::Result<Vec<C>> {
+    Ok(get_missing_blocks_limited(txn, cid, Limit::default())?.0)
+}
+
+pub(crate) fn get_missing_blocks_limited<C: ToSql + FromSql>(
+    txn: &Transaction,
+    cid: C,
+    limit: Limit,
+) -> crate::Result<(Vec<C>, bool)> {
     let id = c!("getting missing_blocks ID" => get_or_create_id(txn, cid));
-    let res = txn
+    let mut stmt = txn
         .prepare_cached(
             r#"
                 WITH RECURSIVE
                     -- find descendants of cid, including the id of the cid itself
+                    -- links recorded without data are followed as well
                     desc(id) AS (
                         SELECT ?
                         UNION
-                        SELECT child_id FROM refs, desc ON id = parent_id
+                        SELECT child_id FROM (
+                            SELECT parent_id, child_id FROM refs
+                            UNION ALL
+                            SELECT parent_id, child_id FROM pending_refs
+                        ), desc ON id = parent_id
                     ),
                     -- find orphaned ids
                     orphaned_ids as (
@@ -663,34 +2034,366 @@ pub(crate) fn get_missing_blocks<C: ToSql + FromSql>(
                 SELECT cid FROM cids, orphaned_ids USING (id)
                 "#,
         )
+        .ctx("finding missing_blocks (prep)")?;
+    let rows = stmt.query([id]).ctx("finding missing_blocks")?;
+    collect_limited(rows, limit).ctx("parsing missing_blocks")
+}
+
+/// get the union of the missing descendants of several cids, each included at most once
+pub(crate) fn get_missing_blocks_many<C: ToSql + FromSql>(
+    txn: &Transaction,
+    cids: impl IntoIterator<Item = C>,
+) -> crate::Result<Vec<C>> {
+    // a temp table avoids the limit on the number of parameters
+    c!("creating missing_blocks roots" => txn.execute_batch(
+        "CREATE TEMP TABLE IF NOT EXISTS missing_roots (id INTEGER PRIMARY KEY); \
+        DELETE FROM temp.missing_roots;"
+    ));
+    {
+        let mut insert = txn
+            .prepare_cached("INSERT OR IGNORE INTO temp.missing_roots (id) VALUES (?)")
+            .ctx("adding missing_blocks root (prep)")?;
+        for cid in cids {
+            let id = c!("getting missing_blocks ID" => get_or_create_id(txn, cid));
+            insert.execute([id]).ctx("adding missing_blocks root")?;
+        }
+    }
+    let res = txn
+        .prepare_cached(
+            r#"
+            WITH RECURSIVE
+                desc(id) AS (
+                    SELECT id FROM temp.missing_roots
+                    UNION
+                    SELECT child_id FROM (
+                        SELECT parent_id, child_id FROM refs
+                        UNION ALL
+                        SELECT parent_id, child_id FROM pending_refs
+                    ), desc ON id = parent_id
+                ),
+                orphaned_ids AS (
+                    SELECT id FROM desc LEFT JOIN blocks ON id = block_id WHERE block_id IS NULL
+                )
+            SELECT cid FROM cids, orphaned_ids USING (id)
+            "#,
+        )
         .ctx("finding missing_blocks (prep)")?
-        .query_map([id], |row| row.get(0))
+        .query_map([], |row| row.get(0))
         .ctx("finding missing_blocks")?
         .collect::<rusqlite::Result<Vec<C>>>()
-        .ctx("parsing missing_blocks")?;
+        .ctx("parsing missing_blocks")?;
+    c!("clearing missing_blocks roots" => txn.execute_batch("DELETE FROM temp.missing_roots"));
+    Ok(res)
+}
+
+/// get up to `limit` missing descendants of a cid, ordered by cid and starting after `after`
+pub(crate) fn get_missing_blocks_chunked<C: ToSql + FromSql>(
+    txn: &Transaction,
+    cid: C,
+    limit: usize,
+    after: Option<C>,
+) -> crate::Result<Vec<C>> {
+    let id = c!("getting missing_blocks ID" => get_or_create_id(txn, cid));
+    let limit = i64::try_from(limit).unwrap_or(i64::MAX);
+    txn.prepare_cached(
+        r#"
+            WITH RECURSIVE
+                desc(id) AS (
+                    SELECT ?1
+                    UNION
+                    SELECT child_id FROM (
+                        SELECT parent_id, child_id FROM refs
+                        UNION ALL
+                        SELECT parent_id, child_id FROM pending_refs
+                    ), desc ON id = parent_id
+                ),
+                orphaned_ids AS (
+                    SELECT id FROM desc LEFT JOIN blocks ON id = block_id WHERE block_id IS NULL
+                )
+            SELECT cid FROM cids, orphaned_ids USING (id)
+                WHERE ?2 IS NULL OR cid > ?2 ORDER BY cid LIMIT ?3
+            "#,
+    )
+    .ctx("finding missing_blocks chunk (prep)")?
+    .query_map(params![id, after, limit], |row| row.get(0))
+    .ctx("finding missing_blocks chunk")?
+    .collect::<rusqlite::Result<Vec<C>>>()
+    .ctx("parsing missing_blocks chunk")
+}
+
+/// get the missing descendants of several cids, in the order they should be fetched
+///
+/// Blocks are ordered by the first root they are reachable from, then by their distance from
+/// that root, so the shallow blocks that reveal further links come first.
+pub(crate) fn wanted_blocks<C: ToSql + FromSql>(
+    txn: &Transaction,
+    cids: impl IntoIterator<Item = C>,
+) -> crate::Result<Vec<C>> {
+    c!("creating wanted blocks" => txn.execute_batch(
+        "CREATE TEMP TABLE IF NOT EXISTS wanted (\
+            id INTEGER PRIMARY KEY, priority INTEGER NOT NULL, depth INTEGER NOT NULL\
+        ); \
+        DELETE FROM temp.wanted;"
+    ));
+    {
+        let mut children = txn
+            .prepare_cached(
+                "SELECT child_id FROM refs WHERE parent_id = ?1 \
+                UNION ALL SELECT child_id FROM pending_refs WHERE parent_id = ?1",
+            )
+            .ctx("getting wanted children (prep)")?;
+        let mut insert = txn
+            .prepare_cached("INSERT INTO temp.wanted (id, priority, depth) VALUES (?, ?, ?)")
+            .ctx("adding wanted block (prep)")?;
+        // anything reachable from an id that was already visited has been visited from an
+        // earlier root, or at a smaller depth from the same one, so one set does for all roots
+        let mut visited = HashSet::new();
+        let mut pending = VecDeque::new();
+        for (priority, cid) in cids.into_iter().enumerate() {
+            let id = c!("getting wanted root ID" => get_or_create_id(txn, cid));
+            pending.push_back((id, 0i64));
+            while let Some((id, depth)) = pending.pop_front() {
+                if !visited.insert(id) {
+                    continue;
+                }
+                insert
+                    .execute([id, priority as i64, depth])
+                    .ctx("adding wanted block")?;
+                let ids = children
+                    .query_map([id], |row| row.get(0))
+                    .ctx("getting wanted children")?
+                    .collect::<rusqlite::Result<Vec<i64>>>()
+                    .ctx("parsing wanted children")?;
+                pending.extend(
+                    ids.into_iter()
+                        .filter(|id| !visited.contains(id))
+                        .map(|id| (id, depth + 1)),
+                );
+            }
+        }
+    }
+    let res = txn
+        .prepare_cached(
+            "SELECT cid FROM temp.wanted, cids USING (id) LEFT JOIN blocks ON id = block_id \
+            WHERE block_id IS NULL ORDER BY priority, depth, id",
+        )
+        .ctx("finding wanted blocks (prep)")?
+        .query_map([], |row| row.get(0))
+        .ctx("finding wanted blocks")?
+        .collect::<rusqlite::Result<Vec<C>>>()
+        .ctx("parsing wanted blocks")?;
+    c!("clearing wanted blocks" => txn.execute_batch("DELETE FROM temp.wanted"));
     Ok(res)
 }
 
+/// count the descendants of a cid, including itself, for which we do not have the data yet
+pub(crate) fn count_missing_blocks(txn: &Transaction, cid: impl ToSql) -> crate::Result<u64> {
+    let id = match c!("getting missing_blocks ID" => get_id(txn, cid)) {
+        Some(id) => id,
+        // a cid we have never seen is missing itself
+        None => return Ok(1),
+    };
+    let n: i64 = txn
+        .prepare_cached(
+            r#"
+            WITH RECURSIVE
+                desc(id) AS (
+                    SELECT ?
+                    UNION
+                    SELECT child_id FROM (
+                        SELECT parent_id, child_id FROM refs
+                        UNION ALL
+                        SELECT parent_id, child_id FROM pending_refs
+                    ), desc ON id = parent_id
+                )
+            SELECT COUNT(*) FROM desc LEFT JOIN blocks ON id = block_id WHERE block_id IS NULL
+            "#,
+        )
+        .ctx("counting missing_blocks (prep)")?
+        .query_row([id], |row| row.get(0))
+        .ctx("counting missing_blocks")?;
+    Ok(n as u64)
+}
+
+/// like get_missing_blocks, but only looking at most `max_depth` links below the cid
+pub(crate) fn get_missing_blocks_to_depth<C: ToSql + FromSql>(
+    txn: &Transaction,
+    cid: C,
+    max_depth: u32,
+) -> crate::Result<Vec<C>> {
+    let id = c!("getting missing_blocks ID" => get_or_create_id(txn, cid));
+    txn.prepare_cached(
+        r#"
+            WITH RECURSIVE
+                -- the same id may be found at several depths, which is bounded by max_depth
+                desc(id, depth) AS (
+                    SELECT ?1, 0
+                    UNION
+                    SELECT child_id, depth + 1 FROM (
+                        SELECT parent_id, child_id FROM refs
+                        UNION ALL
+                        SELECT parent_id, child_id FROM pending_refs
+                    ), desc ON id = parent_id
+                    WHERE depth < ?2
+                ),
+                orphaned_ids AS (
+                    SELECT DISTINCT id FROM desc LEFT JOIN blocks ON id = block_id
+                        WHERE block_id IS NULL
+                )
+            SELECT cid FROM cids, orphaned_ids USING (id)
+            "#,
+    )
+    .ctx("finding missing_blocks (prep)")?
+    .query_map(params![id, max_depth], |row| row.get(0))
+    .ctx("finding missing_blocks")?
+    .collect::<rusqlite::Result<Vec<C>>>()
+    .ctx("parsing missing_blocks")
+}
+
+/// set or delete an alias; without a mode, an existing alias keeps its mode and a new one is
+/// recursive
 pub(crate) fn alias<C: ToSql>(
     txn: &Transaction,
     name: &[u8],
     key: Option<&C>,
+    mode: Option<PinMode>,
 ) -> crate::Result<()> {
+    let max_depth = match mode {
+        None | Some(PinMode::Recursive) => None,
+        Some(PinMode::Direct) => Some(0),
+        Some(PinMode::Depth(depth)) => Some(depth),
+    };
     if let Some(key) = key {
         let id = c!("getting alias ID" => get_or_create_id(txn, key));
-        txn.prepare_cached("REPLACE INTO aliases (name, block_id) VALUES (?, ?)")
-            .ctx("setting alias (prep)")?
-            .execute(params![name, id])
-            .ctx("setting alias")?;
+        // not using REPLACE, since that would delete the alias_info
+        txn.prepare_cached(
+            "INSERT INTO aliases (name, block_id) VALUES (?, ?) \
+            ON CONFLICT (name) DO UPDATE SET block_id = excluded.block_id",
+        )
+        .ctx("setting alias (prep)")?
+        .execute(params![name, id])
+        .ctx("setting alias")?;
+        txn.prepare_cached(
+            "INSERT INTO alias_info (name, created, updated, max_depth) \
+            VALUES (?1, strftime('%s', 'now'), strftime('%s', 'now'), ?2) \
+            ON CONFLICT (name) DO UPDATE SET updated = excluded.updated, \
+            max_depth = CASE WHEN ?3 THEN excluded.max_depth ELSE max_depth END",
+        )
+        .ctx("setting alias info (prep)")?
+        .execute(params![name, max_depth, mode.is_some()])
+        .ctx("setting alias info")?;
+        log_change(txn, CHANGE_ALIAS_SET, Some(id), Some(name))?;
     } else {
-        txn.prepare_cached("DELETE FROM aliases WHERE name = ?")
+        txn.prepare_cached("DELETE FROM alias_info WHERE name = ?")
+            .ctx("removing alias info (prep)")?
+            .execute([name])
+            .ctx("removing alias info")?;
+        let n = txn
+            .prepare_cached("DELETE FROM aliases WHERE name = ?")
             .ctx("removing alias (prep)")?
             .execute([name])
             .ctx("removing alias")?;
+        if n > 0 {
+            log_change(txn, CHANGE_ALIAS_REMOVED, None, Some(name))?;
+        }
     }
     Ok(())
 }
 
+/// set or delete an alias, returning the previous root
+pub(crate) fn update_alias<C: ToSql + FromSql>(
+    txn: &Transaction,
+    name: &[u8],
+    key: Option<&C>,
+) -> crate::Result<Option<C>> {
+    let old = resolve(txn, name)?;
+    alias(txn, name, key, None)?;
+    Ok(old)
+}
+
+/// cid, created and updated timestamps, metadata and depth limit of an alias
+pub(crate) type AliasRow<C> = (C, Option<i64>, Option<i64>, Option<Vec<u8>>, Option<u32>);
+
+pub(crate) fn alias_info<C: FromSql>(
+    txn: &Transaction,
+    name: &[u8],
+) -> crate::Result<Option<AliasRow<C>>> {
+    txn.prepare_cached(
+        "SELECT cid, created, updated, metadata, max_depth \
+        FROM aliases JOIN cids ON id = block_id LEFT JOIN alias_info USING (name) \
+        WHERE name = ?",
+    )
+    .ctx("getting alias info (prep)")?
+    .query_row([name], |row| {
+        Ok((
+            row.get(0)?,
+            row.get(1)?,
+            row.get(2)?,
+            row.get(3)?,
+            row.get(4)?,
+        ))
+    })
+    .optional()
+    .ctx("getting alias info")
+}
+
+/// set the metadata of an existing alias, returns false if there is no such alias
+pub(crate) fn set_alias_metadata(
+    txn: &Transaction,
+    name: &[u8],
+    metadata: Option<&[u8]>,
+) -> crate::Result<bool> {
+    if !alias_exists(txn, name)? {
+        return Ok(false);
+    }
+    // aliases created before alias_info existed don’t have a row yet
+    txn.prepare_cached(
+        "INSERT INTO alias_info (name, created, updated, metadata) \
+        VALUES (?1, strftime('%s', 'now'), strftime('%s', 'now'), ?2) \
+        ON CONFLICT (name) DO UPDATE SET updated = excluded.updated, metadata = excluded.metadata",
+    )
+    .ctx("setting alias metadata (prep)")?
+    .execute(params![name, metadata])
+    .ctx("setting alias metadata")?;
+    Ok(true)
+}
+
+fn alias_exists(txn: &Transaction, name: &[u8]) -> crate::Result<bool> {
+    let n: i64 = txn
+        .prepare_cached("SELECT COUNT(*) FROM aliases WHERE name = ?")
+        .ctx("checking alias (prep)")?
+        .query_row([name], |row| row.get(0))
+        .ctx("checking alias")?;
+    Ok(n > 0)
+}
+
+/// move an alias to a new name, fails if the new name is already taken
+///
+/// returns false if there is no alias named `old`
+pub(crate) fn rename_alias(txn: &Transaction, old: &[u8], new: &[u8]) -> crate::Result<bool> {
+    if old == new {
+        return alias_exists(txn, old);
+    }
+    if alias_exists(txn, new)? {
+        return Err(BlockStoreError::AliasExists(new.to_vec()));
+    }
+    let n = txn
+        .prepare_cached("UPDATE aliases SET name = ? WHERE name = ?")
+        .ctx("renaming alias (prep)")?
+        .execute([new, old])
+        .ctx("renaming alias")?;
+    if n > 0 {
+        let id: i64 = txn
+            .prepare_cached("SELECT block_id FROM aliases WHERE name = ?")
+            .ctx("getting renamed alias (prep)")?
+            .query_row([new], |row| row.get(0))
+            .ctx("getting renamed alias")?;
+        log_change(txn, CHANGE_ALIAS_REMOVED, None, Some(old))?;
+        log_change(txn, CHANGE_ALIAS_SET, Some(id), Some(new))?;
+    }
+    Ok(n > 0)
+}
+
 pub(crate) fn resolve<C: FromSql>(txn: &Transaction, name: &[u8]) -> crate::Result<Option<C>> {
     txn.prepare_cached("SELECT cid FROM aliases, cids ON block_id = id WHERE name = ?")
         .ctx("resolving alias (prep)")?
@@ -710,11 +2413,22 @@ pub(crate) fn reverse_alias(
                 WITH RECURSIVE
                     ancestor_of(id) AS
                     (
-                        SELECT ?
+                        SELECT ?1
                         UNION
                         SELECT parent_id FROM refs, ancestor_of ON id = child_id
+                    ),
+                    near_ancestor_of(id, dist) AS
+                    (
+                        SELECT ?1, 0
+                        UNION
+                        SELECT parent_id, dist + 1 FROM refs, near_ancestor_of ON id = child_id
+                            WHERE dist < (SELECT MAX(max_depth) FROM alias_info)
                     )
-                SELECT name FROM ancestor_of, aliases ON id = block_id;
+                SELECT name FROM ancestor_of, aliases ON id = block_id
+                    LEFT JOIN alias_info USING (name) WHERE max_depth IS NULL
+                UNION
+                SELECT name FROM near_ancestor_of, aliases ON id = block_id
+                    JOIN alias_info USING (name) WHERE dist <= max_depth;
                 "#,
             )
             .ctx("getting reverse_alias (prep)")?
@@ -740,26 +2454,321 @@ pub(crate) fn get_ids(txn: &Transaction) -> crate::Result<Vec<i64>> {
 
 /// get all cids of blocks in the store
 pub(crate) fn get_block_cids<C: FromSql>(txn: &Transaction) -> crate::Result<Vec<C>> {
-    txn.prepare_cached("SELECT cid FROM cids JOIN blocks ON id = block_id")
-        .ctx("getting all CIDs (prep)")?
-        .query_map([], |row| row.get(0))
-        .ctx("getting all CIDs")?
-        .collect::<rusqlite::Result<Vec<C>>>()
-        .ctx("parsing all CIDs")
+    Ok(get_block_cids_limited(txn, Limit::default())?.0)
+}
+
+pub(crate) fn get_block_cids_limited<C: FromSql>(
+    txn: &Transaction,
+    limit: Limit,
+) -> crate::Result<(Vec<C>, bool)> {
+    let mut stmt = txn
+        .prepare_cached("SELECT cid FROM cids JOIN blocks ON id = block_id")
+        .ctx("getting all CIDs (prep)")?;
+    let rows = stmt.query([]).ctx("getting all CIDs")?;
+    collect_limited(rows, limit).ctx("parsing all CIDs")
 }
 
 /// get all cids that we know about, even ones that we don't have a block for
 pub(crate) fn get_known_cids<C: FromSql>(txn: &Transaction) -> crate::Result<Vec<C>> {
-    txn.prepare_cached("SELECT cid FROM cids")
-        .ctx("getting known CIDs (prep)")?
-        .query_map([], |row| row.get(0))
-        .ctx("getting known CIDs")?
+    Ok(get_known_cids_limited(txn, Limit::default())?.0)
+}
+
+pub(crate) fn get_known_cids_limited<C: FromSql>(
+    txn: &Transaction,
+    limit: Limit,
+) -> crate::Result<(Vec<C>, bool)> {
+    let mut stmt = txn
+        .prepare_cached("SELECT cid FROM cids")
+        .ctx("getting known CIDs (prep)")?;
+    let rows = stmt.query([]).ctx("getting known CIDs")?;
+    collect_limited(rows, limit).ctx("parsing known CIDs")
+}
+
+/// get up to `limit` cids with an id greater than `after`, ordered by id
+pub(crate) fn get_known_cids_page<C: FromSql>(
+    txn: &Transaction,
+    after: i64,
+    limit: usize,
+) -> crate::Result<Vec<(i64, C)>> {
+    let limit = i64::try_from(limit).unwrap_or(i64::MAX);
+    txn.prepare_cached("SELECT id, cid FROM cids WHERE id > ? ORDER BY id LIMIT ?")
+        .ctx("getting known CIDs page (prep)")?
+        .query_map([after, limit], |row| Ok((row.get(0)?, row.get(1)?)))
+        .ctx("getting known CIDs page")?
+        .collect::<rusqlite::Result<Vec<_>>>()
+        .ctx("parsing known CIDs page")
+}
+
+/// get blocks with an id greater than `after`, ordered by id
+///
+/// stops after `max_blocks` blocks or once `max_bytes` is exceeded, but returns at least one block.
+pub(crate) fn get_blocks_page<C: FromSql>(
+    txn: &Transaction,
+    after: i64,
+    max_blocks: usize,
+    max_bytes: usize,
+) -> crate::Result<Vec<(i64, C, Vec<u8>)>> {
+    let mut stmt = txn
+        .prepare_cached(
+            "SELECT block_id, cid, block FROM blocks, cids ON block_id = id \
+            WHERE block_id > ? ORDER BY block_id",
+        )
+        .ctx("getting blocks page (prep)")?;
+    let mut rows = stmt.query([after]).ctx("getting blocks page")?;
+    let mut res = Vec::new();
+    let mut bytes = 0usize;
+    while res.len() < max_blocks && bytes < max_bytes {
+        let row = match rows.next().ctx("getting blocks page")? {
+            Some(row) => row,
+            None => break,
+        };
+        let data: Vec<u8> = row.get(2).ctx("parsing blocks page")?;
+        bytes = bytes.saturating_add(data.len());
+        res.push((
+            row.get(0).ctx("parsing blocks page")?,
+            row.get(1).ctx("parsing blocks page")?,
+            data,
+        ));
+    }
+    Ok(res)
+}
+
+/// sequence number, id, cid and data of a block
+pub(crate) type SequencedBlock<C> = (i64, i64, C, Vec<u8>);
+
+/// get up to `limit` blocks added after the sequence number `after`, in insertion order
+pub(crate) fn get_blocks_since<C: FromSql>(
+    txn: &Transaction,
+    after: i64,
+    limit: usize,
+) -> crate::Result<Vec<SequencedBlock<C>>> {
+    let limit = i64::try_from(limit).unwrap_or(i64::MAX);
+    txn.prepare_cached(
+        "SELECT seq, block_id, cid, block FROM block_seq, blocks USING (block_id), cids \
+        ON block_id = id WHERE seq > ? ORDER BY seq LIMIT ?",
+    )
+    .ctx("getting blocks since (prep)")?
+    .query_map([after, limit], |row| {
+        Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+    })
+    .ctx("getting blocks since")?
+    .collect::<rusqlite::Result<Vec<_>>>()
+    .ctx("parsing blocks since")
+}
+
+/// the kinds of entries in the changes table
+pub(crate) const CHANGE_PUT: i64 = 0;
+pub(crate) const CHANGE_DELETE: i64 = 1;
+pub(crate) const CHANGE_ALIAS_SET: i64 = 2;
+pub(crate) const CHANGE_ALIAS_REMOVED: i64 = 3;
+
+/// append an entry to the changes table, with the cid of `block_id` if given
+fn log_change(
+    txn: &Transaction,
+    op: i64,
+    block_id: Option<i64>,
+    name: Option<&[u8]>,
+) -> crate::Result<()> {
+    txn.prepare_cached(
+        "INSERT INTO changes (op, cid, name, time) \
+        VALUES (?1, (SELECT cid FROM cids WHERE id = ?2), ?3, strftime('%s', 'now'))",
+    )
+    .ctx("logging change (prep)")?
+    .execute(params![op, block_id, name])
+    .ctx("logging change")?;
+    Ok(())
+}
+
+/// sequence number, kind, cid, alias name and time of a change
+pub(crate) type ChangeRow<C> = (i64, i64, Option<C>, Option<Vec<u8>>, i64);
+
+/// get up to `limit` changes with a sequence number greater than `after`, oldest first
+pub(crate) fn changes_since<C: FromSql>(
+    txn: &Transaction,
+    after: i64,
+    limit: usize,
+) -> crate::Result<Vec<ChangeRow<C>>> {
+    let limit = i64::try_from(limit).unwrap_or(i64::MAX);
+    txn.prepare_cached(
+        "SELECT seq, op, cid, name, time FROM changes WHERE seq > ? ORDER BY seq LIMIT ?",
+    )
+    .ctx("getting changes since (prep)")?
+    .query_map([after, limit], |row| {
+        Ok((
+            row.get(0)?,
+            row.get(1)?,
+            row.get(2)?,
+            row.get(3)?,
+            row.get(4)?,
+        ))
+    })
+    .ctx("getting changes since")?
+    .collect::<rusqlite::Result<Vec<_>>>()
+    .ctx("parsing changes since")
+}
+
+/// delete the changes with a sequence number less than `before`, returning how many
+pub(crate) fn truncate_changes(txn: &Transaction, before: i64) -> crate::Result<usize> {
+    txn.prepare_cached("DELETE FROM changes WHERE seq < ?")
+        .ctx("truncating changes (prep)")?
+        .execute([before])
+        .ctx("truncating changes")
+}
+
+/// get size, presence and number of links of a block, None if the cid is not known
+pub(crate) fn block_stat(txn: &Transaction, cid: impl ToSql) -> crate::Result<Option<BlockStat>> {
+    txn.prepare_cached(
+        "SELECT LENGTH(block), block_id IS NOT NULL, \
+            (SELECT COUNT(*) FROM refs WHERE parent_id = id) \
+        FROM cids LEFT JOIN blocks ON id = block_id WHERE cid = ?",
+    )
+    .ctx("getting block stat (prep)")?
+    .query_row([cid], |row| {
+        Ok(BlockStat {
+            size: row.get::<_, Option<i64>>(0)?.unwrap_or_default() as u64,
+            has_data: row.get(1)?,
+            link_count: row.get::<_, i64>(2)? as u64,
+        })
+    })
+    .optional()
+    .ctx("getting block stat")
+}
+
+/// get number and size of the blocks reachable from a cid
+pub(crate) fn dag_stats(txn: &Transaction, cid: impl ToSql) -> crate::Result<DagStats> {
+    match c!("getting dag_stats ID" => get_id(txn, cid)) {
+        Some(id) => dag_stats_of_id(txn, id, None),
+        None => Ok(DagStats {
+            missing: 1,
+            ..DagStats::default()
+        }),
+    }
+}
+
+/// get number and size of the blocks pinned by an alias, respecting its max depth
+pub(crate) fn alias_dag_stats(txn: &Transaction, name: &[u8]) -> crate::Result<Option<DagStats>> {
+    let root = txn
+        .prepare_cached(
+            "SELECT block_id, max_depth FROM aliases LEFT JOIN alias_info USING (name) \
+            WHERE name = ?",
+        )
+        .ctx("getting alias root (prep)")?
+        .query_row([name], |row| Ok((row.get::<_, i64>(0)?, row.get(1)?)))
+        .optional()
+        .ctx("getting alias root")?;
+    match root {
+        Some((id, max_depth)) => Ok(Some(dag_stats_of_id(txn, id, max_depth)?)),
+        None => Ok(None),
+    }
+}
+
+/// get number and size of the blocks reachable from a block id, down to `max_depth` links if given
+fn dag_stats_of_id(txn: &Transaction, id: i64, max_depth: Option<u32>) -> crate::Result<DagStats> {
+    txn.prepare_cached(
+        r#"
+        WITH RECURSIVE
+            -- depth is the number of levels still to go below id, NULL for unlimited
+            descendant_of(id, depth) AS
+            (
+                SELECT ?, ?
+                UNION
+                SELECT child_id, depth - 1 FROM refs, descendant_of ON id = parent_id
+                    WHERE depth IS NULL OR depth > 0
+            )
+        SELECT COUNT(block_id), COALESCE(SUM(LENGTH(block)), 0), COUNT(*) - COUNT(block_id)
+            FROM (SELECT DISTINCT id FROM descendant_of) LEFT JOIN blocks ON id = block_id;
+        "#,
+    )
+    .ctx("getting dag stats (prep)")?
+    .query_row(params![id, max_depth], |row| {
+        Ok(DagStats {
+            blocks: row.get::<_, i64>(0)? as u64,
+            bytes: row.get::<_, i64>(1)? as u64,
+            missing: row.get::<_, i64>(2)? as u64,
+        })
+    })
+    .ctx("getting dag stats")
+}
+
+/// get the cids of the blocks with the given codec
+pub(crate) fn cids_by_codec<C: FromSql>(txn: &Transaction, codec: u64) -> crate::Result<Vec<C>> {
+    txn.prepare_cached("SELECT cid FROM block_cid_info, cids ON block_id = id WHERE codec = ?")
+        .ctx("getting CIDs by codec (prep)")?
+        .query_map([codec as i64], |row| row.get(0))
+        .ctx("getting CIDs by codec")?
         .collect::<rusqlite::Result<Vec<C>>>()
-        .ctx("parsing known CIDs")
+        .ctx("parsing CIDs by codec")
+}
+
+/// get the number and size of blocks per codec
+pub(crate) fn codec_stats(txn: &Transaction) -> crate::Result<Vec<CodecStats>> {
+    txn.prepare_cached(
+        "SELECT codec, COUNT(*), SUM(LENGTH(block)) FROM block_cid_info, blocks USING (block_id) \
+        GROUP BY codec ORDER BY codec",
+    )
+    .ctx("getting codec stats (prep)")?
+    .query_map([], |row| {
+        Ok(CodecStats {
+            codec: row.get::<_, i64>(0)? as u64,
+            count: row.get::<_, i64>(1)? as u64,
+            size: row.get::<_, i64>(2)? as u64,
+        })
+    })
+    .ctx("getting codec stats")?
+    .collect::<rusqlite::Result<Vec<_>>>()
+    .ctx("parsing codec stats")
+}
+
+/// get the `n` largest blocks with their sizes, largest first
+pub(crate) fn largest_blocks<C: FromSql>(
+    txn: &Transaction,
+    n: usize,
+) -> crate::Result<Vec<(C, u64)>> {
+    let n = i64::try_from(n).unwrap_or(i64::MAX);
+    // the length is taken from the record header, so this does not read the data
+    txn.prepare_cached(
+        "SELECT cid, LENGTH(block) AS len FROM blocks, cids ON block_id = id \
+        ORDER BY len DESC LIMIT ?",
+    )
+    .ctx("getting largest blocks (prep)")?
+    .query_map([n], |row| Ok((row.get(0)?, row.get::<_, i64>(1)? as u64)))
+    .ctx("getting largest blocks")?
+    .collect::<rusqlite::Result<Vec<_>>>()
+    .ctx("parsing largest blocks")
+}
+
+/// get the cids in binary form that start with `prefix`, in ascending order
+pub(crate) fn find_cids_with_prefix<C: FromSql>(
+    txn: &Transaction,
+    prefix: &[u8],
+    limit: Limit,
+) -> crate::Result<(Vec<C>, bool)> {
+    // the smallest blob greater than all blobs with the prefix, unless the prefix is all 0xff
+    let mut end = prefix.to_vec();
+    while end.last() == Some(&0xff) {
+        end.pop();
+    }
+    // separate statements, so that both bounds can be used for the index range
+    if let Some(last) = end.last_mut() {
+        *last += 1;
+        let mut stmt = txn
+            .prepare_cached("SELECT cid FROM cids WHERE cid >= ? AND cid < ? ORDER BY cid")
+            .ctx("finding CIDs by prefix (prep)")?;
+        let rows = stmt
+            .query(params![prefix, end])
+            .ctx("finding CIDs by prefix")?;
+        collect_limited(rows, limit).ctx("parsing CIDs by prefix")
+    } else {
+        let mut stmt = txn
+            .prepare_cached("SELECT cid FROM cids WHERE cid >= ? ORDER BY cid")
+            .ctx("finding CIDs by prefix (prep)")?;
+        let rows = stmt.query([prefix]).ctx("finding CIDs by prefix")?;
+        collect_limited(rows, limit).ctx("parsing CIDs by prefix")
+    }
 }
 
 pub(crate) fn aliases<C: FromSql>(txn: &Transaction) -> crate::Result<Vec<(Vec<u8>, C)>> {
-    txn.prepare_cached("SELECT name, cid FROM aliases JOIN cids ON id = block_id")
+    txn.prepare_cached("SELECT name, cid FROM aliases JOIN cids ON id = block_id ORDER BY name")
         .ctx("getting aliases (prep)")?
         .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
         .ctx("getting aliases")?
@@ -769,17 +2778,26 @@ pub(crate) fn aliases<C: FromSql>(txn: &Transaction) -> crate::Result<Vec<(Vec<u
 
 pub(crate) fn vacuum(conn: &mut Connection) -> crate::Result<()> {
     let _span = tracing::debug_span!("vacuuming the db").entered();
-    conn.execute("VACUUM;", []).ctx("running VACUUM")?;
+    // converts DBs created without incremental auto_vacuum
+    conn.execute_batch("PRAGMA auto_vacuum = 2; VACUUM;")
+        .ctx("running VACUUM")?;
     Ok(())
 }
 
+/// set up a connection; read-only connections leave the journal mode and the WAL to the writers
 pub(crate) fn init_pragmas(
     conn: &mut Connection,
     is_memory: bool,
     cache_pages: i64,
+    read_only: bool,
 ) -> crate::Result<()> {
-    c!("running pragmas" => conn.execute_batch(PRAGMAS));
-    c!("setting cache_pages" => conn.pragma_update(None, "cache_pages", &cache_pages));
+    if read_only {
+        // checkpointing needs to write, which fails while another process has a live WAL
+        c!("running pragmas" => conn.execute_batch("PRAGMA foreign_keys = ON;"));
+    } else {
+        c!("running pragmas" => conn.execute_batch(PRAGMAS));
+    }
+    c!("setting cache_pages" => conn.pragma_update(None, "cache_pages", cache_pages));
 
     let foreign_keys: i64 = c!("getting foreign_keys" => conn.pragma_query_value(None, "foreign_keys", |row| row.get(0)));
     let journal_mode: String = c!("getting journal_mode" => conn.pragma_query_value(None, "journal_mode", |row| row.get(0)));
@@ -875,7 +2893,7 @@ fn ensure_tables(txn: &Transaction, tables: &[(&str, &str)]) -> crate::Result<()
     tracing::debug!("schema now read-write");
     for (name, sql) in tables {
         changed |=
-            ensure_table(txn, *name, *sql).with_context(|| format!("ensuring table {}", name))?;
+            ensure_table(txn, name, sql).with_context(|| format!("ensuring table {}", name))?;
     }
     tracing::debug!("schemas checked");
 
@@ -894,6 +2912,40 @@ fn ensure_tables(txn: &Transaction, tables: &[(&str, &str)]) -> crate::Result<()
     Ok(())
 }
 
+/// delete the rows that would have been deleted by a cascade if foreign keys had been on
+///
+/// Up to v2 the main connection of a store ran with foreign keys off, so e.g. gc left the refs
+/// of collected blocks behind, and the blocks they point to could never be collected.
+fn delete_dangling_rows(txn: &Transaction) -> crate::Result<()> {
+    loop {
+        // a cascade can leave rows behind that refer to the ones deleted here
+        let rows = c!("checking foreign keys" => txn
+        .prepare(
+            "SELECT DISTINCT c.\"table\", c.rowid \
+            FROM pragma_foreign_key_check c, pragma_foreign_key_list(c.\"table\") l \
+            WHERE l.id = c.fkid AND l.on_delete = 'CASCADE' AND c.rowid IS NOT NULL",
+        )
+        .and_then(|mut stmt| {
+            stmt.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?)))?
+                .collect::<rusqlite::Result<Vec<_>>>()
+        }));
+        if rows.is_empty() {
+            return Ok(());
+        }
+        tracing::info!(
+            rows = rows.len(),
+            "deleting rows left behind without foreign keys"
+        );
+        for (table, rowid) in rows {
+            // the table name has been reported by sqlite, but better be safe
+            if TABLES.iter().any(|(t, _)| *t == table) {
+                c!("deleting dangling row" => txn
+                    .execute(&format!("DELETE FROM \"{}\" WHERE rowid = ?", table), [rowid]));
+            }
+        }
+    }
+}
+
 fn migrate_v0_v1(txn: &Transaction) -> crate::Result<()> {
     let num_blocks: i64 = c!("getting block count" => txn.query_row("SELECT COUNT(*) FROM blocks_v0", [], |r| r.get(0)));
     let mut stmt = c!("getting old blocks (prep)" => txn.prepare("SELECT * FROM blocks_v0"));
@@ -915,13 +2967,17 @@ fn migrate_v0_v1(txn: &Transaction) -> crate::Result<()> {
         block
             .references(&mut set)
             .context("extracting references")?;
+        let links = set
+            .iter()
+            .map(|cid| Ok((CidBytes::try_from(cid)?, 1)))
+            .collect::<crate::Result<Vec<_>>>()?;
         put_block(
             txn,
-            &block.cid().to_bytes(),
+            &mut Ids::uncached(),
+            &CidBytes::try_from(block.cid())?,
+            block.cid(),
             block.data(),
-            set.into_iter()
-                .map(|cid| cid.to_bytes())
-                .collect::<Vec<_>>(),
+            links,
             None,
         )?;
     }
@@ -932,6 +2988,294 @@ fn migrate_v0_v1(txn: &Transaction) -> crate::Result<()> {
     Ok(())
 }
 
+fn insert_cid_info(txn: &Transaction, block_id: i64, cid: &Cid) -> crate::Result<()> {
+    txn.prepare_cached(
+        "INSERT OR REPLACE INTO block_cid_info (block_id, multihash, codec, hash_code) \
+        VALUES (?, ?, ?, ?)",
+    )
+    .ctx("adding cid info (prep)")?
+    .execute(params![
+        block_id,
+        cid.hash().to_bytes(),
+        cid.codec() as i64,
+        cid.hash().code() as i64
+    ])
+    .ctx("adding cid info")?;
+    Ok(())
+}
+
+/// record the cid info of all blocks, for stores created before it was tracked
+fn fill_block_cid_info(txn: &Transaction) -> crate::Result<()> {
+    let mut select = c!("getting block cids (prep)" =>
+        txn.prepare("SELECT id, cid FROM cids, blocks ON id = block_id"));
+    let rows = c!("getting block cids" =>
+        select.query_map([], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, CidBytes>(1)?))));
+    for row in rows {
+        let (id, cid) = c!("reading block cid" => row);
+        insert_cid_info(txn, id, &Cid::try_from(&cid)?)?;
+    }
+    Ok(())
+}
+
+/// copies the content of the attached `merge_source` store that is missing locally, in the
+/// order of the dependencies between the tables
+const MERGE: &[(&str, &str)] = &[
+    (
+        "adding merged cids",
+        "INSERT OR IGNORE INTO main.cids (cid) SELECT cid FROM merge_source.cids",
+    ),
+    (
+        "mapping merged cids",
+        "CREATE TEMP TABLE merge_ids AS \
+        SELECT l.id AS id, s.id AS source_id FROM merge_source.cids s JOIN main.cids l USING (cid)",
+    ),
+    (
+        "indexing merged cids",
+        "CREATE INDEX temp.idx_merge_ids_source_id ON merge_ids (source_id)",
+    ),
+    (
+        "finding merged blocks",
+        "CREATE TEMP TABLE merge_blocks AS \
+        SELECT m.id AS id, m.source_id AS source_id FROM temp.merge_ids m \
+            JOIN merge_source.blocks b ON b.block_id = m.source_id \
+        WHERE m.id NOT IN (SELECT block_id FROM main.blocks)",
+    ),
+    (
+        "adding merged blocks",
+        "INSERT INTO main.blocks (block_id, block) \
+        SELECT m.id, b.block FROM temp.merge_blocks m \
+            JOIN merge_source.blocks b ON b.block_id = m.source_id",
+    ),
+    (
+        "adding merged block times",
+        "INSERT OR REPLACE INTO main.block_times (block_id, added) \
+        SELECT id, strftime('%s', 'now') FROM temp.merge_blocks",
+    ),
+    (
+        "adding merged sequence numbers",
+        "INSERT OR REPLACE INTO main.block_seq (block_id) \
+        SELECT id FROM temp.merge_blocks ORDER BY source_id",
+    ),
+    (
+        "deleting merged pending links",
+        "DELETE FROM main.pending_refs WHERE parent_id IN (SELECT id FROM temp.merge_blocks)",
+    ),
+    (
+        "adding merged links",
+        "INSERT INTO main.refs (parent_id, child_id) \
+        SELECT m.id, c.id FROM temp.merge_blocks m \
+            JOIN merge_source.refs r ON r.parent_id = m.source_id \
+            JOIN temp.merge_ids c ON c.source_id = r.child_id",
+    ),
+    (
+        "adding merged link counts",
+        "INSERT INTO main.ref_counts (parent_id, child_id, count) \
+        SELECT m.id, c.id, r.count FROM temp.merge_blocks m \
+            JOIN merge_source.ref_counts r ON r.parent_id = m.source_id \
+            JOIN temp.merge_ids c ON c.source_id = r.child_id",
+    ),
+    (
+        "adding merged pending links",
+        "INSERT OR IGNORE INTO main.pending_refs (parent_id, child_id) \
+        SELECT p.id, c.id FROM merge_source.pending_refs r \
+            JOIN temp.merge_ids p ON p.source_id = r.parent_id \
+            JOIN temp.merge_ids c ON c.source_id = r.child_id \
+        WHERE p.id NOT IN (SELECT block_id FROM main.blocks)",
+    ),
+    (
+        "updating merged stats",
+        "UPDATE main.stats SET \
+            count = count + (SELECT COUNT(*) FROM temp.merge_blocks), \
+            size = size + (SELECT COALESCE(SUM(LENGTH(block)), 0) FROM main.blocks \
+                WHERE block_id IN (SELECT id FROM temp.merge_blocks))",
+    ),
+    (
+        "finding merged aliases",
+        "CREATE TEMP TABLE merge_aliases AS \
+        SELECT a.name AS name, m.id AS id FROM merge_source.aliases a \
+            JOIN temp.merge_ids m ON m.source_id = a.block_id \
+        WHERE a.name NOT IN (SELECT name FROM main.aliases)",
+    ),
+    (
+        "adding merged aliases",
+        "INSERT INTO main.aliases (name, block_id) SELECT name, id FROM temp.merge_aliases",
+    ),
+    (
+        "adding merged alias info",
+        "INSERT INTO main.alias_info (name, created, updated, metadata, max_depth) \
+        SELECT name, created, updated, metadata, max_depth FROM merge_source.alias_info \
+        WHERE name IN (SELECT name FROM temp.merge_aliases)",
+    ),
+];
+
+/// the blocks (id, cid, size) and aliases added by [`merge_from_file`]
+#[derive(Debug, Default)]
+pub(crate) struct Merged {
+    pub(crate) blocks: Vec<(i64, Cid, usize)>,
+    pub(crate) aliases: Vec<(Vec<u8>, Cid)>,
+}
+
+/// checks the data of a block against its cid
+pub(crate) type Verify = Arc<dyn Fn(&Cid, &[u8]) -> crate::Result<()> + Send + Sync>;
+
+/// copy the blocks and aliases of the store at `path` that are missing here, in one transaction
+///
+/// aliases that exist in both stores keep their local target.
+pub(crate) fn merge_from_file(
+    conn: &mut Connection,
+    path: &Path,
+    verify: Option<Verify>,
+) -> crate::Result<Merged> {
+    let _span = tracing::debug_span!("merging", path = %path.display()).entered();
+    let path = path
+        .to_str()
+        .ok_or_else(|| anyhow::anyhow!("merge path {} is not valid UTF-8", path.display()))?;
+    // can’t be done inside a transaction
+    conn.execute("ATTACH DATABASE ? AS merge_source", [path])
+        .ctx("attaching merge source")?;
+    let res = in_txn(conn, None, true, move |txn| {
+        merge_attached(txn, verify.as_ref())
+    });
+    let detached = conn
+        .execute_batch("DETACH DATABASE merge_source")
+        .ctx("detaching merge source");
+    let merged = res?;
+    detached?;
+    Ok(merged)
+}
+
+fn merge_attached(txn: &Transaction, verify: Option<&Verify>) -> crate::Result<Merged> {
+    let version: u32 = c!("getting merge source version" => txn.pragma_query_value(
+        Some(rusqlite::DatabaseName::Attached("merge_source")),
+        "user_version",
+        |row| row.get(0),
+    ));
+    if version > SCHEMA_VERSION {
+        return Err(BlockStoreError::UnsupportedSchemaVersion(version));
+    }
+    if version != SCHEMA_VERSION {
+        return Err(anyhow::anyhow!(
+            "cannot merge DB version {} (opening it as a block store migrates it)",
+            version
+        )
+        .into());
+    }
+    // the version says nothing about files that were changed by hand or other tools
+    let mut missing = Vec::new();
+    for (table, _) in TABLES {
+        let exists: bool = c!("checking merge source tables" => txn.query_row(
+            "SELECT COUNT(*) > 0 FROM merge_source.sqlite_master WHERE type = 'table' AND name = ?",
+            [table],
+            |row| row.get(0),
+        ));
+        if !exists {
+            missing.push(*table);
+        }
+    }
+    if !missing.is_empty() {
+        return Err(
+            anyhow::anyhow!("cannot merge DB lacking the tables {}", missing.join(", ")).into(),
+        );
+    }
+    for (label, sql) in MERGE {
+        txn.execute_batch(sql).ctx(label)?;
+    }
+    let mut merged = Merged::default();
+    {
+        let mut stmt = c!("getting merged blocks (prep)" => txn.prepare(
+            "SELECT m.id, cid, LENGTH(block), CASE WHEN ? THEN block END \
+                FROM temp.merge_blocks m \
+                JOIN main.cids c ON c.id = m.id JOIN main.blocks ON block_id = m.id \
+            ORDER BY m.source_id",
+        ));
+        let rows = c!("getting merged blocks" => stmt.query_map([verify.is_some()], |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                row.get::<_, CidBytes>(1)?,
+                row.get::<_, i64>(2)?,
+                row.get::<_, Option<Vec<u8>>>(3)?,
+            ))
+        }));
+        for row in rows {
+            let (id, cid, len, data) = c!("reading merged block" => row);
+            let cid = Cid::try_from(&cid)?;
+            if let (Some(verify), Some(data)) = (verify, data) {
+                verify(&cid, &data)?;
+            }
+            insert_cid_info(txn, id, &cid)?;
+            log_change(txn, CHANGE_PUT, Some(id), None)?;
+            merged.blocks.push((id, cid, len as usize));
+        }
+        let mut stmt = c!("getting merged aliases (prep)" => txn.prepare(
+            "SELECT name, m.id, cid FROM temp.merge_aliases m JOIN main.cids c ON c.id = m.id \
+            ORDER BY name",
+        ));
+        let rows = c!("getting merged aliases" => stmt.query_map([], |row| {
+            Ok((row.get::<_, Vec<u8>>(0)?, row.get::<_, i64>(1)?, row.get::<_, CidBytes>(2)?))
+        }));
+        for row in rows {
+            let (name, id, cid) = c!("reading merged alias" => row);
+            log_change(txn, CHANGE_ALIAS_SET, Some(id), Some(&name))?;
+            merged.aliases.push((name, Cid::try_from(&cid)?));
+        }
+    }
+    c!("dropping merge tables" => txn.execute_batch(
+        "DROP TABLE temp.merge_ids; DROP TABLE temp.merge_blocks; DROP TABLE temp.merge_aliases;"
+    ));
+    Ok(merged)
+}
+
+/// get the cid and data of a block with the given multihash
+pub(crate) fn get_block_by_multihash<C: FromSql>(
+    txn: &Transaction,
+    multihash: &[u8],
+) -> crate::Result<Option<(i64, C, Vec<u8>)>> {
+    txn.prepare_cached(
+        "SELECT id, cid, block FROM block_cid_info, cids, blocks \
+        ON block_cid_info.block_id = id AND id = blocks.block_id \
+        WHERE multihash = ? ORDER BY id LIMIT 1",
+    )
+    .ctx("getting block by multihash (prep)")?
+    .query_row([multihash], |row| {
+        Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+    })
+    .optional()
+    .ctx("getting block by multihash")
+}
+
+fn get_store_id(txn: &Transaction) -> rusqlite::Result<Option<String>> {
+    txn.prepare_cached("SELECT uuid FROM store_id WHERE id = 0")?
+        .query_row([], |row| row.get(0))
+        .optional()
+}
+
+/// a random (version 4) UUID in its usual hyphenated form
+fn new_uuid(txn: &Transaction) -> crate::Result<String> {
+    let mut bytes: Vec<u8> =
+        c!("generating store id" => txn.query_row("SELECT randomblob(16)", [], |row| row.get(0)));
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex = bytes
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<String>();
+    Ok(format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    ))
+}
+
+/// returns the id generated when the store was created
+pub(crate) fn store_id(txn: &Transaction) -> crate::Result<String> {
+    get_store_id(txn)
+        .ctx("getting store id")?
+        .ok_or_else(|| BlockStoreError::Other(anyhow::anyhow!("store has no id")))
+}
+
 pub(crate) fn init_db(
     conn: &mut Connection,
     is_memory: bool,
@@ -941,24 +3285,26 @@ pub(crate) fn init_db(
     let _span = tracing::debug_span!("initializing db").entered();
 
     // can’t be done inside a transaction
-    init_pragmas(conn, is_memory, cache_pages)?;
-    conn.pragma_update(None, "synchronous", &synchronous.to_string())
+    init_pragmas(conn, is_memory, cache_pages, false)?;
+    conn.pragma_update(None, "synchronous", synchronous.to_string())
         .ctx("setting Synchronous mode")?;
 
     c!("foreign keys off" => conn.pragma_update(None, "foreign_keys", false));
 
     in_txn(conn, Some(("init", Duration::from_secs(1))), true, |txn| {
         let user_version = c!("getting user_version" => user_version(txn));
-        if user_version > 2 {
-            return Err(anyhow::anyhow!(
-                "found future DB version {} (downgrades are not supported)",
-                user_version
-            )
-            .into());
+        if user_version > SCHEMA_VERSION {
+            return Err(BlockStoreError::UnsupportedSchemaVersion(user_version));
         }
 
         let migrate =
             user_version == 0 && c!("checking table `blocks`" => table_exists(txn, "blocks"));
+        // v3 adds the tables for ref counts, alias info, block times and so on, which are
+        // created and filled below; older versions must not write to the store afterwards,
+        // since they would not keep them up to date
+        if user_version == 2 {
+            tracing::info!("executing migration from v2 to v3");
+        }
         if migrate {
             tracing::info!("executing migration from v0 to v1");
             c!("renaming blocks to v0" => txn.execute_batch("ALTER TABLE blocks RENAME TO blocks_v0"));
@@ -966,9 +3312,45 @@ pub(crate) fn init_db(
             c!("dropping refs table" => txn.execute_batch("DROP TABLE IF EXISTS refs"));
         }
 
+        let backfill_seq = !c!("checking table `block_seq`" => table_exists(txn, "block_seq"));
+        let backfill_cid_info = !c!("checking table `block_cid_info`" =>
+            table_exists(txn, "block_cid_info"));
+        let backfill_changes = !c!("checking table `changes`" => table_exists(txn, "changes"));
         ensure_tables(txn, TABLES)?;
+        if user_version == 2 {
+            // the connection is switched to foreign keys on below
+            delete_dangling_rows(txn)?;
+        }
+        if backfill_seq {
+            // the best guess for the insertion order of existing blocks
+            c!("filling block_seq" => txn.execute_batch(
+                "INSERT INTO block_seq (block_id) SELECT block_id FROM blocks ORDER BY block_id"
+            ));
+        }
+        if backfill_cid_info {
+            fill_block_cid_info(txn)?;
+        }
+        if backfill_changes {
+            // start the log with the current content, so it can be replayed from the beginning
+            c!("filling changes" => txn.execute(
+                "INSERT INTO changes (op, cid, name, time) \
+                SELECT ?1, cid, NULL, strftime('%s', 'now') \
+                    FROM block_seq, cids ON block_id = id ORDER BY seq",
+                [CHANGE_PUT],
+            ));
+            c!("filling changes" => txn.execute(
+                "INSERT INTO changes (op, cid, name, time) \
+                SELECT ?1, cid, name, strftime('%s', 'now') \
+                    FROM aliases, cids ON block_id = id ORDER BY name",
+                [CHANGE_ALIAS_SET],
+            ));
+        }
+        if c!("getting store id" => get_store_id(txn)).is_none() {
+            let uuid = new_uuid(txn)?;
+            c!("setting store id" =>
+                txn.execute("INSERT INTO store_id (id, uuid) VALUES (0, ?1)", [uuid]));
+        }
         c!(DEBUG "creating indexes" => txn.execute_batch(INIT));
-        c!(DEBUG "cleaning up temp pins" => txn.execute_batch(CLEANUP_TEMP_PINS));
         if let Err(BlockStoreError::SqliteError(QueryReturnedNoRows, _)) = get_store_stats(txn) {
             c!("faking store stats" => txn.execute_batch("INSERT INTO stats VALUES (0, 0);"));
         }
@@ -980,7 +3362,8 @@ pub(crate) fn init_db(
         Ok(())
     })?;
 
-    c!("foreign keys on" => conn.pragma_update(None, "foreign_keys", false));
+    // up to v2 they were left off here, see `delete_dangling_rows` for the rows this left behind
+    c!("foreign keys on" => conn.pragma_update(None, "foreign_keys", true));
     Ok(())
 }
 
@@ -1014,18 +3397,21 @@ where
     F: for<'a> Fn(&'a Transaction) -> crate::Result<T> + 'static,
 {
     let _span = if let Some(name) = name.map(|x| x.0).filter(|x| !x.is_empty()) {
-        tracing::debug_span!("txn", "{}", name).entered()
+        tracing::debug_span!("txn", operation = name, immediate).entered()
     } else {
-        tracing::trace_span!("txn").entered()
+        tracing::trace_span!("txn", immediate).entered()
     };
     let started = Instant::now();
     let mut attempts = 0;
+    let mut retry_budget = None;
     loop {
-        let txn = c!("beginning transaction" =>
-            if immediate { conn.transaction_with_behavior(TransactionBehavior::Immediate) } else { conn.transaction() }
-        );
-        let result = f(&txn);
-        let result = result.and_then(|t| {
+        let txn = if immediate {
+            conn.transaction_with_behavior(TransactionBehavior::Immediate)
+        } else {
+            conn.transaction()
+        };
+        let result = txn.ctx("beginning transaction").and_then(|txn| {
+            let t = f(&txn)?;
             c!("committing transaction" => txn.commit());
             Ok(t)
         });
@@ -1035,30 +3421,73 @@ where
                 if let Some((name, expected)) = name {
                     let dt = started.elapsed();
                     if dt > expected {
-                        tracing::info!("{} took {}ms", name, dt.as_millis());
+                        tracing::info!(
+                            operation = name,
+                            elapsed_ms = dt.as_millis() as u64,
+                            expected_ms = expected.as_millis() as u64,
+                            "slow transaction"
+                        );
                     }
                 }
                 break Ok(value);
             }
-            Err(BlockStoreError::SqliteError(SqliteFailure(e, _), _)) if e.code == DatabaseBusy => {
+            Err(BlockStoreError::SqliteError(SqliteFailure(e, _), msg))
+                if e.code == DatabaseBusy || e.code == DatabaseLocked =>
+            {
+                // retry for as long as sqlite would wait for a lock
+                let budget = *retry_budget.get_or_insert_with(|| busy_timeout(conn));
+                if attempts >= MIN_BUSY_ATTEMPTS && started.elapsed() >= budget {
+                    tracing::warn!(attempts, operation = msg, "database busy, giving up");
+                    break Err(BlockStoreError::Busy(msg));
+                }
+                if attempts > 1 {
+                    std::thread::sleep(busy_backoff(attempts));
+                }
                 if attempts > 3 && started.elapsed().as_millis() > 100 {
                     tracing::warn!(
-                        "getting starved ({} attempts so far, {}ms)",
                         attempts,
-                        started.elapsed().as_millis()
+                        elapsed_ms = started.elapsed().as_millis() as u64,
+                        operation = msg,
+                        "transaction getting starved"
                     );
                 } else {
-                    tracing::debug!("retrying transaction {:?}", name);
+                    tracing::debug!(attempts, operation = msg, "retrying busy transaction");
                 }
             }
+            Err(BlockStoreError::SqliteError(SqliteFailure(e, _), _))
+                if e.code == OperationInterrupted =>
+            {
+                tracing::debug!("transaction cancelled");
+                break Err(BlockStoreError::Cancelled);
+            }
+            Err(BlockStoreError::SqliteError(SqliteFailure(e, _), msg)) if e.code == DiskFull => {
+                tracing::warn!(operation = msg, "transaction rolled back, disk full");
+                break Err(BlockStoreError::DiskFull(msg));
+            }
             Err(cause) => {
-                tracing::error!("transaction rolled back! {:#}", cause);
+                tracing::error!(error = %format!("{:#}", cause), "transaction rolled back");
                 break Err(cause);
             }
         }
     }
 }
 
+/// the number of attempts of a busy transaction before giving up, even with a zero busy timeout
+const MIN_BUSY_ATTEMPTS: u32 = 3;
+
+fn busy_timeout(conn: &Connection) -> Duration {
+    conn.pragma_query_value(None, "busy_timeout", |row| row.get::<_, i64>(0))
+        .ok()
+        .and_then(|ms| u64::try_from(ms).ok())
+        .map(Duration::from_millis)
+        .unwrap_or_default()
+}
+
+/// exponential backoff from 1ms up to 64ms
+pub(crate) fn busy_backoff(attempts: u32) -> Duration {
+    Duration::from_millis(1 << attempts.saturating_sub(2).min(6))
+}
+
 #[cfg(test)]
 #[allow(unused)]
 fn p(c: &Transaction, s: &str) {
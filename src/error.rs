@@ -25,6 +25,33 @@ pub enum BlockStoreError {
     TryFromIntError(std::num::TryFromIntError, &'static str),
     #[display(fmt = "cannot open additional connection for in-memory DB")]
     NoAdditionalInMemory,
+    /// The operation was aborted via a [`CancellationToken`](crate::CancellationToken) or by the
+    /// [statement watchdog](crate::Config::with_statement_watchdog)
+    #[display(fmt = "operation cancelled")]
+    Cancelled,
+    /// An alias with this name already exists
+    #[display(fmt = "alias {:?} already exists", "String::from_utf8_lossy(_0)")]
+    AliasExists(Vec<u8>),
+    /// The disk or the configured maximum DB size is full
+    ///
+    /// The transaction has been rolled back, so the store is still consistent. Deleting data,
+    /// e.g. via gc with smaller size targets, makes room for new writes. Writes of blocks can do
+    /// that automatically, see [`with_gc_on_disk_full`](crate::Config::with_gc_on_disk_full).
+    #[display(fmt = "database or disk is full while {}", _0)]
+    DiskFull(&'static str),
+    /// The database stayed locked by other connections for longer than the
+    /// [busy timeout](crate::Config::with_busy_timeout)
+    #[display(fmt = "database is busy while {}", _0)]
+    Busy(&'static str),
+    /// The database was written by a newer version of this library with an unknown schema
+    ///
+    /// Downgrades are not supported, since the old code could misinterpret or corrupt the data.
+    #[display(
+        fmt = "DB schema version {} is newer than the supported version {}",
+        _0,
+        "crate::db::SCHEMA_VERSION"
+    )]
+    UnsupportedSchemaVersion(u32),
     /// Other error
     Other(anyhow::Error),
 }
@@ -49,6 +76,11 @@ impl std::error::Error for BlockStoreError {
             BlockStoreError::TryFromIntError(e, _) => Some(e),
             BlockStoreError::Other(e) => AsRef::<dyn Error>::as_ref(e).source(),
             BlockStoreError::NoAdditionalInMemory => None,
+            BlockStoreError::Cancelled => None,
+            BlockStoreError::DiskFull(_) => None,
+            BlockStoreError::Busy(_) => None,
+            BlockStoreError::UnsupportedSchemaVersion(_) => None,
+            BlockStoreError::AliasExists(_) => None,
         }
     }
 }
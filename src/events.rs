@@ -0,0 +1,110 @@
+use crate::{
+    block_cache::BlockCache,
+    cache::{BlockInfo, CacheTracker, WriteInfo},
+    counters::AtomicCounters,
+};
+use libipld::Cid;
+use parking_lot::Mutex;
+use std::{
+    fmt,
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc,
+    },
+};
+
+/// A change to the store, see [`subscribe`](crate::BlockStore::subscribe)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StoreEvent {
+    /// The data of a block was added
+    BlockAdded(Cid),
+    /// The data of a block was deleted by gc or a purge
+    BlockRemoved(Cid),
+    /// An alias was set to point to a cid
+    AliasSet(Vec<u8>, Cid),
+    /// An alias was removed
+    AliasRemoved(Vec<u8>),
+}
+
+/// the subscribers of all connections opened from the same store
+#[derive(Clone, Default)]
+pub(crate) struct Events(Arc<Mutex<Vec<Sender<StoreEvent>>>>);
+
+impl fmt::Debug for Events {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Events").finish()
+    }
+}
+
+impl Events {
+    pub(crate) fn subscribe(&self) -> Receiver<StoreEvent> {
+        let (sender, receiver) = mpsc::channel();
+        self.0.lock().push(sender);
+        receiver
+    }
+
+    pub(crate) fn emit(&self, events: impl IntoIterator<Item = StoreEvent>) {
+        let mut subscribers = self.0.lock();
+        if subscribers.is_empty() {
+            return;
+        }
+        for event in events {
+            // forget about subscribers that have dropped their receiver
+            subscribers.retain(|s| s.send(event.clone()).is_ok());
+        }
+    }
+
+    /// wrap a cache tracker, so that the blocks deleted by gc are reported as events, counted
+    /// and evicted from the block cache
+    pub(crate) fn tracker<'a, T: CacheTracker>(
+        &'a self,
+        inner: &'a T,
+        counters: &'a AtomicCounters,
+        block_cache: &'a BlockCache,
+    ) -> EventTracker<'a, T> {
+        EventTracker {
+            inner,
+            events: self,
+            counters,
+            block_cache,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct EventTracker<'a, T> {
+    inner: &'a T,
+    events: &'a Events,
+    counters: &'a AtomicCounters,
+    block_cache: &'a BlockCache,
+}
+
+impl<'a, T: CacheTracker> CacheTracker for EventTracker<'a, T> {
+    fn blocks_accessed(&self, blocks: Vec<BlockInfo>) {
+        self.inner.blocks_accessed(blocks)
+    }
+
+    fn blocks_written(&self, blocks: Vec<WriteInfo>) {
+        self.inner.blocks_written(blocks)
+    }
+
+    fn blocks_deleted(&self, blocks: Vec<BlockInfo>) {
+        AtomicCounters::add(&self.counters.blocks_deleted, blocks.len() as u64);
+        self.block_cache.remove(blocks.iter().map(|b| b.cid()));
+        self.events
+            .emit(blocks.iter().map(|b| StoreEvent::BlockRemoved(*b.cid())));
+        self.inner.blocks_deleted(blocks)
+    }
+
+    fn sort_ids(&self, ids: &mut [i64]) {
+        self.inner.sort_ids(ids)
+    }
+
+    fn has_persistent_state(&self) -> bool {
+        self.inner.has_persistent_state()
+    }
+
+    fn retain_ids(&self, ids: &[i64]) {
+        self.inner.retain_ids(ids)
+    }
+}
@@ -0,0 +1,188 @@
+use crate::{BlockStore, Result};
+use fnv::{FnvHashMap, FnvHashSet};
+use libipld::{codec::References, store::StoreParams, Block, Cid, Ipld};
+use std::{fmt, sync::Arc};
+
+type Predicate = Arc<dyn Fn(&Cid, &[u8]) -> bool + Send + Sync>;
+
+/// Which part of a dag to [`export`](BlockStore::export)
+///
+/// A block that does not match is neither exported nor traversed, so none of the blocks below
+/// it are exported unless they can be reached through other, matching blocks. The default
+/// selects the whole dag.
+#[derive(Clone, Default)]
+pub struct Selector {
+    max_depth: Option<u32>,
+    codecs: Option<FnvHashSet<u64>>,
+    max_bytes: Option<u64>,
+    predicate: Option<Predicate>,
+}
+
+impl fmt::Debug for Selector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Selector")
+            .field("max_depth", &self.max_depth)
+            .field("codecs", &self.codecs)
+            .field("max_bytes", &self.max_bytes)
+            .field("predicate", &self.predicate.is_some())
+            .finish()
+    }
+}
+
+impl Selector {
+    /// Only select blocks up to `depth` links below the root, 0 selecting only the root
+    pub fn with_max_depth(mut self, depth: u32) -> Self {
+        self.max_depth = Some(depth);
+        self
+    }
+
+    /// Only select blocks with one of the given codecs
+    pub fn with_codecs(mut self, codecs: impl IntoIterator<Item = u64>) -> Self {
+        self.codecs = Some(codecs.into_iter().collect());
+        self
+    }
+
+    /// Stop the export before the total size of the exported blocks exceeds `bytes`
+    pub fn with_max_bytes(mut self, bytes: u64) -> Self {
+        self.max_bytes = Some(bytes);
+        self
+    }
+
+    /// Only select blocks for which `predicate` returns true, given their cid and data
+    pub fn with_predicate<F>(mut self, predicate: F) -> Self
+    where
+        F: Fn(&Cid, &[u8]) -> bool + Send + Sync + 'static,
+    {
+        self.predicate = Some(Arc::new(predicate));
+        self
+    }
+
+    /// whether the block may match, before its data has been read
+    fn admits(&self, cid: &Cid, depth: u32) -> bool {
+        self.max_depth.map_or(true, |max| depth <= max)
+            && self
+                .codecs
+                .as_ref()
+                .map_or(true, |codecs| codecs.contains(&cid.codec()))
+    }
+}
+
+/// An iterator over the blocks selected from a dag, see [`export`](BlockStore::export)
+pub struct Export<'a, S> {
+    store: &'a mut BlockStore<S>,
+    selector: Selector,
+    // depth first, so the blocks come in the same order as in a CAR file
+    pending: Vec<(Cid, u32)>,
+    // the smallest depth each block was traversed at, since a shallower path may select more
+    // blocks below it when there is a max depth
+    visited: FnvHashMap<Cid, u32>,
+    exported: FnvHashSet<Cid>,
+    bytes: u64,
+    done: bool,
+}
+
+impl<'a, S> fmt::Debug for Export<'a, S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Export")
+            .field("selector", &self.selector)
+            .field("pending", &self.pending.len())
+            .field("bytes", &self.bytes)
+            .finish()
+    }
+}
+
+impl<'a, S> Export<'a, S> {
+    pub(crate) fn new(store: &'a mut BlockStore<S>, root: Cid, selector: Selector) -> Self {
+        Self {
+            store,
+            selector,
+            pending: vec![(root, 0)],
+            visited: FnvHashMap::default(),
+            exported: FnvHashSet::default(),
+            bytes: 0,
+            done: false,
+        }
+    }
+
+    /// Total size of the blocks exported so far
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    /// whether `cid` needs to be traversed at `depth`, recording it if so
+    fn visit(&mut self, cid: Cid, depth: u32) -> bool {
+        match self.visited.get_mut(&cid) {
+            // without a max depth there is nothing more to select on a shorter path
+            Some(_) if self.selector.max_depth.is_none() => false,
+            Some(min) if *min <= depth => false,
+            Some(min) => {
+                *min = depth;
+                true
+            }
+            None => {
+                self.visited.insert(cid, depth);
+                true
+            }
+        }
+    }
+}
+
+impl<'a, S> Iterator for Export<'a, S>
+where
+    S: StoreParams,
+    Ipld: References<S::Codecs>,
+{
+    type Item = Result<Block<S>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.done {
+            let (cid, depth) = self.pending.pop()?;
+            if !self.selector.admits(&cid, depth) || !self.visit(cid, depth) {
+                continue;
+            }
+            let data = match self.store.get_block(&cid) {
+                Ok(Some(data)) => data,
+                // missing blocks are skipped, like when the dag is incomplete
+                Ok(None) => continue,
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            };
+            if let Some(predicate) = &self.selector.predicate {
+                if !predicate(&cid, &data) {
+                    continue;
+                }
+            }
+            let exported = self.exported.contains(&cid);
+            let bytes = self.bytes + data.len() as u64;
+            if !exported && self.selector.max_bytes.map_or(false, |max| bytes > max) {
+                self.done = true;
+                return None;
+            }
+            let block = Block::<S>::new_unchecked(cid, data);
+            let mut links = Vec::new();
+            if let Err(e) = block.references(&mut links) {
+                self.done = true;
+                return Some(Err(e.into()));
+            }
+            // reversed, so that the first link is visited first
+            let visited = &self.visited;
+            self.pending.extend(
+                links
+                    .into_iter()
+                    .rev()
+                    .filter(|link| visited.get(link).map_or(true, |d| *d > depth + 1))
+                    .map(|link| (link, depth + 1)),
+            );
+            // a block reached again on a shorter path is only traversed again
+            if exported {
+                continue;
+            }
+            self.exported.insert(cid);
+            self.bytes = bytes;
+            return Some(Ok(block));
+        }
+        None
+    }
+}
@@ -0,0 +1,166 @@
+//! Generation of synthetic dags for tests and benchmarks
+//!
+//! This module is only available with the `fixtures` feature. The generated blocks are encoded
+//! as dag-cbor and hashed with sha2-256, so the store params must support both.
+use crate::{BlockStore, Result};
+use libipld::{
+    cbor::DagCborCodec,
+    codec::{Codec, References},
+    store::StoreParams,
+    Block, Cid, Ipld,
+};
+use multihash::{Code, MultihashDigest};
+use std::{collections::BTreeMap, ops::RangeInclusive};
+
+/// The shape of a synthetic dag, see [`generate`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct DagShape {
+    depth: usize,
+    fanout: usize,
+    block_size: RangeInclusive<usize>,
+    shared_ratio: f64,
+    seed: u64,
+}
+
+impl Default for DagShape {
+    fn default() -> Self {
+        Self {
+            depth: 3,
+            fanout: 4,
+            block_size: 100..=1000,
+            shared_ratio: 0.0,
+            seed: 0,
+        }
+    }
+}
+
+impl DagShape {
+    /// Number of levels below the root, a depth of 0 is a single block
+    pub fn with_depth(mut self, depth: usize) -> Self {
+        self.depth = depth;
+        self
+    }
+    /// Number of children of each non-leaf block
+    pub fn with_fanout(mut self, fanout: usize) -> Self {
+        self.fanout = fanout;
+        self
+    }
+    /// Range of payload sizes, chosen uniformly for each block
+    pub fn with_block_size(mut self, block_size: RangeInclusive<usize>) -> Self {
+        self.block_size = block_size;
+        self
+    }
+    /// Probability of a child being a previously generated subtree of the same depth
+    ///
+    /// With a ratio of 0 the dag is a tree, with a ratio of 1 each level consists of a single
+    /// block that is linked `fanout` times from its parent.
+    pub fn with_shared_ratio(mut self, shared_ratio: f64) -> Self {
+        self.shared_ratio = shared_ratio.clamp(0.0, 1.0);
+        self
+    }
+    /// Seed for the random choices; the same shape always generates the same dag
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+}
+
+/// small deterministic PRNG (xorshift64*), good enough for generating test data
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        // splitmix64, so that similar seeds give unrelated states
+        let mut z = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        // xorshift is stuck at a zero state
+        Self((z ^ (z >> 31)).max(1))
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    fn range(&mut self, range: &RangeInclusive<usize>) -> usize {
+        let span = (range.end().saturating_sub(*range.start()) as u64).saturating_add(1);
+        range.start() + (self.next() % span) as usize
+    }
+
+    fn chance(&mut self, p: f64) -> bool {
+        let x = (self.next() >> 11) as f64 / (1u64 << 53) as f64;
+        x < p
+    }
+}
+
+struct Generator<'a, S> {
+    blocks: Vec<Block<S>>,
+    shape: &'a DagShape,
+    rng: Rng,
+    // previously generated subtrees by depth, for sharing
+    subtrees: BTreeMap<usize, Vec<Cid>>,
+    count: usize,
+}
+
+impl<'a, S> Generator<'a, S>
+where
+    S: StoreParams,
+    Ipld: References<S::Codecs>,
+{
+    fn node(&mut self, depth: usize) -> Result<Cid> {
+        let mut links = Vec::new();
+        if depth > 0 {
+            for _ in 0..self.shape.fanout {
+                let candidates = self.subtrees.get(&(depth - 1)).map_or(0, Vec::len);
+                let child = if candidates > 0 && self.rng.chance(self.shape.shared_ratio) {
+                    let i = self.rng.next() as usize % candidates;
+                    self.subtrees[&(depth - 1)][i]
+                } else {
+                    self.node(depth - 1)?
+                };
+                links.push(Ipld::Link(child));
+            }
+        }
+        let size = self.rng.range(&self.shape.block_size);
+        let mut data = Vec::with_capacity(size);
+        while data.len() < size {
+            data.extend_from_slice(&self.rng.next().to_le_bytes());
+        }
+        data.truncate(size);
+        // the counter makes sure that blocks are distinct even for tiny payloads
+        let ipld = Ipld::List(vec![
+            Ipld::Integer(self.count as i128),
+            Ipld::Bytes(data),
+            Ipld::List(links),
+        ]);
+        self.count += 1;
+        let bytes = DagCborCodec.encode(&ipld)?;
+        let cid = Cid::new_v1(DagCborCodec.into(), Code::Sha2_256.digest(&bytes));
+        self.blocks.push(Block::new_unchecked(cid, bytes));
+        self.subtrees.entry(depth).or_default().push(cid);
+        Ok(cid)
+    }
+}
+
+/// Generate a dag of the given shape in a single transaction and return its root
+///
+/// The dag is not pinned, so it is up to the caller to alias the root if it should survive gc.
+pub fn generate<S>(store: &mut BlockStore<S>, shape: &DagShape) -> Result<Cid>
+where
+    S: StoreParams,
+    Ipld: References<S::Codecs>,
+{
+    let mut generator = Generator {
+        blocks: Vec::new(),
+        shape,
+        rng: Rng::new(shape.seed),
+        subtrees: BTreeMap::new(),
+        count: 0,
+    };
+    let root = generator.node(shape.depth)?;
+    store.put_blocks(generator.blocks, None)?;
+    Ok(root)
+}
@@ -0,0 +1,107 @@
+use crate::{SharedBlockStore, TempPin};
+use async_trait::async_trait;
+use libipld::{codec::References, error::BlockNotFound, store::StoreParams, Block, Cid, Ipld};
+use parking_lot::Mutex;
+use std::{fmt, sync::Arc};
+
+/// A temp pin that can be cloned and shared between threads, as the libipld `Store` requires
+pub type SharedTempPin = Arc<Mutex<TempPin>>;
+
+/// An adapter implementing the libipld [`Store`](libipld::store::Store) trait
+///
+/// This only stores blocks locally, so `insert` does not publish a block and `fetch` and `sync`
+/// fail with [`BlockNotFound`] instead of getting missing blocks from the network. The
+/// operations block the calling thread while accessing the database, including the async ones.
+pub struct IpldStore<S>(Arc<SharedBlockStore<S>>);
+
+impl<S> Clone for IpldStore<S> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<S> fmt::Debug for IpldStore<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("IpldStore").field(&self.0).finish()
+    }
+}
+
+impl<S> IpldStore<S> {
+    pub fn new(store: SharedBlockStore<S>) -> Self {
+        Self(Arc::new(store))
+    }
+
+    /// The underlying store, e.g. for the operations not covered by the trait
+    pub fn store(&self) -> &SharedBlockStore<S> {
+        &self.0
+    }
+}
+
+#[async_trait]
+impl<S> libipld::store::Store for IpldStore<S>
+where
+    S: StoreParams,
+    Ipld: References<S::Codecs>,
+{
+    type Params = S;
+    type TempPin = SharedTempPin;
+
+    fn create_temp_pin(&self) -> libipld::Result<Self::TempPin> {
+        Ok(Arc::new(Mutex::new(self.0.temp_pin())))
+    }
+
+    fn temp_pin(&self, tmp: &Self::TempPin, cid: &Cid) -> libipld::Result<()> {
+        let mut pin = tmp.lock();
+        Ok(self.0.write(|store| store.extend_temp_pin(&mut pin, cid))?)
+    }
+
+    fn contains(&self, cid: &Cid) -> libipld::Result<bool> {
+        Ok(self.0.has_block(cid)?)
+    }
+
+    fn get(&self, cid: &Cid) -> libipld::Result<Block<S>> {
+        match self.0.get_block(cid)? {
+            Some(data) => Ok(Block::new_unchecked(*cid, data)),
+            None => Err(BlockNotFound(*cid).into()),
+        }
+    }
+
+    fn insert(&self, block: &Block<S>) -> libipld::Result<()> {
+        Ok(self.0.put_block(block.clone(), None)?)
+    }
+
+    fn alias<T: AsRef<[u8]> + Send + Sync>(
+        &self,
+        alias: T,
+        cid: Option<&Cid>,
+    ) -> libipld::Result<()> {
+        Ok(self.0.write(|store| store.alias(alias.as_ref(), cid))?)
+    }
+
+    fn resolve<T: AsRef<[u8]> + Send + Sync>(&self, alias: T) -> libipld::Result<Option<Cid>> {
+        Ok(self.0.read(|store| store.resolve(alias.as_ref()))?)
+    }
+
+    fn reverse_alias(&self, cid: &Cid) -> libipld::Result<Option<Vec<Vec<u8>>>> {
+        let aliases = self.0.read(|store| store.reverse_alias(cid))?;
+        Ok(aliases.map(|aliases| aliases.into_iter().collect()))
+    }
+
+    async fn flush(&self) -> libipld::Result<()> {
+        Ok(self.0.write(|store| store.flush())?)
+    }
+
+    async fn fetch(&self, cid: &Cid) -> libipld::Result<Block<S>> {
+        self.get(cid)
+    }
+
+    async fn sync(&self, cid: &Cid) -> libipld::Result<()> {
+        let missing = self
+            .0
+            .read(|store| store.get_missing_blocks::<Vec<Cid>>(cid))?;
+        match missing.first() {
+            Some(cid) => Err(BlockNotFound(*cid).into()),
+            None => Ok(()),
+        }
+    }
+}
@@ -46,30 +46,85 @@
 //!
 //! For blocking usage, use [BlockStore](BlockStore). This is the most low level interface.
 //!
+//! ## Multiple handles
+//!
+//! A store can be used from several threads by obtaining a connection per thread via
+//! [additional_connection](BlockStore::additional_connection). Opening the same file several
+//! times within one process is equivalent: all handles share temp pins and statistics, and only
+//! the first one performs startup tasks like schema migration and clearing temp pins left
+//! behind by a previous process.
+//!
+//! All handles may read concurrently, but there is only ever a single writer: write transactions
+//! are serialized by SQLite, a handle that wants to write waits until the current writer is done.
+//! Long write transactions (e.g. putting many blocks at once) therefore delay all other writers.
+//!
 //! # Major differences to the go-ipfs pinning concept
 //!
 //! - Pinning/aliasing a root does not require that the dag is complete
 //! - Aliases/named pins as opposed to unnamed and non-reference-counted pins
 //! - Temporary pins as a mechanism to keep blocks safe from gc while a tree is being constructed
+mod block_cache;
 pub mod cache;
+mod car;
 mod cidbytes;
+mod counters;
 mod db;
 mod error;
+mod events;
+mod export;
+#[cfg(feature = "fixtures")]
+pub mod fixtures;
+#[cfg(feature = "ipld-store")]
+mod ipld_store;
+mod missing_cache;
+mod overlay;
+mod self_test;
+mod session;
+mod shared;
+mod snapshot;
+#[cfg(feature = "sql-cid")]
+mod sql_cid;
+mod standby;
+mod sync;
 #[cfg(test)]
 mod tests;
 mod transaction;
+mod wantlist;
+mod watchdog;
+mod write_buffer;
+mod writer_thread;
 
-use cache::{CacheTracker, NoopCacheTracker};
+use block_cache::BlockCache;
+use cache::{BlockInfo, CacheTracker, NoopCacheTracker, WriteInfo};
+pub use car::CarIndex;
+use cidbytes::CidBytes;
+use counters::AtomicCounters;
+pub use counters::Counters;
 use db::*;
 use error::Context;
 pub use error::{BlockStoreError, Result};
-use libipld::{codec::References, store::StoreParams, Block, Cid, Ipld};
+use events::Events;
+pub use events::StoreEvent;
+pub use export::{Export, Selector};
+use fnv::FnvHashMap;
+#[cfg(feature = "ipld-store")]
+pub use ipld_store::{IpldStore, SharedTempPin};
+use libipld::{codec::References, multihash::Multihash, store::StoreParams, Block, Cid, Ipld};
+use missing_cache::MissingCache;
+pub use overlay::Overlay;
 use parking_lot::Mutex;
 use rusqlite::{Connection, DatabaseName, OpenFlags};
+pub use self_test::{self_test, SelfTestReport};
+pub use shared::SharedBlockStore;
+pub use snapshot::{Snapshot, SnapshotBlocks};
+#[cfg(feature = "sql-cid")]
+pub use sql_cid::{SqlCid, MAX_SQL_CID_SIZE};
 use std::{
     borrow::Cow,
-    collections::HashSet,
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    convert::TryFrom,
     fmt,
+    io::Write,
     iter::FromIterator,
     marker::PhantomData,
     mem,
@@ -77,12 +132,18 @@ use std::{
     path::{Path, PathBuf},
     sync::{
         atomic::{AtomicBool, Ordering},
-        Arc,
+        Arc, Weak,
     },
-    time::Duration,
+    time::{Duration, Instant, SystemTime},
 };
+pub use sync::StoreDiff;
 use tracing::*;
-pub use transaction::Transaction;
+use transaction::verify_hash;
+pub use transaction::{Transaction, WriteTransaction};
+pub use wantlist::Wantlist;
+use watchdog::Watchdog;
+pub use write_buffer::{Acked, WriteBuffer};
+pub use writer_thread::{Pending, WriteHandle};
 
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub enum DbPath {
@@ -137,8 +198,8 @@ impl SizeTargets {
     /// Size targets that can not be reached. This can be used to disable gc.
     pub fn max_value() -> Self {
         Self {
-            count: u64::max_value(),
-            size: u64::max_value(),
+            count: u64::MAX,
+            size: u64::MAX,
         }
     }
 }
@@ -161,12 +222,133 @@ impl fmt::Display for Synchronous {
     }
 }
 
+/// How hard [`checkpoint`](BlockStore::checkpoint) tries to move the WAL into the DB file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheckpointMode {
+    /// checkpoint as much as possible without waiting for readers or writers
+    Passive,
+    /// wait for writers, then checkpoint everything that no reader still needs
+    Full,
+    /// like `Full`, then also wait for readers so that the next writer starts at the beginning
+    /// of the WAL
+    Restart,
+    /// like `Restart`, then also truncate the WAL file to zero bytes
+    Truncate,
+}
+
+impl fmt::Display for CheckpointMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            CheckpointMode::Passive => "PASSIVE",
+            CheckpointMode::Full => "FULL",
+            CheckpointMode::Restart => "RESTART",
+            CheckpointMode::Truncate => "TRUNCATE",
+        })
+    }
+}
+
+/// The outcome of a [`checkpoint`](BlockStore::checkpoint)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CheckpointResult {
+    pub(crate) busy: bool,
+    pub(crate) wal_frames: u64,
+    pub(crate) checkpointed_frames: u64,
+}
+
+impl CheckpointResult {
+    /// Whether the checkpoint could not complete because of other readers or writers
+    ///
+    /// This is typically caused by a long-running read transaction on another connection.
+    pub fn busy(&self) -> bool {
+        self.busy
+    }
+
+    /// Number of frames in the WAL
+    pub fn wal_frames(&self) -> u64 {
+        self.wal_frames
+    }
+
+    /// Number of frames in the WAL that have been written to the DB file
+    pub fn checkpointed_frames(&self) -> u64 {
+        self.checkpointed_frames
+    }
+}
+
+/// The verdict of a gc filter about a block that is eligible for garbage collection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GcDecision {
+    /// let gc delete the block
+    Delete,
+    /// keep the block for now, it will be considered again by the next gc run
+    Keep,
+}
+
+#[derive(Clone)]
+pub(crate) struct GcFilter(Arc<dyn Fn(&Cid) -> GcDecision + Send + Sync>);
+
+impl GcFilter {
+    pub(crate) fn keep(&self, cid: &Cid) -> bool {
+        (self.0)(cid) == GcDecision::Keep
+    }
+}
+
+impl fmt::Debug for GcFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GcFilter").finish()
+    }
+}
+
+type Hasher = Arc<dyn Fn(&[u8]) -> Vec<u8> + Send + Sync>;
+
+/// user supplied digest implementations for verifying hashes, by multihash code
+#[derive(Clone, Default)]
+pub(crate) struct Hashers(FnvHashMap<u64, Hasher>);
+
+impl Hashers {
+    /// the digest of `data` for the multihash `code`, if there is a hasher for it
+    pub(crate) fn digest(&self, code: u64, data: &[u8]) -> Option<Vec<u8>> {
+        self.0.get(&code).map(|hasher| hasher(data))
+    }
+}
+
+impl fmt::Debug for Hashers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.0.keys()).finish()
+    }
+}
+
+/// the SQLCipher key, kept out of the debug output of the config
+#[cfg(feature = "sqlcipher")]
+#[derive(Clone)]
+pub(crate) struct EncryptionKey(Arc<str>);
+
+#[cfg(feature = "sqlcipher")]
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EncryptionKey").finish()
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Config {
     size_targets: SizeTargets,
     cache_tracker: Arc<dyn CacheTracker>,
+    gc_filter: Option<GcFilter>,
+    gc_grace_period: Duration,
+    gc_on_disk_full: Option<(usize, Duration)>,
+    verify_hashes: bool,
+    hashers: Hashers,
+    link_multiplicity: bool,
+    statement_watchdog: Option<(Duration, bool)>,
     pragma_synchronous: Synchronous,
     pragma_cache_pages: u64,
+    wal_autocheckpoint: Option<u64>,
+    checkpoint_wal_size: Option<u64>,
+    busy_timeout: Option<Duration>,
+    block_cache_size: u64,
+    missing_cache: (usize, Duration),
+    #[cfg(feature = "sqlcipher")]
+    encryption_key: Option<EncryptionKey>,
     // open in readonly mode
     read_only: bool,
     // create if it does not yet exist
@@ -178,8 +360,22 @@ impl Default for Config {
         Self {
             size_targets: Default::default(),
             cache_tracker: Arc::new(NoopCacheTracker),
+            gc_filter: None,
+            gc_grace_period: Duration::ZERO,
+            gc_on_disk_full: None,
+            verify_hashes: false,
+            hashers: Hashers::default(),
+            link_multiplicity: false,
+            statement_watchdog: None,
             pragma_synchronous: Synchronous::Full, // most conservative setting
             pragma_cache_pages: 8192, // 32 megabytes with the default page size of 4096
+            wal_autocheckpoint: None,
+            checkpoint_wal_size: None,
+            busy_timeout: None,
+            block_cache_size: 0,
+            missing_cache: (0, Duration::ZERO),
+            #[cfg(feature = "sqlcipher")]
+            encryption_key: None,
             read_only: false,
             create: true,
         }
@@ -201,6 +397,80 @@ impl Config {
         self.cache_tracker = Arc::new(cache_tracker);
         self
     }
+    /// Set a callback that gc consults before deleting each unpinned block
+    ///
+    /// This allows vetoing the deletion of specific blocks, e.g. those currently being served to a
+    /// peer, without having to keep temp pins for all of them. The callback is invoked while
+    /// holding the write lock on the database, so it must be fast.
+    pub fn with_gc_filter<F>(mut self, filter: F) -> Self
+    where
+        F: Fn(&Cid) -> GcDecision + Send + Sync + 'static,
+    {
+        self.gc_filter = Some(GcFilter(Arc::new(filter)));
+        self
+    }
+    /// Set a minimum age for unpinned blocks to be eligible for gc
+    ///
+    /// This protects blocks that were just added from being collected before the caller had a
+    /// chance to add their parent or an alias, without needing a temp pin for every insert. The
+    /// age is tracked with a resolution of one second. Blocks added by versions of this crate
+    /// without this feature are always considered old enough.
+    pub fn with_gc_grace_period(mut self, value: Duration) -> Self {
+        self.gc_grace_period = value;
+        self
+    }
+    /// Run an [`incremental_gc`](BlockStore::incremental_gc) with the given arguments when
+    /// writing blocks fails because the disk is full, then retry the write once
+    ///
+    /// This applies to [`put_block`](BlockStore::put_block), [`put_blocks`](BlockStore::put_blocks)
+    /// and [`add_tree`](BlockStore::add_tree). If the retry fails as well, the
+    /// [`DiskFull`](BlockStoreError::DiskFull) error is returned. Off by default.
+    pub fn with_gc_on_disk_full(mut self, min_blocks: usize, max_duration: Duration) -> Self {
+        self.gc_on_disk_full = Some((min_blocks, max_duration));
+        self
+    }
+    /// Verify the hash of every block before it is added to the store
+    ///
+    /// This includes the blocks copied by [`merge_from_file`](BlockStore::merge_from_file). The
+    /// hash functions are those registered with [`with_hasher`](Self::with_hasher), then those
+    /// in the `Hashes` code table of the store's [`StoreParams`]. Blocks with an unsupported
+    /// hash are rejected. Cids with a truncated digest are accepted if it is a prefix of the
+    /// full digest.
+    pub fn with_verify_hashes(mut self, value: bool) -> Self {
+        self.verify_hashes = value;
+        self
+    }
+    /// Register the digest implementation for the multihash `code`, used when
+    /// [verifying hashes](Self::with_verify_hashes)
+    ///
+    /// This allows supporting e.g. blake3 or custom hashed cids without changing the
+    /// `StoreParams` of the store; registered hashers take precedence over its code table.
+    pub fn with_hasher<F>(mut self, code: u64, digest: F) -> Self
+    where
+        F: Fn(&[u8]) -> Vec<u8> + Send + Sync + 'static,
+    {
+        self.hashers.0.insert(code, Arc::new(digest));
+        self
+    }
+    /// Record how often each child is linked from a block when it is added
+    ///
+    /// Duplicate links are always stored once, so gc and traversals are unaffected. With this
+    /// enabled, [`get_links`](BlockStore::get_links) can additionally return the links of blocks
+    /// added afterwards as a multiset, e.g. for dag-pb directories with repeated entries.
+    pub fn with_link_multiplicity(mut self, value: bool) -> Self {
+        self.link_multiplicity = value;
+        self
+    }
+    /// Log a warning with the SQL and its parameters when a statement runs longer than `threshold`
+    ///
+    /// With `kill`, such statements are also interrupted, which makes the operation fail with
+    /// [`Cancelled`](BlockStoreError::Cancelled). Statements are checked while they execute, so
+    /// one that is blocked waiting for a lock is only reported once it continues.
+    /// Capturing the SQL has some overhead per statement, so this is off by default.
+    pub fn with_statement_watchdog(mut self, threshold: Duration, kill: bool) -> Self {
+        self.statement_watchdog = Some((threshold, kill));
+        self
+    }
     pub fn with_pragma_synchronous(mut self, value: Synchronous) -> Self {
         self.pragma_synchronous = value;
         self
@@ -209,17 +479,102 @@ impl Config {
         self.pragma_cache_pages = value;
         self
     }
+    /// Set how long to wait for other connections to release their locks, 5 seconds by default
+    ///
+    /// Transactions that fail because of concurrent writers are retried with backoff for the
+    /// same duration before failing with [`Busy`](BlockStoreError::Busy).
+    pub fn with_busy_timeout(mut self, value: Duration) -> Self {
+        self.busy_timeout = Some(value);
+        self
+    }
+    /// Set the number of WAL pages after which a commit checkpoints the WAL, 0 to disable
+    ///
+    /// If not set, the sqlite default of 1000 pages is used. These checkpoints are passive, so
+    /// they do not shrink the WAL file, see [`with_checkpoint_wal_size`](Self::with_checkpoint_wal_size).
+    pub fn with_wal_autocheckpoint(mut self, pages: u64) -> Self {
+        self.wal_autocheckpoint = Some(pages);
+        self
+    }
+    /// Truncate the WAL after gc and after writing blocks once it has grown beyond `bytes`
+    ///
+    /// Truncating waits for readers on other connections to finish, so this trades write
+    /// latency for a bounded WAL file. Off by default.
+    pub fn with_checkpoint_wal_size(mut self, bytes: u64) -> Self {
+        self.checkpoint_wal_size = Some(bytes);
+        self
+    }
+    /// Keep the data of recently read blocks in memory, using at most `bytes`, 0 by default
+    ///
+    /// This saves going to the database for blocks that are read over and over, like the roots
+    /// requested by many peers. The cache is shared by all handles to the same file within this
+    /// process, with the size given when the file was first opened. Blocks deleted through these
+    /// handles are evicted right away, but those deleted by other processes may still be read
+    /// from the cache. Blocks larger than half the size are not cached.
+    pub fn with_block_cache_size(mut self, bytes: u64) -> Self {
+        self.block_cache_size = bytes;
+        self
+    }
+    /// Remember up to `cids` for which there was no data during `ttl`, off by default
+    ///
+    /// This saves going to the database when a fetcher keeps asking for blocks it is still
+    /// waiting for. A cid is forgotten as soon as its block is written through a handle to the
+    /// same file within this process, which shares the cache like the
+    /// [block cache](Self::with_block_cache_size). Blocks written by other processes may be
+    /// reported as missing for up to `ttl`.
+    pub fn with_missing_cache(mut self, cids: usize, ttl: Duration) -> Self {
+        self.missing_cache = (cids, ttl);
+        self
+    }
+    /// Encrypt the store with SQLCipher, using `key` as the passphrase
+    ///
+    /// A raw 256 bit key can be given as `x'...'` with 64 hex digits, which skips the key
+    /// derivation. A new store is created encrypted; an existing store must have been created
+    /// with the same key, otherwise opening it fails with a "file is not a database" error.
+    /// An unencrypted store cannot be encrypted after the fact with this.
+    #[cfg(feature = "sqlcipher")]
+    pub fn with_encryption_key(mut self, key: impl Into<String>) -> Self {
+        self.encryption_key = Some(EncryptionKey(key.into().into()));
+        self
+    }
 }
 
 pub struct BlockStore<S> {
     conn: Connection,
+    cancellation: CancellationToken,
     expired_temp_pins: Arc<Mutex<Vec<i64>>>,
+    tag: Option<String>,
+    tag_stats: TagStatsMap,
     config: Config,
     db_path: DbPath,
     recompute_done: Arc<AtomicBool>,
+    events: Events,
+    counters: Arc<AtomicCounters>,
+    block_cache: BlockCache,
+    missing_cache: MissingCache,
+    open_file: Option<Arc<OpenFile>>,
+    // identifies the temp pins created by this process, shared by all connections to a file
+    session: i64,
     _s: PhantomData<S>,
 }
 
+/// state shared by all handles to the same database file within this process
+struct OpenFile {
+    expired_temp_pins: Arc<Mutex<Vec<i64>>>,
+    tag_stats: TagStatsMap,
+    recompute_done: Arc<AtomicBool>,
+    events: Events,
+    counters: Arc<AtomicCounters>,
+    block_cache: BlockCache,
+    missing_cache: MissingCache,
+    session: i64,
+    // held while the file is open, so that other processes keep the temp pins of this session
+    _session_lock: session::SessionLock,
+}
+
+// an Option, since BTreeMap::new is not const on the minimum supported Rust version
+static OPEN_FILES: Mutex<Option<BTreeMap<PathBuf, Weak<OpenFile>>>> =
+    parking_lot::const_mutex(None);
+
 #[derive(Debug, Clone, Default, PartialEq, Eq)]
 pub struct StoreStats {
     count: u64,
@@ -264,210 +619,1355 @@ impl StoreStats {
     }
 }
 
-/// a handle that contains a temporary pin
-///
-/// Dropping this handle enqueues the pin for dropping before the next gc.
-// do not implement Clone for this!
-pub struct TempPin {
-    id: i64,
-    expired_temp_pins: Arc<Mutex<Vec<i64>>>,
+/// Counts of everything in the store, see [`get_store_summary`](BlockStore::get_store_summary)
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StoreSummary {
+    pub(crate) blocks: u64,
+    pub(crate) bytes: u64,
+    pub(crate) cids: u64,
+    pub(crate) orphans: u64,
+    pub(crate) aliases: u64,
+    pub(crate) temp_pins: u64,
 }
 
-impl TempPin {
-    fn new(expired_temp_pins: Arc<Mutex<Vec<i64>>>) -> Self {
-        Self {
-            id: 0,
-            expired_temp_pins,
-        }
+impl StoreSummary {
+    /// Number of blocks in the store
+    pub fn blocks(&self) -> u64 {
+        self.blocks
     }
-}
 
-/// dump the temp alias id so you can find it in the database
-impl fmt::Debug for TempPin {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let mut builder = f.debug_struct("TempAlias");
-        if self.id > 0 {
-            builder.field("id", &self.id);
-        } else {
-            builder.field("unused", &true);
-        }
-        builder.finish()
+    /// Total size of the blocks in the store
+    pub fn bytes(&self) -> u64 {
+        self.bytes
     }
-}
 
-impl Drop for TempPin {
-    fn drop(&mut self) {
-        if self.id > 0 {
-            self.expired_temp_pins.lock().push(self.id);
-        }
+    /// Number of known cids, including those of missing blocks
+    pub fn cids(&self) -> u64 {
+        self.cids
     }
-}
 
-impl<S> BlockStore<S>
-where
-    S: StoreParams,
-    Ipld: References<S::Codecs>,
-{
-    fn create_connection(db_path: DbPath, config: &Config) -> crate::Result<rusqlite::Connection> {
-        let mut flags = OpenFlags::SQLITE_OPEN_NO_MUTEX | OpenFlags::SQLITE_OPEN_URI;
-        flags |= if config.read_only {
-            OpenFlags::SQLITE_OPEN_READ_ONLY
-        } else {
-            OpenFlags::SQLITE_OPEN_READ_WRITE
-        };
-        if config.create && !config.read_only {
-            flags |= OpenFlags::SQLITE_OPEN_CREATE
-        }
-        let conn = match db_path {
-            DbPath::Memory => Connection::open_in_memory().ctx("opening in-memory DB")?,
-            DbPath::File(path) => Connection::open_with_flags(path, flags).ctx("opening DB")?,
-        };
-        Ok(conn)
+    /// Number of cids that have neither data nor anything referencing them
+    pub fn orphans(&self) -> u64 {
+        self.orphans
     }
 
-    pub fn open_path(db_path: DbPath, config: Config) -> crate::Result<Self> {
-        let is_memory = db_path.is_memory();
-        let mut conn = Self::create_connection(db_path.clone(), &config)?;
-        // this needs to be done only once, and before the first transaction
-        conn.execute_batch("PRAGMA journal_mode = WAL")
-            .ctx("setting WAL mode")?;
-        init_db(
-            &mut conn,
-            is_memory,
-            config.pragma_cache_pages as i64,
-            config.pragma_synchronous,
-        )?;
-        let mut this = Self {
-            conn,
-            expired_temp_pins: Arc::new(Mutex::new(Vec::new())),
-            config,
-            db_path,
-            recompute_done: Arc::new(AtomicBool::new(false)),
-            _s: PhantomData,
-        };
-        if !is_memory {
-            let mut conn = this.additional_connection()?;
-            std::thread::spawn(move || {
-                if let Err(e) = recompute_store_stats(&mut conn.conn) {
-                    tracing::error!("cannot recompute store stats: {}", e);
-                }
-                // This is done to avoid GC doing a wal_checkpoint(RESTART) while the above
-                // long-running query is ongoing, since that would block all writers during
-                // that period.
-                conn.recompute_done.store(true, Ordering::SeqCst);
-            });
-        } else {
-            this.recompute_done.store(true, Ordering::SeqCst);
-        }
-        if this.config.cache_tracker.has_persistent_state() {
-            let ids = in_txn(
-                &mut this.conn,
-                Some(("get IDs", Duration::from_secs(1))),
-                false,
-                get_ids,
-            )?;
-            this.config.cache_tracker.retain_ids(&ids);
-        }
-        Ok(this)
+    /// Number of aliases
+    pub fn aliases(&self) -> u64 {
+        self.aliases
     }
 
-    /// Create another connection to the underlying database
-    ///
-    /// This allows you to perform operations in parallel.
-    pub fn additional_connection(&self) -> crate::Result<Self> {
-        if self.db_path.is_memory() {
-            return Err(BlockStoreError::NoAdditionalInMemory);
-        }
-        let mut conn = Self::create_connection(self.db_path.clone(), &self.config)?;
-        init_pragmas(
-            &mut conn,
-            self.db_path.is_memory(),
-            self.config.pragma_cache_pages as i64,
-        )?;
-        conn.pragma_update(
-            None,
-            "synchronous",
-            &self.config.pragma_synchronous.to_string(),
-        )
-        .ctx("setting synchronous mode")?;
-        Ok(Self {
-            conn,
-            expired_temp_pins: self.expired_temp_pins.clone(),
-            config: self.config.clone(),
-            db_path: self.db_path.clone(),
-            recompute_done: self.recompute_done.clone(),
-            _s: PhantomData,
-        })
+    /// Number of live temp pins
+    pub fn temp_pins(&self) -> u64 {
+        self.temp_pins
     }
+}
 
-    /// Create an in memory block store with the given config
-    pub fn memory(config: Config) -> crate::Result<Self> {
-        Self::open_path(DbPath::Memory, config)
+/// Space taken by the store on disk, see [`disk_usage`](BlockStore::disk_usage)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DiskUsage {
+    pub(crate) page_size: u64,
+    pub(crate) pages: u64,
+    pub(crate) free_pages: u64,
+    pub(crate) wal_size: u64,
+}
+
+impl DiskUsage {
+    /// Size of the main DB file, including free pages
+    pub fn db_size(&self) -> u64 {
+        self.pages * self.page_size
     }
 
-    /// Create a persistent block store with the given config
-    pub fn open(path: impl AsRef<Path>, config: Config) -> crate::Result<Self> {
-        let mut pb: PathBuf = PathBuf::new();
-        pb.push(path);
-        Self::open_path(DbPath::File(pb), config)
+    /// Size of the free pages in the main DB file, which can be reclaimed by vacuuming
+    pub fn free_size(&self) -> u64 {
+        self.free_pages * self.page_size
     }
 
-    /// Open the file at the given path for testing.
-    ///
-    /// This will create a writeable in-memory database that is initialized with the content
-    /// of the file at the given path.
-    pub fn open_test(path: impl AsRef<Path>, config: Config) -> crate::Result<Self> {
-        let mut conn = Self::create_connection(DbPath::Memory, &config)?;
-        debug!(
-            "Restoring in memory database from {}",
-            path.as_ref().display()
-        );
-        conn.restore(
-            DatabaseName::Main,
-            path,
-            Some(|p: rusqlite::backup::Progress| {
-                let percent = if p.pagecount == 0 {
-                    100
-                } else {
-                    (p.pagecount - p.remaining) * 100 / p.pagecount
-                };
-                if percent % 10 == 0 {
-                    debug!("Restoring: {} %", percent);
-                }
-            }),
-        )
-        .ctx("restoring test DB from backup")?;
-        let ids = in_txn(
-            &mut conn,
-            Some(("get ids", Duration::from_secs(1))),
-            false,
-            get_ids,
-        )?;
-        config.cache_tracker.retain_ids(&ids);
-        Ok(Self {
-            conn,
-            expired_temp_pins: Arc::new(Mutex::new(Vec::new())),
-            config,
-            db_path: DbPath::Memory,
-            recompute_done: Arc::new(AtomicBool::new(true)),
-            _s: PhantomData,
-        })
+    /// Size of the write-ahead log, which shrinks when it is checkpointed
+    pub fn wal_size(&self) -> u64 {
+        self.wal_size
     }
 
-    pub fn backup(&mut self, path: PathBuf) -> Result<()> {
-        in_txn(&mut self.conn, None, false, move |txn| {
-            txn.backup(DatabaseName::Main, path.as_path(), None)
-                .ctx("backing up DB")
-        })
+    /// Total size of the files making up the store
+    pub fn total(&self) -> u64 {
+        self.db_size() + self.wal_size
     }
+}
 
-    pub fn flush(&mut self) -> crate::Result<()> {
-        in_txn(&mut self.conn, None, false, |txn| {
-            txn.pragma_update(None, "wal_checkpoint", &"TRUNCATE")
+/// Block reads and writes attributed to a tag, see [`set_tag`](BlockStore::set_tag)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TagStats {
+    pub(crate) blocks_read: u64,
+    pub(crate) bytes_read: u64,
+    pub(crate) blocks_written: u64,
+    pub(crate) bytes_written: u64,
+}
+
+impl TagStats {
+    /// Number of blocks successfully read
+    pub fn blocks_read(&self) -> u64 {
+        self.blocks_read
+    }
+
+    /// Total size of the blocks read
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes_read
+    }
+
+    /// Number of blocks put in committed transactions, including those that already existed
+    pub fn blocks_written(&self) -> u64 {
+        self.blocks_written
+    }
+
+    /// Total size of the blocks written
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes_written
+    }
+}
+
+pub(crate) type TagStatsMap = Arc<Mutex<FnvHashMap<String, TagStats>>>;
+
+/// Differences between the links stored for a block and those decoded from its data
+///
+/// See [`verify_links`](BlockStore::verify_links)
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LinkDiff {
+    pub(crate) missing: Vec<Cid>,
+    pub(crate) unexpected: Vec<Cid>,
+}
+
+impl LinkDiff {
+    /// true if the stored links match the decoded ones
+    pub fn is_empty(&self) -> bool {
+        self.missing.is_empty() && self.unexpected.is_empty()
+    }
+
+    /// Links found in the block data, but not in the store
+    pub fn missing(&self) -> &[Cid] {
+        &self.missing
+    }
+
+    /// Links found in the store, but not in the block data
+    pub fn unexpected(&self) -> &[Cid] {
+        &self.unexpected
+    }
+}
+
+/// The outcome of a GC dry run, see [`gc_preview`](BlockStore::gc_preview)
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GcPreview {
+    cids: Vec<Cid>,
+    size: u64,
+}
+
+impl GcPreview {
+    /// Cids of the blocks that would be deleted, in the order in which gc would delete them
+    pub fn cids(&self) -> &[Cid] {
+        &self.cids
+    }
+
+    /// Number of blocks that would be deleted
+    pub fn count(&self) -> u64 {
+        self.cids.len() as u64
+    }
+
+    /// Total size of the blocks that would be deleted
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+}
+
+/// Bounds for the size of a result, see e.g.
+/// [`get_descendants_limited`](BlockStore::get_descendants_limited)
+///
+/// The default is unbounded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limit {
+    pub(crate) max_results: usize,
+    pub(crate) max_bytes: usize,
+}
+
+impl Default for Limit {
+    fn default() -> Self {
+        Self {
+            max_results: usize::MAX,
+            max_bytes: usize::MAX,
+        }
+    }
+}
+
+impl Limit {
+    /// Maximum number of items in the result
+    pub fn with_max_results(mut self, value: usize) -> Self {
+        self.max_results = value;
+        self
+    }
+    /// Maximum total size of the binary cids in the result
+    pub fn with_max_bytes(mut self, value: usize) -> Self {
+        self.max_bytes = value;
+        self
+    }
+}
+
+/// A result that may have been cut short by a [`Limit`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Limited<C> {
+    /// All items are included
+    Complete(C),
+    /// The limit was hit, so only some of the items are included
+    Truncated(C),
+}
+
+impl<C> Limited<C> {
+    /// Whether the limit was hit
+    pub fn is_truncated(&self) -> bool {
+        matches!(self, Self::Truncated(_))
+    }
+
+    /// The items, regardless of whether they are complete
+    pub fn into_inner(self) -> C {
+        match self {
+            Self::Complete(c) | Self::Truncated(c) => c,
+        }
+    }
+
+    pub(crate) fn new(items: C, truncated: bool) -> Self {
+        if truncated {
+            Self::Truncated(items)
+        } else {
+            Self::Complete(items)
+        }
+    }
+}
+
+/// One batch of a paginated listing
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Page<C> {
+    items: C,
+    next: Option<u64>,
+}
+
+impl<C> Page<C> {
+    /// The items of this page
+    pub fn items(&self) -> &C {
+        &self.items
+    }
+
+    /// The items of this page
+    pub fn into_items(self) -> C {
+        self.items
+    }
+
+    /// The cursor for getting the next page, or `None` if this is the last one
+    pub fn next(&self) -> Option<u64> {
+        self.next
+    }
+
+    pub(crate) fn new(items: C, next: Option<u64>) -> Self {
+        Self { items, next }
+    }
+}
+
+/// What was done by a call to [`maintain`](BlockStore::maintain)
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MaintenanceReport {
+    temp_pins_dropped: usize,
+    orphans_deleted: usize,
+    checkpointed: bool,
+    vacuumed: bool,
+    analyzed: bool,
+    complete: bool,
+}
+
+impl MaintenanceReport {
+    /// Number of expired temp pins that were removed from the database
+    pub fn temp_pins_dropped(&self) -> usize {
+        self.temp_pins_dropped
+    }
+
+    /// Number of cids without block data that were no longer referenced and thus deleted
+    pub fn orphans_deleted(&self) -> usize {
+        self.orphans_deleted
+    }
+
+    /// Whether the WAL was checkpointed
+    ///
+    /// This is skipped while the store stats are still being recomputed after opening.
+    pub fn checkpointed(&self) -> bool {
+        self.checkpointed
+    }
+
+    /// Whether an incremental vacuum was performed
+    pub fn vacuumed(&self) -> bool {
+        self.vacuumed
+    }
+
+    /// Whether the query planner statistics were updated
+    pub fn analyzed(&self) -> bool {
+        self.analyzed
+    }
+
+    /// Whether all maintenance tasks were performed within the time budget
+    pub fn complete(&self) -> bool {
+        self.complete
+    }
+}
+
+/// Which blocks an alias protects from gc
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PinMode {
+    /// The root and all blocks reachable from it; this is what [`alias`](BlockStore::alias)
+    /// does
+    Recursive,
+    /// Only the root block itself
+    Direct,
+    /// The root and the blocks up to the given number of links below it
+    ///
+    /// This allows keeping e.g. the directory structure of a large dataset while its leaves
+    /// remain evictable. A depth of 0 is the same as [`Direct`](Self::Direct) and is reported as
+    /// such.
+    Depth(u32),
+}
+
+/// How duplicate links are reported by [`get_links`](BlockStore::get_links)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkMode {
+    /// Each child once
+    Set,
+    /// Each child as often as it is linked from the block
+    ///
+    /// This requires [`with_link_multiplicity`](Config::with_link_multiplicity) to have been
+    /// enabled when the block was added, otherwise each child is reported once.
+    Multiset,
+}
+
+/// The order in which [`get_descendants_ordered`](BlockStore::get_descendants_ordered) visits a
+/// dag
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Traversal {
+    /// All blocks at one depth before any block at the next depth
+    ///
+    /// The reported depth of a block is the length of the shortest path to it.
+    BreadthFirst,
+    /// Each link followed to the bottom before the next link of the same block, as in a CAR file
+    ///
+    /// The reported depth of a block is its depth on the path on which it was first visited.
+    DepthFirst,
+}
+
+/// Information about an alias, see [`alias_info`](BlockStore::alias_info)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AliasInfo {
+    pub(crate) cid: Cid,
+    pub(crate) created: Option<SystemTime>,
+    pub(crate) updated: Option<SystemTime>,
+    pub(crate) metadata: Option<Vec<u8>>,
+    pub(crate) mode: PinMode,
+}
+
+impl AliasInfo {
+    /// The root the alias points to
+    pub fn cid(&self) -> &Cid {
+        &self.cid
+    }
+
+    /// When the alias was created, with a resolution of one second
+    ///
+    /// This is `None` for aliases created by versions of this crate that did not record it.
+    pub fn created(&self) -> Option<SystemTime> {
+        self.created
+    }
+
+    /// When the alias was last set or its metadata changed
+    pub fn updated(&self) -> Option<SystemTime> {
+        self.updated
+    }
+
+    /// Application defined metadata, see [`set_alias_metadata`](BlockStore::set_alias_metadata)
+    pub fn metadata(&self) -> Option<&[u8]> {
+        self.metadata.as_deref()
+    }
+
+    /// How much of the dag the alias protects
+    pub fn mode(&self) -> PinMode {
+        self.mode
+    }
+}
+
+/// Size and links of a block, see [`block_stat`](BlockStore::block_stat)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockStat {
+    pub(crate) size: u64,
+    pub(crate) has_data: bool,
+    pub(crate) link_count: u64,
+}
+
+impl BlockStat {
+    /// The size of the block data in bytes, 0 if the store does not have the data
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Whether the store has the data of the block
+    pub fn has_data(&self) -> bool {
+        self.has_data
+    }
+
+    /// The number of distinct children of the block, 0 if the store does not have the data
+    pub fn link_count(&self) -> u64 {
+        self.link_count
+    }
+}
+
+/// Size of a dag, see [`reachable_size`](BlockStore::reachable_size) and
+/// [`alias_stats`](BlockStore::alias_stats)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DagStats {
+    pub(crate) blocks: u64,
+    pub(crate) bytes: u64,
+    pub(crate) missing: u64,
+}
+
+impl DagStats {
+    /// Number of blocks of the dag in the store
+    pub fn blocks(&self) -> u64 {
+        self.blocks
+    }
+
+    /// Total size of the blocks of the dag in the store
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    /// Number of blocks of the dag known to be missing from the store
+    ///
+    /// The children of missing blocks are unknown, so the dag may be missing more than this.
+    pub fn missing(&self) -> u64 {
+        self.missing
+    }
+}
+
+/// What was copied by [`merge_from_file`](BlockStore::merge_from_file)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MergeStats {
+    pub(crate) blocks: u64,
+    pub(crate) bytes: u64,
+    pub(crate) aliases: u64,
+}
+
+impl MergeStats {
+    /// Number of blocks that were added
+    pub fn blocks(&self) -> u64 {
+        self.blocks
+    }
+
+    /// Total size of the blocks that were added
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    /// Number of aliases that were added
+    pub fn aliases(&self) -> u64 {
+        self.aliases
+    }
+}
+
+/// An entry of the change log, see [`changes_since`](BlockStore::changes_since)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Change {
+    pub(crate) seq: u64,
+    pub(crate) time: SystemTime,
+    pub(crate) event: StoreEvent,
+}
+
+impl Change {
+    /// Sequence number of the change, which only ever increases
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    /// When the change was made, with a resolution of seconds
+    pub fn time(&self) -> SystemTime {
+        self.time
+    }
+
+    /// What was changed
+    pub fn event(&self) -> &StoreEvent {
+        &self.event
+    }
+}
+
+/// Number and size of the blocks with one codec, see [`codec_stats`](BlockStore::codec_stats)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CodecStats {
+    pub(crate) codec: u64,
+    pub(crate) count: u64,
+    pub(crate) size: u64,
+}
+
+impl CodecStats {
+    /// The multicodec code, e.g. 0x55 for raw or 0x70 for dag-pb
+    pub fn codec(&self) -> u64 {
+        self.codec
+    }
+
+    /// Number of blocks with this codec
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Total size of the blocks with this codec
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+}
+
+/// Live state of a store for diagnosing e.g. why the disk usage does not shrink
+///
+/// See [`diagnostics`](BlockStore::diagnostics).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Diagnostics {
+    temp_pins: u64,
+    temp_pinned_blocks: u64,
+    gc_candidates: u64,
+    wal_size: Option<u64>,
+}
+
+impl Diagnostics {
+    /// Number of temp pins currently held by any handle
+    pub fn temp_pins(&self) -> u64 {
+        self.temp_pins
+    }
+
+    /// Number of distinct blocks that are directly protected by temp pins
+    pub fn temp_pinned_blocks(&self) -> u64 {
+        self.temp_pinned_blocks
+    }
+
+    /// Number of cids that are not pinned and thus eligible for gc
+    pub fn gc_candidates(&self) -> u64 {
+        self.gc_candidates
+    }
+
+    /// Size of the write-ahead log in bytes, `None` for in-memory stores
+    ///
+    /// A WAL that keeps growing usually means that a long-running read transaction prevents
+    /// checkpointing.
+    pub fn wal_size(&self) -> Option<u64> {
+        self.wal_size
+    }
+}
+
+/// Result of [`audit_constraints`](BlockStore::audit_constraints)
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConstraintReport {
+    pub(crate) foreign_key_violations: BTreeMap<String, u64>,
+    pub(crate) leaked_temp_pins: u64,
+    pub(crate) stats_mismatch: bool,
+    pub(crate) fixed: bool,
+}
+
+impl ConstraintReport {
+    /// Number of rows violating a foreign key constraint, by table
+    pub fn foreign_key_violations(&self) -> &BTreeMap<String, u64> {
+        &self.foreign_key_violations
+    }
+
+    /// Number of temp pins not belonging to any session, which would keep their blocks forever
+    pub fn leaked_temp_pins(&self) -> u64 {
+        self.leaked_temp_pins
+    }
+
+    /// Whether the block count or size in the stats table did not match the actual blocks
+    pub fn stats_mismatch(&self) -> bool {
+        self.stats_mismatch
+    }
+
+    /// Whether the problems found have been fixed
+    pub fn fixed(&self) -> bool {
+        self.fixed
+    }
+
+    /// True if no problems were found
+    pub fn is_clean(&self) -> bool {
+        self.foreign_key_violations.is_empty() && self.leaked_temp_pins == 0 && !self.stats_mismatch
+    }
+}
+
+/// A resumable garbage collection, obtained from [`start_gc`](BlockStore::start_gc)
+///
+/// This owns the set of gc candidates computed when it was started. Blocks are checked again
+/// for being pinned right before deletion, so it is safe to keep this around while the store
+/// is being modified; blocks that became unpinned after starting will only be found by the
+/// next gc, though.
+#[derive(Debug)]
+pub struct IncrementalGc {
+    ids: VecDeque<i64>,
+}
+
+impl IncrementalGc {
+    /// Delete unpinned blocks until the size targets are met or `max_duration` is elapsed
+    ///
+    /// At least one block is deleted per call (if needed), to guarantee progress. This must only
+    /// be used with a connection to the store from which this gc was started.
+    ///
+    /// Returns true if either size targets are met or there are no candidates left.
+    pub fn step<S>(&mut self, store: &mut BlockStore<S>, max_duration: Duration) -> Result<bool>
+    where
+        S: StoreParams,
+        Ipld: References<S::Codecs>,
+    {
+        let _span = tracing::debug_span!("GC step", remaining = self.ids.len()).entered();
+        store.cleanup_temp_pins()?;
+        store.maybe_checkpoint()?;
+        let ret = delete_gc_candidates(
+            &mut store.conn,
+            &mut self.ids,
+            1,
+            max_duration,
+            store.config.size_targets,
+            &store.events.tracker(
+                &store.config.cache_tracker,
+                &store.counters,
+                &store.block_cache,
+            ),
+            &store.config.gc_filter,
+            store.config.gc_grace_period,
+        )?;
+        store.maybe_checkpoint()?;
+        incremental_vacuum(&mut store.conn, None)?;
+        Ok(ret)
+    }
+
+    /// Number of candidates that have not yet been considered for deletion
+    pub fn remaining(&self) -> usize {
+        self.ids.len()
+    }
+}
+
+/// An iterator over the cids and data of all blocks, see [`iter_blocks`](BlockStore::iter_blocks)
+pub struct BlockIter<'a, S> {
+    store: &'a mut BlockStore<S>,
+    after: i64,
+    batch: VecDeque<(Cid, Vec<u8>)>,
+    done: bool,
+}
+
+impl<'a, S> fmt::Debug for BlockIter<'a, S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BlockIter")
+            .field("after", &self.after)
+            .field("done", &self.done)
+            .finish()
+    }
+}
+
+impl<'a, S> Iterator for BlockIter<'a, S>
+where
+    S: StoreParams,
+    Ipld: References<S::Codecs>,
+{
+    type Item = Result<(Cid, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        /// blocks in one batch, unless they are larger than `BATCH_BYTES` in total
+        const BATCH_BLOCKS: usize = 1000;
+        const BATCH_BYTES: usize = 16 << 20;
+        if self.batch.is_empty() && !self.done {
+            let mut txn = self.store.transaction();
+            let res = txn
+                .get_blocks_after(self.after, BATCH_BLOCKS, BATCH_BYTES)
+                .and_then(|blocks| txn.commit().map(|_| blocks));
+            match res {
+                Ok(blocks) => {
+                    self.done = blocks.is_empty();
+                    if let Some((id, _, _)) = blocks.last() {
+                        self.after = *id;
+                    }
+                    self.batch
+                        .extend(blocks.into_iter().map(|(_, cid, data)| (cid, data)));
+                }
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+        self.batch.pop_front().map(Ok)
+    }
+}
+
+/// Incremental reader for the data of a block, see
+/// [`open_block_reader`](BlockStore::open_block_reader)
+pub struct BlockReader<'a> {
+    blob: rusqlite::blob::Blob<'a>,
+}
+
+impl<'a> BlockReader<'a> {
+    /// The size of the block data in bytes
+    pub fn len(&self) -> usize {
+        self.blob.len()
+    }
+
+    /// Whether the block data is empty
+    pub fn is_empty(&self) -> bool {
+        self.blob.is_empty()
+    }
+}
+
+impl<'a> fmt::Debug for BlockReader<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BlockReader")
+            .field("len", &self.blob.len())
+            .finish()
+    }
+}
+
+impl<'a> std::io::Read for BlockReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.blob.read(buf)
+    }
+}
+
+impl<'a> std::io::Seek for BlockReader<'a> {
+    fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+        self.blob.seek(pos)
+    }
+}
+
+/// The remaining work of [`purge_closure`](BlockStore::purge_closure)
+#[derive(Debug)]
+pub struct Purge {
+    ids: VecDeque<i64>,
+}
+
+impl Purge {
+    /// Delete blocks of the closure until there are none left or `max_duration` is elapsed
+    ///
+    /// At least one block is deleted per call, to guarantee progress. Blocks that have been
+    /// pinned since the purge was started are kept. This must only be used with a connection to
+    /// the store from which this purge was started.
+    ///
+    /// Returns true if there are no blocks left to delete.
+    pub fn step<S>(&mut self, store: &mut BlockStore<S>, max_duration: Duration) -> Result<bool>
+    where
+        S: StoreParams,
+        Ipld: References<S::Codecs>,
+    {
+        let _span = tracing::debug_span!("purge step", remaining = self.ids.len()).entered();
+        store.cleanup_temp_pins()?;
+        store.maybe_checkpoint()?;
+        // zero size targets are always exceeded, so only the pins stop the deletion
+        let ret = delete_gc_candidates(
+            &mut store.conn,
+            &mut self.ids,
+            1,
+            max_duration,
+            SizeTargets::new(0, 0),
+            &store.events.tracker(
+                &store.config.cache_tracker,
+                &store.counters,
+                &store.block_cache,
+            ),
+            &None,
+            Duration::ZERO,
+        )?;
+        store.maybe_checkpoint()?;
+        incremental_vacuum(&mut store.conn, None)?;
+        Ok(ret)
+    }
+
+    /// Number of blocks that have not yet been considered for deletion
+    pub fn remaining(&self) -> usize {
+        self.ids.len()
+    }
+}
+
+/// A handle for aborting long-running operations on a connection from another thread
+///
+/// Once cancelled, all statements executed on the connection are aborted with
+/// [`BlockStoreError::Cancelled`], until the token is [reset](Self::reset). Note that very short
+/// statements may still complete successfully.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    /// Abort any running and future operations on the connection
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    /// Allow operations on the connection again
+    pub fn reset(&self) {
+        self.0.store(false, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// a handle that contains a temporary pin
+///
+/// Dropping this handle enqueues the pin for dropping before the next gc.
+// do not implement Clone for this!
+pub struct TempPin {
+    id: i64,
+    expired_temp_pins: Arc<Mutex<Vec<i64>>>,
+}
+
+impl TempPin {
+    fn new(expired_temp_pins: Arc<Mutex<Vec<i64>>>) -> Self {
+        Self {
+            id: 0,
+            expired_temp_pins,
+        }
+    }
+
+    /// The id of this temp pin in the database, if it pins anything yet
+    pub fn id(&self) -> Option<i64> {
+        if self.id > 0 {
+            Some(self.id)
+        } else {
+            None
+        }
+    }
+}
+
+/// dump the temp alias id so you can find it in the database
+impl fmt::Debug for TempPin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut builder = f.debug_struct("TempAlias");
+        if self.id > 0 {
+            builder.field("id", &self.id);
+        } else {
+            builder.field("unused", &true);
+        }
+        builder.finish()
+    }
+}
+
+impl Drop for TempPin {
+    fn drop(&mut self) {
+        if self.id > 0 {
+            self.expired_temp_pins.lock().push(self.id);
+        }
+    }
+}
+
+/// a random id for telling apart the temp pins of different processes
+fn new_session() -> i64 {
+    use std::hash::{BuildHasher, Hasher};
+    // every RandomState is seeded differently
+    let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+    hasher.write_u32(std::process::id());
+    hasher.finish() as i64
+}
+
+impl<S> BlockStore<S>
+where
+    S: StoreParams,
+    Ipld: References<S::Codecs>,
+{
+    fn create_connection(
+        db_path: DbPath,
+        config: &Config,
+    ) -> crate::Result<(rusqlite::Connection, CancellationToken)> {
+        let token = CancellationToken::default();
+        let conn = Self::connect(db_path, config, &token)?;
+        Ok((conn, token))
+    }
+
+    fn connect(
+        db_path: DbPath,
+        config: &Config,
+        token: &CancellationToken,
+    ) -> crate::Result<rusqlite::Connection> {
+        let mut flags = OpenFlags::SQLITE_OPEN_NO_MUTEX | OpenFlags::SQLITE_OPEN_URI;
+        flags |= if config.read_only {
+            OpenFlags::SQLITE_OPEN_READ_ONLY
+        } else {
+            OpenFlags::SQLITE_OPEN_READ_WRITE
+        };
+        if config.create && !config.read_only {
+            flags |= OpenFlags::SQLITE_OPEN_CREATE
+        }
+        let mut conn = match db_path {
+            DbPath::Memory => Connection::open_in_memory().ctx("opening in-memory DB")?,
+            DbPath::File(path) => Connection::open_with_flags(path, flags).ctx("opening DB")?,
+        };
+        // the key must be set before anything is read from the DB
+        #[cfg(feature = "sqlcipher")]
+        if let Some(EncryptionKey(key)) = &config.encryption_key {
+            conn.pragma_update(None, "key", &**key)
+                .ctx("setting encryption key")?;
+        }
+        if let Some(timeout) = config.busy_timeout {
+            conn.busy_timeout(timeout).ctx("setting busy timeout")?;
+        }
+        if let Some(pages) = config.wal_autocheckpoint {
+            conn.pragma_update(None, "wal_autocheckpoint", pages as i64)
+                .ctx("setting wal_autocheckpoint")?;
+        }
+        let cancelled = token.0.clone();
+        let watchdog = config
+            .statement_watchdog
+            .map(|config| (Watchdog::install(&mut conn), config));
+        conn.progress_handler(
+            1000,
+            Some(move || {
+                cancelled.load(Ordering::Relaxed)
+                    || watchdog
+                        .as_ref()
+                        .map_or(false, |(watchdog, (threshold, kill))| {
+                            watchdog.check(*threshold, *kill)
+                        })
+            }),
+        );
+        Ok(conn)
+    }
+
+    pub fn open_path(db_path: DbPath, config: Config) -> crate::Result<Self> {
+        let is_memory = db_path.is_memory();
+        // hold the lock while opening, so that concurrent opens of the same file are coordinated
+        let mut guard = OPEN_FILES.lock();
+        let open_files = guard.get_or_insert_with(BTreeMap::new);
+        let (mut conn, cancellation) = Self::create_connection(db_path.clone(), &config)?;
+        // read-only handles do not run the startup tasks, so writers must not attach to them
+        let key = match &db_path {
+            DbPath::File(path) if !config.read_only => {
+                Some(std::fs::canonicalize(path).unwrap_or_else(|_| path.clone()))
+            }
+            _ => None,
+        };
+        open_files.retain(|_, file| file.strong_count() > 0);
+        if let Some(file) = key.as_ref().and_then(|k| open_files.get(k)?.upgrade()) {
+            debug!("attaching to store that is already open in this process");
+            Self::init_additional_connection(&mut conn, &config)?;
+            return Ok(Self {
+                conn,
+                cancellation,
+                expired_temp_pins: file.expired_temp_pins.clone(),
+                tag: None,
+                tag_stats: file.tag_stats.clone(),
+                config,
+                db_path,
+                recompute_done: file.recompute_done.clone(),
+                events: file.events.clone(),
+                counters: file.counters.clone(),
+                block_cache: file.block_cache.clone(),
+                missing_cache: file.missing_cache.clone(),
+                session: file.session,
+                open_file: Some(file),
+                _s: PhantomData,
+            });
+        }
+        if config.read_only && !is_memory {
+            // the schema is maintained by the writers
+            Self::init_additional_connection(&mut conn, &config)?;
+        } else {
+            // this needs to be done only once, and before the first transaction; switching to WAL
+            // writes the DB header, after which auto_vacuum can only be changed by a VACUUM
+            conn.execute_batch("PRAGMA auto_vacuum = 2; PRAGMA journal_mode = WAL")
+                .ctx("setting WAL mode")?;
+            init_db(
+                &mut conn,
+                is_memory,
+                config.pragma_cache_pages as i64,
+                config.pragma_synchronous,
+            )?;
+        }
+        let session = new_session();
+        let block_cache = BlockCache::new(config.block_cache_size);
+        let missing_cache = MissingCache::new(config.missing_cache.0, config.missing_cache.1);
+        let session_lock = match &key {
+            Some(key) => Some(session::SessionLock::acquire(key, session)?),
+            None => None,
+        };
+        let open_file = key.zip(session_lock).map(|(key, session_lock)| {
+            let file = Arc::new(OpenFile {
+                expired_temp_pins: Default::default(),
+                tag_stats: Default::default(),
+                recompute_done: Default::default(),
+                events: Default::default(),
+                counters: Default::default(),
+                block_cache: block_cache.clone(),
+                missing_cache: missing_cache.clone(),
+                session,
+                _session_lock: session_lock,
+            });
+            open_files.insert(key, Arc::downgrade(&file));
+            file
+        });
+        drop(guard);
+        let mut this = Self {
+            conn,
+            cancellation,
+            expired_temp_pins: open_file
+                .as_ref()
+                .map(|f| f.expired_temp_pins.clone())
+                .unwrap_or_default(),
+            tag: None,
+            tag_stats: open_file
+                .as_ref()
+                .map(|f| f.tag_stats.clone())
+                .unwrap_or_default(),
+            config,
+            db_path,
+            recompute_done: open_file
+                .as_ref()
+                .map(|f| f.recompute_done.clone())
+                .unwrap_or_default(),
+            events: open_file
+                .as_ref()
+                .map(|f| f.events.clone())
+                .unwrap_or_default(),
+            counters: open_file
+                .as_ref()
+                .map(|f| f.counters.clone())
+                .unwrap_or_default(),
+            block_cache,
+            missing_cache,
+            open_file,
+            session,
+            _s: PhantomData,
+        };
+        if this.open_file.is_some() {
+            // the temp pins of processes that crashed
+            let removed = this.clear_dead_sessions()?;
+            if removed > 0 {
+                info!(removed, "removed temp pins of processes that are gone");
+            }
+        }
+        if !is_memory && !this.config.read_only {
+            let mut conn = this.additional_connection()?;
+            // this connection must not keep the file registered as open
+            conn.open_file = None;
+            std::thread::spawn(move || {
+                if let Err(e) = recompute_store_stats(&mut conn.conn) {
+                    tracing::error!("cannot recompute store stats: {}", e);
+                }
+                // This is done to avoid GC doing a wal_checkpoint(RESTART) while the above
+                // long-running query is ongoing, since that would block all writers during
+                // that period.
+                conn.recompute_done.store(true, Ordering::SeqCst);
+            });
+        } else {
+            this.recompute_done.store(true, Ordering::SeqCst);
+        }
+        if this.config.cache_tracker.has_persistent_state() {
+            let ids = in_txn(
+                &mut this.conn,
+                Some(("get IDs", Duration::from_secs(1))),
+                false,
+                get_ids,
+            )?;
+            this.config.cache_tracker.retain_ids(&ids);
+        }
+        Ok(this)
+    }
+
+    /// Create another connection to the underlying database
+    ///
+    /// This allows you to perform operations in parallel.
+    pub fn additional_connection(&self) -> crate::Result<Self> {
+        if self.db_path.is_memory() {
+            return Err(BlockStoreError::NoAdditionalInMemory);
+        }
+        let (mut conn, cancellation) = Self::create_connection(self.db_path.clone(), &self.config)?;
+        Self::init_additional_connection(&mut conn, &self.config)?;
+        Ok(Self {
+            conn,
+            cancellation,
+            expired_temp_pins: self.expired_temp_pins.clone(),
+            tag: self.tag.clone(),
+            tag_stats: self.tag_stats.clone(),
+            config: self.config.clone(),
+            db_path: self.db_path.clone(),
+            recompute_done: self.recompute_done.clone(),
+            events: self.events.clone(),
+            counters: self.counters.clone(),
+            block_cache: self.block_cache.clone(),
+            missing_cache: self.missing_cache.clone(),
+            open_file: self.open_file.clone(),
+            session: self.session,
+            _s: PhantomData,
+        })
+    }
+
+    fn init_additional_connection(conn: &mut Connection, config: &Config) -> crate::Result<()> {
+        check_schema_version(conn)?;
+        init_pragmas(
+            conn,
+            false,
+            config.pragma_cache_pages as i64,
+            config.read_only,
+        )?;
+        conn.pragma_update(None, "synchronous", config.pragma_synchronous.to_string())
+            .ctx("setting synchronous mode")?;
+        Ok(())
+    }
+
+    /// Create an in memory block store with the given config
+    pub fn memory(config: Config) -> crate::Result<Self> {
+        Self::open_path(DbPath::Memory, config)
+    }
+
+    /// Create a persistent block store with the given config
+    pub fn open(path: impl AsRef<Path>, config: Config) -> crate::Result<Self> {
+        let mut pb: PathBuf = PathBuf::new();
+        pb.push(path);
+        Self::open_path(DbPath::File(pb), config)
+    }
+
+    /// Open the file at the given path for testing.
+    ///
+    /// This will create a writeable in-memory database that is initialized with the content
+    /// of the file at the given path.
+    pub fn open_test(path: impl AsRef<Path>, config: Config) -> crate::Result<Self> {
+        let (mut conn, cancellation) = Self::create_connection(DbPath::Memory, &config)?;
+        debug!(
+            "Restoring in memory database from {}",
+            path.as_ref().display()
+        );
+        conn.restore(
+            DatabaseName::Main,
+            path,
+            Some(|p: rusqlite::backup::Progress| {
+                let percent = if p.pagecount == 0 {
+                    100
+                } else {
+                    (p.pagecount - p.remaining) * 100 / p.pagecount
+                };
+                if percent % 10 == 0 {
+                    debug!("Restoring: {} %", percent);
+                }
+            }),
+        )
+        .ctx("restoring test DB from backup")?;
+        let ids = in_txn(
+            &mut conn,
+            Some(("get ids", Duration::from_secs(1))),
+            false,
+            get_ids,
+        )?;
+        config.cache_tracker.retain_ids(&ids);
+        let block_cache = BlockCache::new(config.block_cache_size);
+        let missing_cache = MissingCache::new(config.missing_cache.0, config.missing_cache.1);
+        Ok(Self {
+            conn,
+            cancellation,
+            expired_temp_pins: Arc::new(Mutex::new(Vec::new())),
+            tag: None,
+            tag_stats: Default::default(),
+            config,
+            db_path: DbPath::Memory,
+            recompute_done: Arc::new(AtomicBool::new(true)),
+            events: Default::default(),
+            counters: Default::default(),
+            block_cache,
+            missing_cache,
+            open_file: None,
+            session: new_session(),
+            _s: PhantomData,
+        })
+    }
+
+    /// Get the space taken by the store on disk
+    ///
+    /// Unlike the block sizes in [`get_store_stats`](Self::get_store_stats), this includes the
+    /// overhead of the tables and indexes, free pages and the write-ahead log. For in-memory
+    /// stores the WAL size is always 0.
+    pub fn disk_usage(&mut self) -> Result<DiskUsage> {
+        let stats = self.get_store_stats()?;
+        Ok(DiskUsage {
+            page_size: stats.page_size(),
+            pages: stats.used_pages(),
+            free_pages: stats.free_pages(),
+            wal_size: self.wal_size()?,
+        })
+    }
+
+    /// Get the current size of the write-ahead log file in bytes
+    ///
+    /// The WAL only shrinks when it is checkpointed in [`Truncate`](CheckpointMode::Truncate)
+    /// mode, or by the journal size limit after gc has checkpointed it. For in-memory stores
+    /// this is always 0.
+    pub fn wal_size(&self) -> Result<u64> {
+        let path = match &self.db_path {
+            DbPath::File(path) => sibling(path, "wal"),
+            DbPath::Memory => return Ok(0),
+        };
+        match std::fs::metadata(path) {
+            Ok(meta) => Ok(meta.len()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(0),
+            Err(e) => Err(io_error(e, "getting WAL size")),
+        }
+    }
+
+    pub fn backup(&mut self, path: PathBuf) -> Result<()> {
+        in_txn(&mut self.conn, None, false, move |txn| {
+            txn.backup(DatabaseName::Main, path.as_path(), None)
+                .ctx("backing up DB")
+        })
+    }
+
+    /// Update a warm standby copy of the store at `path` (experimental)
+    ///
+    /// Only the pages that changed since the last update are written, after saving their
+    /// previous content to a journal next to `path`. An interrupted update is rolled back by the
+    /// next call or by [`replay_standby`](Self::replay_standby), which puts the standby back into
+    /// service after a crash; the standby must not be opened as a store otherwise. Returns the
+    /// number of pages written.
+    ///
+    /// The WAL is checkpointed first, which waits for readers on other connections to finish,
+    /// and the database file is then read within a read transaction. Fails with
+    /// [`Busy`](BlockStoreError::Busy) if other connections keep writing in between.
+    pub fn update_standby(&mut self, path: impl AsRef<Path>) -> Result<u64> {
+        let wal = match &self.db_path {
+            DbPath::File(db) => sibling(db, "wal"),
+            DbPath::Memory => {
+                return Err(anyhow::anyhow!("a standby requires a file based store").into())
+            }
+        };
+        let path = path.as_ref().to_owned();
+        standby::recover(&path)?;
+        for attempt in 1..=STANDBY_ATTEMPTS {
+            self.checkpoint(CheckpointMode::Truncate)?;
+            let wal = wal.clone();
+            let path = path.clone();
+            let shipped = in_txn(&mut self.conn, None, false, move |txn| {
+                txn.query_row("SELECT COUNT(*) FROM sqlite_master", [], |_| Ok(()))
+                    .ctx("starting read transaction")?;
+                // with an empty WAL, the read transaction keeps checkpoints from changing the file
+                match std::fs::metadata(&wal) {
+                    Ok(meta) if meta.len() > 0 => return Ok(None),
+                    Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                        return Err(io_error(e, "getting WAL size"))
+                    }
+                    _ => {}
+                }
+                standby::ship(txn, &path).map(Some)
+            })?;
+            if let Some(pages) = shipped {
+                return Ok(pages);
+            }
+            debug!(attempt, "WAL written to while updating standby, retrying");
+            std::thread::sleep(busy_backoff(attempt));
+        }
+        Err(BlockStoreError::Busy("updating standby"))
+    }
+
+    /// Replace the database at `path` with the standby copy at `standby` (experimental)
+    ///
+    /// The standby itself is left untouched, except for rolling back an interrupted
+    /// [`update_standby`](Self::update_standby). The database at `path` must not be open while
+    /// doing this; any leftover WAL of the replaced database is discarded.
+    pub fn replay_standby(standby: impl AsRef<Path>, path: impl AsRef<Path>) -> Result<()> {
+        standby::replay(standby.as_ref(), path.as_ref())
+    }
+
+    /// Replace the content of a read-only store with the current state of the store at `primary`
+    ///
+    /// This supports deployments where one process writes and publishes the database while many
+    /// readers serve from their own copies. The primary is copied next to this store's file and
+    /// then atomically moved into place, after which this connection is reopened, so readers are
+    /// never exposed to a partial copy. Other connections to this store's file need to be
+    /// reopened to see the new content. The whole database is copied each time.
+    pub fn refresh_from(&mut self, primary: impl AsRef<Path>) -> Result<()> {
+        if !self.config.read_only {
+            return Err(anyhow::anyhow!("refresh_from requires a read-only store").into());
+        }
+        let primary = primary.as_ref();
+        match self.db_path.clone() {
+            DbPath::Memory => {
+                self.conn
+                    .restore(
+                        DatabaseName::Main,
+                        primary,
+                        None::<fn(rusqlite::backup::Progress)>,
+                    )
+                    .ctx("restoring from primary")?;
+            }
+            DbPath::File(path) => {
+                let tmp = sibling(&path, "refresh-tmp");
+                Connection::open_with_flags(primary, OpenFlags::SQLITE_OPEN_READ_ONLY)
+                    .ctx("opening primary")?
+                    .backup(DatabaseName::Main, &tmp, None)
+                    .ctx("copying primary")?;
+                // close the old file before replacing it, so that its WAL can be removed
+                self.conn = Connection::open_in_memory().ctx("opening placeholder DB")?;
+                for suffix in ["wal", "shm"] {
+                    match std::fs::remove_file(sibling(&path, suffix)) {
+                        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                            return Err(io_error(e, "removing stale WAL"))
+                        }
+                        _ => {}
+                    }
+                }
+                std::fs::rename(&tmp, &path).map_err(|e| io_error(e, "moving copy into place"))?;
+                let mut conn =
+                    Self::connect(self.db_path.clone(), &self.config, &self.cancellation)?;
+                Self::init_additional_connection(&mut conn, &self.config)?;
+                self.conn = conn;
+            }
+        }
+        // blocks missing from the old content may be in the new one, and ids may differ
+        self.missing_cache.clear();
+        self.block_cache.clear();
+        if self.config.cache_tracker.has_persistent_state() {
+            let ids = in_txn(&mut self.conn, None, false, get_ids)?;
+            self.config.cache_tracker.retain_ids(&ids);
+        }
+        Ok(())
+    }
+
+    /// Change the key of a store opened [with an encryption key](Config::with_encryption_key)
+    ///
+    /// All pages are re-encrypted, so this takes time proportional to the size of the store.
+    /// Connections created afterwards with [`additional_connection`](Self::additional_connection)
+    /// use the new key, but other connections to the store that are already open, as well as
+    /// configs used for opening it later, need to be given the new key.
+    #[cfg(feature = "sqlcipher")]
+    pub fn rekey(&mut self, key: impl Into<String>) -> Result<()> {
+        if self.config.encryption_key.is_none() {
+            return Err(anyhow::anyhow!("rekey requires a store opened with a key").into());
+        }
+        let key = EncryptionKey(key.into().into());
+        self.conn
+            .pragma_update(None, "rekey", &*key.0)
+            .ctx("changing encryption key")?;
+        self.config.encryption_key = Some(key);
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> crate::Result<()> {
+        in_txn(&mut self.conn, None, false, |txn| {
+            txn.pragma_update(None, "wal_checkpoint", "TRUNCATE")
                 .ctx("flushing WAL")
         })
     }
 
+    /// Checkpoint the write-ahead log, moving its content into the DB file
+    ///
+    /// Gc checkpoints the WAL regularly, but a read transaction that stays open for a long time
+    /// keeps those checkpoints from completing and the WAL from being reused, which lets it grow
+    /// without bound. This allows checking the progress via [`busy`](CheckpointResult::busy)
+    /// and retrying once the reader is done.
+    pub fn checkpoint(&mut self, mode: CheckpointMode) -> Result<CheckpointResult> {
+        let (busy, log, checkpointed): (i64, i64, i64) = self
+            .conn
+            .query_row(&format!("PRAGMA wal_checkpoint({})", mode), [], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+            })
+            .ctx("running wal_checkpoint")?;
+        // the frame counts are -1 if the DB is not in WAL mode
+        Ok(CheckpointResult {
+            busy: busy != 0,
+            wal_frames: u64::try_from(log).unwrap_or_default(),
+            checkpointed_frames: u64::try_from(checkpointed).unwrap_or_default(),
+        })
+    }
+
     pub fn integrity_check(&mut self) -> crate::Result<()> {
         let result = integrity_check(&mut self.conn)?;
         if result == vec!["ok".to_owned()] {
@@ -479,23 +1979,265 @@ where
                 "checking integrity",
             ))
         }
-        // FIXME add actual integrity check on the stored blocks
+        // FIXME add actual integrity check on the stored blocks
+    }
+
+    /// Gather information about temp pins, gc candidates and the WAL
+    ///
+    /// This runs the gc reachability query, so it is about as expensive as starting a gc.
+    pub fn diagnostics(&mut self) -> Result<Diagnostics> {
+        let grace_period = self.config.gc_grace_period;
+        let (temp_pins, temp_pinned_blocks, gc_candidates) =
+            in_txn(&mut self.conn, None, false, move |txn| {
+                diagnostics(txn, grace_period)
+            })?;
+        let wal_size = match &self.db_path {
+            DbPath::File(path) => match std::fs::metadata(sibling(path, "wal")) {
+                Ok(meta) => Some(meta.len()),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Some(0),
+                Err(e) => return Err(io_error(e, "getting WAL size")),
+            },
+            DbPath::Memory => None,
+        };
+        Ok(Diagnostics {
+            temp_pins,
+            temp_pinned_blocks,
+            gc_candidates,
+            wal_size,
+        })
+    }
+
+    /// Check the database for rows violating the constraints of the schema
+    ///
+    /// Foreign key enforcement only applies to rows written while it is active, so this finds
+    /// e.g. refs or aliases pointing to nonexistent ids that were left behind by older versions
+    /// or by tools writing to the database directly. Temp pins whose session was lost, and
+    /// which would therefore never be cleared, are reported too, as is a mismatch of the usage
+    /// stats.
+    ///
+    /// With `fix`, offending rows and leaked temp pins are deleted and the stats are corrected.
+    pub fn audit_constraints(&mut self, fix: bool) -> Result<ConstraintReport> {
+        audit_constraints(&mut self.conn, fix)
+    }
+
+    pub fn transaction(&mut self) -> Transaction<'_, S> {
+        Transaction::new(self)
+    }
+
+    /// Take a consistent read-only view of the store, e.g. for exporting it
+    ///
+    /// Writes on other connections do not affect the snapshot, so it is typically taken on an
+    /// [`additional_connection`](Self::additional_connection) while the main one keeps writing.
+    pub fn snapshot(&mut self) -> Result<Snapshot<'_>> {
+        Snapshot::new(self)
+    }
+
+    /// Start a write transaction for grouping puts and alias changes into one atomic commit
+    ///
+    /// This waits for up to the [busy timeout](Config::with_busy_timeout) if another connection
+    /// is writing.
+    pub fn write_transaction(&mut self) -> Result<WriteTransaction<'_, S>> {
+        WriteTransaction::new(self)
+    }
+
+    /// Get a token for cancelling operations on this connection from another thread
+    ///
+    /// This is useful e.g. for not having to wait for an in-flight traversal of a huge DAG or
+    /// a GC run upon shutdown.
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.cancellation.clone()
+    }
+
+    /// Attribute block reads and writes performed through this handle to the given tag
+    ///
+    /// Connections obtained with [`additional_connection`](Self::additional_connection) inherit
+    /// the tag, but can be re-tagged independently. Use [`tag_stats`](Self::tag_stats) to see
+    /// the aggregated numbers across all connections.
+    pub fn set_tag(&mut self, tag: Option<&str>) {
+        self.tag = tag.map(|t| t.to_owned());
+    }
+
+    /// Aggregated block reads and writes per tag, see [`set_tag`](Self::set_tag)
+    ///
+    /// Operations performed without a tag are not counted.
+    pub fn tag_stats(&self) -> HashMap<String, TagStats> {
+        self.tag_stats
+            .lock()
+            .iter()
+            .map(|(k, v)| (k.clone(), *v))
+            .collect()
+    }
+
+    /// Get a temporary alias for safely adding blocks to the store
+    pub fn temp_pin(&self) -> TempPin {
+        TempPin::new(self.expired_temp_pins.clone())
+    }
+
+    /// Subscribe to changes of the store
+    ///
+    /// Events are sent once the transaction making the change has been committed, for changes
+    /// made through any connection to the same store within this process. Changes made by other
+    /// processes are not seen. Dropping the receiver ends the subscription.
+    pub fn subscribe(&self) -> std::sync::mpsc::Receiver<StoreEvent> {
+        self.events.subscribe()
+    }
+
+    /// the hashers to verify blocks with, if configured
+    pub(crate) fn verify_hashes(&self) -> Option<Hashers> {
+        self.config
+            .verify_hashes
+            .then(|| self.config.hashers.clone())
+    }
+
+    /// Get the operation counts of this store since it was opened
+    ///
+    /// The counters are shared by all connections to the same store within this process and
+    /// are cheap to maintain, so they are always on. They are not persisted.
+    pub fn counters(&self) -> Counters {
+        self.counters.get()
+    }
+
+    /// Run a full VACUUM on the SQLITE database
+    ///
+    /// This may take a while, blocking all other writes to the store.
+    pub fn vacuum(&mut self) -> Result<()> {
+        vacuum(&mut self.conn)
+    }
+
+    /// Shrink the DB file by up to `pages` of its free pages, returning the number of free pages left
+    ///
+    /// Unlike [`vacuum`](Self::vacuum) this only takes time proportional to `pages`, so the space
+    /// freed by gc can be given back to the file system in small steps. Gc already does this for
+    /// all free pages at its end. New stores are created with incremental auto_vacuum; for DB
+    /// files created by older versions this does nothing until a full [`vacuum`](Self::vacuum)
+    /// has converted them.
+    pub fn incremental_vacuum(&mut self, pages: u64) -> Result<u64> {
+        incremental_vacuum(&mut self.conn, Some(pages))
+    }
+
+    /// Write the graph of the links stored for the dag below `root` in graphviz DOT format
+    ///
+    /// Each block is labeled with an abbreviated cid and its size; blocks without data are drawn
+    /// dashed and aliases as extra nodes pointing at their root. Since the links come from the
+    /// database rather than from decoding the blocks, this shows what gc sees, which helps with
+    /// debugging malformed dags and pinning problems.
+    pub fn export_dot(&mut self, root: &Cid, mut writer: impl Write) -> Result<()> {
+        let root = CidBytes::try_from(root)?;
+        let graph = in_txn(&mut self.conn, None, false, move |txn| ref_graph(txn, root))?;
+        let mut dot = String::from("digraph dag {\n    node [shape=box, fontname=monospace];\n");
+        for (id, cid, size) in &graph.nodes {
+            let cid = short_cid(&Cid::try_from(cid)?);
+            match size {
+                Some(size) => {
+                    dot += &format!(
+                        "    n{} [label={}];\n",
+                        id,
+                        dot_quote(&format!("{}\n{} B", cid, size))
+                    )
+                }
+                None => {
+                    dot += &format!(
+                        "    n{} [label={}, style=dashed];\n",
+                        id,
+                        dot_quote(&format!("{}\nmissing", cid))
+                    )
+                }
+            }
+        }
+        for (parent, child) in &graph.edges {
+            dot += &format!("    n{} -> n{};\n", parent, child);
+        }
+        for (i, (name, id)) in graph.aliases.iter().enumerate() {
+            dot += &format!(
+                "    a{} [label={}, shape=ellipse];\n    a{} -> n{};\n",
+                i,
+                dot_quote(&String::from_utf8_lossy(name)),
+                i,
+                id
+            );
+        }
+        dot += "}\n";
+        writer
+            .write_all(dot.as_bytes())
+            .map_err(|e| io_error(e, "writing DOT graph"))
     }
 
-    pub fn transaction(&mut self) -> Transaction<'_, S> {
-        Transaction::new(self)
+    /// Copy the blocks and aliases of the block store file at `path` that are missing here
+    ///
+    /// The other file is attached to this connection and copied with bulk SQL in a single
+    /// transaction, which is much faster than putting its blocks one by one. Aliases that exist
+    /// in both stores keep their current target here. Hashes are only verified if
+    /// [configured](Config::with_verify_hashes), which means reading all copied blocks. The
+    /// other store must have been opened by this version at least once, so that its schema is
+    /// up to date.
+    pub fn merge_from_file(&mut self, path: impl AsRef<Path>) -> Result<MergeStats> {
+        let verify = self.verify_hashes().map(|hashers| {
+            Arc::new(move |cid: &Cid, data: &[u8]| verify_hash::<S>(&hashers, cid, data)) as Verify
+        });
+        let merged = merge_from_file(&mut self.conn, path.as_ref(), verify)?;
+        let stats = MergeStats {
+            blocks: merged.blocks.len() as u64,
+            bytes: merged.blocks.iter().map(|(_, _, len)| *len as u64).sum(),
+            aliases: merged.aliases.len() as u64,
+        };
+        let written = merged
+            .blocks
+            .iter()
+            .map(|(id, cid, len)| WriteInfo::new(BlockInfo::new(*id, cid, *len), false))
+            .collect::<Vec<_>>();
+        if !written.is_empty() {
+            self.config.cache_tracker.blocks_written(written);
+        }
+        AtomicCounters::add(&self.counters.block_writes, stats.blocks);
+        self.missing_cache
+            .remove(merged.blocks.iter().map(|(_, cid, _)| cid));
+        let added = merged
+            .blocks
+            .into_iter()
+            .map(|(_, cid, _)| StoreEvent::BlockAdded(cid));
+        let aliased = merged
+            .aliases
+            .into_iter()
+            .map(|(name, cid)| StoreEvent::AliasSet(name, cid));
+        self.events.emit(added.chain(aliased));
+        Ok(stats)
     }
 
-    /// Get a temporary alias for safely adding blocks to the store
-    pub fn temp_pin(&self) -> TempPin {
-        TempPin::new(self.expired_temp_pins.clone())
+    /// Compute the current gc candidates for incremental deletion with [`IncrementalGc::step`]
+    ///
+    /// This runs the expensive reachability query only once, whereas every call to
+    /// [`incremental_gc`](Self::incremental_gc) needs to compute the candidates anew.
+    pub fn start_gc(&mut self) -> Result<IncrementalGc> {
+        self.cleanup_temp_pins()?;
+        AtomicCounters::add(&self.counters.gc_runs, 1);
+        let ids = get_sorted_gc_candidates(
+            &mut self.conn,
+            &self.config.cache_tracker,
+            self.config.gc_grace_period,
+            false,
+        )?;
+        Ok(IncrementalGc { ids })
     }
 
-    /// Run a full VACUUM on the SQLITE database
+    /// Start deleting all blocks that are only reachable from `root`
     ///
-    /// This may take a while, blocking all other writes to the store.
-    pub fn vacuum(&mut self) -> Result<()> {
-        vacuum(&mut self.conn)
+    /// Blocks that are also reachable from another alias or a temp pin are kept. Without `force`,
+    /// aliases of `root` itself are respected as well, so the root needs to be unaliased first;
+    /// with `force`, those aliases are removed right away. Neither the gc filter nor the grace
+    /// period apply, since this is an explicit request to remove data.
+    ///
+    /// The blocks are deleted in budgeted chunks with [`Purge::step`]. If the process is
+    /// interrupted, calling this again picks up where it stopped, since the deleted blocks are no
+    /// longer part of the closure.
+    pub fn purge_closure(&mut self, root: &Cid, force: bool) -> Result<Purge> {
+        self.cleanup_temp_pins()?;
+        let root = CidBytes::try_from(root)?;
+        let (ids, removed) = in_txn(&mut self.conn, None, force, move |txn| {
+            get_purge_candidates(txn, root, force)
+        })?;
+        self.events
+            .emit(removed.into_iter().map(StoreEvent::AliasRemoved));
+        Ok(Purge { ids: ids.into() })
     }
 
     /// Perform maintenance on the TempPins
@@ -518,31 +2260,145 @@ where
         )
     }
 
+    /// Delete the temp pins of processes that are no longer running
+    ///
+    /// Every process writing to a store holds a lock on a file next to the database while it
+    /// has the store open, which tells whether the process that created a temp pin is still
+    /// alive. The temp pins of processes that went away are removed when the store is opened by
+    /// the first connection of a process, so this is only needed when several processes share
+    /// the database and one of them went away without cleaning up, while the others keep
+    /// running. Temp pins of running processes are kept.
+    ///
+    /// Returns the number of temp pins removed.
+    pub fn clear_stale_temp_pins(&mut self) -> Result<u64> {
+        self.cleanup_temp_pins()?;
+        self.clear_dead_sessions()
+    }
+
+    /// delete the temp pins of other sessions whose process is gone, see
+    /// [`clear_stale_temp_pins`](Self::clear_stale_temp_pins)
+    fn clear_dead_sessions(&mut self) -> Result<u64> {
+        let own = self.session;
+        let db = match &self.db_path {
+            DbPath::File(path) => {
+                Some(std::fs::canonicalize(path).unwrap_or_else(|_| path.clone()))
+            }
+            DbPath::Memory => None,
+        };
+        let mut sessions = in_txn(&mut self.conn, None, false, temp_pin_sessions)?;
+        if let Some(db) = &db {
+            // locks of sessions that died before creating any temp pins
+            sessions.extend(session::lock_files(db)?);
+            sessions.sort_unstable();
+            sessions.dedup();
+        }
+        let mut dead = Vec::new();
+        for session in sessions.into_iter().filter(|session| *session != own) {
+            // no other process can see an in-memory store
+            let live = match &db {
+                Some(db) => session::is_live(db, session)?,
+                None => false,
+            };
+            if !live {
+                dead.push(session);
+            }
+        }
+        in_txn(&mut self.conn, None, true, move |txn| {
+            clear_temp_pin_sessions(txn, &dead)
+        })
+    }
+
+    /// Enable or disable the gc marks of the store, which speed up incremental gc of large stores
+    ///
+    /// Without marks, every gc run traverses all dags below the aliases and temp pins to find
+    /// the unpinned blocks. With marks, the blocks reachable from recursive aliases and temp pins
+    /// are remembered in the database and kept up to date by triggers, for all connections and
+    /// processes using it, so a gc run only traverses what was pinned since the last one.
+    ///
+    /// Removing or moving a pin cannot unmark blocks cheaply, so blocks that are no longer pinned
+    /// are only collected once the marks are rebuilt. This happens when GC cannot meet the size
+    /// targets otherwise, and takes as long as the traversal without marks. Blocks are still
+    /// checked to be unpinned right before they are deleted.
+    ///
+    /// The setting is stored in the database; enabling computes the marks right away.
+    pub fn set_gc_marks(&mut self, enabled: bool) -> Result<()> {
+        in_txn(&mut self.conn, None, true, move |txn| {
+            set_gc_marks(txn, enabled)
+        })?;
+        if enabled {
+            rebuild_gc_marks(&mut self.conn)?;
+        }
+        Ok(())
+    }
+
     /// Perform full GC
     ///
     /// This is the same as running incremental GC without limits, plus a full SQLITE VACUUM.
     pub fn gc(&mut self) -> Result<()> {
         self.cleanup_temp_pins()?;
+        AtomicCounters::add(&self.counters.gc_runs, 1);
         self.flush()?;
         incremental_gc(
             &mut self.conn,
             usize::MAX,
             Duration::from_secs(u32::MAX.into()),
             self.config.size_targets,
-            &self.config.cache_tracker,
+            &self.events.tracker(
+                &self.config.cache_tracker,
+                &self.counters,
+                &self.block_cache,
+            ),
+            &self.config.gc_filter,
+            self.config.gc_grace_period,
         )?;
         self.vacuum()?;
         Ok(())
     }
 
-    fn maybe_checkpoint(&mut self) -> Result<()> {
+    /// Determine which blocks a full GC would delete, without deleting anything
+    ///
+    /// This runs the same reachability query and applies the same size targets and cache tracker
+    /// ordering as [`gc`](Self::gc), so it can be used to confirm a pinning problem before data
+    /// is destroyed. Expired temp pins do not protect any blocks, just like during GC, but are
+    /// left in place.
+    pub fn gc_preview(&mut self) -> Result<GcPreview> {
+        let expired_pins = self.expired_temp_pins.lock().clone();
+        let blocks = gc_preview(
+            &mut self.conn,
+            self.config.size_targets,
+            &self.config.cache_tracker,
+            &self.config.gc_filter,
+            self.config.gc_grace_period,
+            expired_pins,
+        )?;
+        let mut preview = GcPreview::default();
+        for (cid, size) in blocks {
+            preview.cids.push(Cid::try_from(&cid)?);
+            preview.size += size;
+        }
+        Ok(preview)
+    }
+
+    fn maybe_checkpoint(&mut self) -> Result<bool> {
         if self.recompute_done.load(Ordering::SeqCst) {
             self.conn
                 .pragma_update(None, "journal_size_limit", 10_000_000i64)
                 .ctx("setting journal_size_limit")?;
             self.conn
-                .pragma_update(None, "wal_checkpoint", &"RESTART")
+                .pragma_update(None, "wal_checkpoint", "RESTART")
                 .ctx("running wal_checkpoint(RESTART)")?;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    /// Truncate the WAL if it exceeds the configured size
+    pub(crate) fn maybe_truncate_wal(&mut self) -> Result<()> {
+        if let Some(limit) = self.config.checkpoint_wal_size {
+            if self.wal_size()? > limit && self.checkpoint(CheckpointMode::Truncate)?.busy() {
+                debug!("WAL could not be truncated because of other connections");
+            }
         }
         Ok(())
     }
@@ -560,26 +2416,149 @@ where
         let stats = self.get_store_stats()?;
         let _span = tracing::debug_span!("incGC", stats = ?&stats).entered();
         self.cleanup_temp_pins()?;
+        AtomicCounters::add(&self.counters.gc_runs, 1);
         self.maybe_checkpoint()?;
         let ret = incremental_gc(
             &mut self.conn,
             min_blocks,
             max_duration,
             self.config.size_targets,
-            &self.config.cache_tracker,
+            &self.events.tracker(
+                &self.config.cache_tracker,
+                &self.counters,
+                &self.block_cache,
+            ),
+            &self.config.gc_filter,
+            self.config.gc_grace_period,
         )?;
         self.maybe_checkpoint()?;
-        in_txn(
-            &mut self.conn,
-            Some(("incremental_vacuum", Duration::from_millis(500))),
-            false,
-            |txn| {
-                txn.execute_batch("PRAGMA incremental_vacuum")
-                    .ctx("incremental vacuum")
-            },
-        )?;
+        incremental_vacuum(&mut self.conn, None)?;
+        self.maybe_truncate_wal()?;
         Ok(ret)
     }
+
+    /// Run incremental GC and orphan cleanup until done or `max_duration` is exhausted
+    ///
+    /// In contrast to [`gc`](Self::gc) this does not perform a full VACUUM and does not risk
+    /// blocking the store for an unbounded amount of time; each deletion is done in its own
+    /// short transaction. The budget may be exceeded by the duration of a single transaction.
+    ///
+    /// Returns true if the size targets are met (or no unpinned blocks are left) and all
+    /// orphaned cids have been removed.
+    pub fn gc_until_done(&mut self, max_duration: Duration) -> Result<bool> {
+        let t0 = Instant::now();
+        while !self.incremental_gc(0, max_duration.saturating_sub(t0.elapsed()))? {
+            if t0.elapsed() >= max_duration {
+                return Ok(false);
+            }
+        }
+        let (_, complete) =
+            delete_orphaned_cids(&mut self.conn, max_duration.saturating_sub(t0.elapsed()))?;
+        Ok(complete)
+    }
+
+    /// Delete orphaned cids in batches until none are left or `max_duration` is exhausted
+    ///
+    /// Each batch is deleted in its own short transaction, so other connections are not
+    /// starved. Orphans created concurrently are picked up as well.
+    ///
+    /// Returns true if no orphans are left.
+    pub fn delete_orphaned(&mut self, max_duration: Duration) -> Result<bool> {
+        let t0 = Instant::now();
+        loop {
+            let (deleted, complete) =
+                delete_orphaned_cids(&mut self.conn, max_duration.saturating_sub(t0.elapsed()))?;
+            tracing::debug!(deleted, complete, "orphan cleanup pass");
+            if !complete {
+                return Ok(false);
+            }
+            if self.count_orphaned()? == 0 {
+                return Ok(true);
+            }
+            if t0.elapsed() >= max_duration {
+                return Ok(false);
+            }
+        }
+    }
+
+    /// Perform the most pressing maintenance work within the given time budget
+    ///
+    /// This is meant to be called whenever the application is idle. The tasks are, in order of
+    /// priority: dropping expired temp pins, deleting orphaned cids, checkpointing the WAL,
+    /// incremental vacuum, and updating query planner statistics. A task is only started if
+    /// the budget is not yet exhausted, but it will not be interrupted once started.
+    ///
+    /// Note that this does not delete any blocks, use [`incremental_gc`](Self::incremental_gc)
+    /// for that.
+    pub fn maintain(&mut self, max_duration: Duration) -> Result<MaintenanceReport> {
+        let _span = tracing::debug_span!("maintain", ?max_duration).entered();
+        let t0 = Instant::now();
+        let mut report = MaintenanceReport {
+            temp_pins_dropped: self.expired_temp_pins.lock().len(),
+            ..Default::default()
+        };
+        self.cleanup_temp_pins()?;
+
+        if t0.elapsed() >= max_duration {
+            return Ok(report);
+        }
+        let (deleted, complete) =
+            delete_orphaned_cids(&mut self.conn, max_duration.saturating_sub(t0.elapsed()))?;
+        report.orphans_deleted = deleted;
+        if !complete || t0.elapsed() >= max_duration {
+            return Ok(report);
+        }
+
+        report.checkpointed = self.maybe_checkpoint()?;
+        if t0.elapsed() >= max_duration {
+            return Ok(report);
+        }
+
+        incremental_vacuum(&mut self.conn, None)?;
+        report.vacuumed = true;
+        if t0.elapsed() >= max_duration {
+            return Ok(report);
+        }
+
+        optimize(&mut self.conn)?;
+        report.analyzed = true;
+        report.complete = true;
+        Ok(report)
+    }
+}
+
+/// `path` with `-suffix` appended to the file name, like SQLite does for the WAL
+/// how often [`BlockStore::update_standby`] tries to read the database file with an empty WAL
+const STANDBY_ATTEMPTS: u32 = 10;
+
+fn sibling(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push("-");
+    name.push(suffix);
+    name.into()
+}
+
+/// a short label for a cid, which is still enough to tell the blocks of a dag apart
+fn short_cid(cid: &Cid) -> String {
+    let text = cid.to_string();
+    if text.len() <= 16 {
+        text
+    } else {
+        format!("{}…{}", &text[..8], &text[text.len() - 6..])
+    }
+}
+
+/// quote a string for use as a DOT identifier or label
+fn dot_quote(text: &str) -> String {
+    let escaped = text
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n");
+    format!("\"{}\"", escaped)
+}
+
+fn io_error(e: std::io::Error, msg: &'static str) -> BlockStoreError {
+    BlockStoreError::Other(anyhow::Error::new(e).context(msg))
 }
 
 macro_rules! delegate {
@@ -602,6 +2581,9 @@ where
     Ipld: References<S::Codecs>,
 {
     /// Set or delete an alias
+    ///
+    /// Re-pointing an existing alias keeps its [pin mode](Self::alias_with_mode), new aliases are
+    /// [recursive](PinMode::Recursive).
     pub fn alias<'b>(
         &mut self,
         name: impl Into<Cow<'b, [u8]>>,
@@ -610,18 +2592,146 @@ where
         self.transaction().alias(name, link)
     }
 
+    /// Set an alias with the given pin mode
+    ///
+    /// A [direct](PinMode::Direct) alias only protects the root block from gc, while the blocks
+    /// linked from it are still collected unless pinned by other means.
+    pub fn alias_with_mode<'b>(
+        &mut self,
+        name: impl Into<Cow<'b, [u8]>>,
+        link: &'b Cid,
+        mode: PinMode,
+    ) -> Result<()> {
+        self.transaction().alias_with_mode(name, link, mode)
+    }
+
+    /// Set an alias protecting only the blocks up to `max_depth` links below the root
+    ///
+    /// This is a shorthand for [`alias_with_mode`](Self::alias_with_mode) with
+    /// [`PinMode::Depth`].
+    pub fn alias_with_depth<'b>(
+        &mut self,
+        name: impl Into<Cow<'b, [u8]>>,
+        link: &'b Cid,
+        max_depth: u32,
+    ) -> Result<()> {
+        self.alias_with_mode(name, link, PinMode::Depth(max_depth))
+    }
+
+    /// Add a batch of blocks and set an alias on `root` in a single transaction
+    ///
+    /// Unlike [`put_blocks`](Self::put_blocks) followed by [`alias`](Self::alias), a concurrent
+    /// gc can never observe the new blocks unpinned, and a failure leaves the store unchanged.
+    pub fn add_tree<'b, I>(
+        &mut self,
+        name: impl Into<Cow<'b, [u8]>>,
+        root: &'b Cid,
+        blocks: I,
+    ) -> Result<()>
+    where
+        I: IntoIterator<Item = Block<S>>,
+    {
+        let name = name.into();
+        let blocks = blocks.into_iter().collect();
+        self.write_blocks(blocks, None, |store, blocks, _| {
+            let mut txn = store.transaction();
+            txn.add_tree(name.as_ref(), root, blocks)?;
+            txn.commit()
+        })?;
+        self.maybe_truncate_wal()
+    }
+
+    /// Replace a temp pin with an alias on `root`
+    ///
+    /// The alias is set and the temp pin emptied atomically, so there is no window in which gc
+    /// could collect the blocks of e.g. a finished sync. The emptied temp pin can be reused.
+    pub fn promote_temp_pin<'b>(
+        &mut self,
+        pin: &mut TempPin,
+        name: impl Into<Cow<'b, [u8]>>,
+        root: &'b Cid,
+    ) -> Result<()> {
+        self.transaction().promote_temp_pin(pin, name, root)
+    }
+
     /// Resolves an alias to a cid
     pub fn resolve<'b>(&mut self, name: impl Into<Cow<'b, [u8]>>) -> Result<Option<Cid>> {
         self.transaction().resolve(name)
     }
 
+    /// Set or delete an alias, returning the cid it previously pointed to
+    ///
+    /// Reading the old value and writing the new one happen atomically, so this can be used to
+    /// detect concurrent updates of mutable pointers.
+    pub fn update_alias<'b>(
+        &mut self,
+        name: impl Into<Cow<'b, [u8]>>,
+        link: Option<&'b Cid>,
+    ) -> Result<Option<Cid>> {
+        self.transaction().update_alias(name, link)
+    }
+
+    /// Get the number and total size of the blocks pinned by an alias
+    ///
+    /// Only blocks down to the [max depth](Self::alias_with_depth) of the alias are counted.
+    /// Blocks shared with other pins are counted for each of them. Returns `None` if there is no
+    /// alias with this name.
+    pub fn alias_stats<'b>(&mut self, name: impl Into<Cow<'b, [u8]>>) -> Result<Option<DagStats>> {
+        self.transaction().alias_stats(name)
+    }
+
+    /// Get the root, timestamps and metadata of an alias
+    pub fn alias_info<'b>(&mut self, name: impl Into<Cow<'b, [u8]>>) -> Result<Option<AliasInfo>> {
+        self.transaction().alias_info(name)
+    }
+
+    /// Attach application defined metadata to an existing alias, e.g. why it was pinned
+    ///
+    /// Returns false if there is no such alias. The metadata is kept when the alias is set to
+    /// a different root and deleted together with the alias.
+    pub fn set_alias_metadata<'b>(
+        &mut self,
+        name: impl Into<Cow<'b, [u8]>>,
+        metadata: Option<&[u8]>,
+    ) -> Result<bool> {
+        self.transaction().set_alias_metadata(name, metadata)
+    }
+
+    /// Atomically move an alias to a new name
+    ///
+    /// Fails with [`AliasExists`](BlockStoreError::AliasExists) if `new` is already taken.
+    /// Returns false if there is no alias named `old`.
+    pub fn rename_alias<'b>(
+        &mut self,
+        old: impl Into<Cow<'b, [u8]>>,
+        new: impl Into<Cow<'b, [u8]>>,
+    ) -> Result<bool> {
+        self.transaction().rename_alias(old, new)
+    }
+
     delegate! {
         /// Returns the aliases referencing a cid
+        ///
+        /// This includes all aliases whose dag reaches the cid, so it answers the question why a
+        /// block is not collected by gc (apart from temp pins). Returns `None` if the cid is
+        /// not known to the store.
         reverse_alias(cid: &Cid) -> Result<Option<HashSet<Vec<u8>>>>;
 
         /// Extend temp pin with an additional cid
         extend_temp_pin(pin: &mut TempPin, link: &Cid) -> Result<()>;
 
+        /// Extend temp pin with several cids at once
+        ///
+        /// The cids don't need to be in the store yet, so this can protect blocks that are about
+        /// to be fetched.
+        extend_temp_pin_many(pin: &mut TempPin, links: &[Cid]) -> Result<()>;
+
+        /// List the ids of all temp pins together with the number of blocks they pin, ordered by id
+        ///
+        /// The ids correspond to [`TempPin::id`]. Temp pins that have been dropped are included
+        /// until they are cleaned up, see [`cleanup_temp_pins`](Self::cleanup_temp_pins).
+        temp_pins<C: FromIterator<(i64, u64)>>() -> Result<C>;
+
         /// Checks if the store knows about the cid.
         ///
         /// Note that this does not necessarily mean that the store has the data for the cid.
@@ -639,35 +2749,378 @@ where
         /// Get descendants of a cid
         get_descendants<C: FromIterator<Cid>>(cid: &Cid) -> Result<C>;
 
+        /// Get descendants of a cid with their depth below it, in the given traversal order
+        ///
+        /// The cid itself is included at depth 0. Children are visited in the order in which
+        /// they are linked from the block data, which is decoded for this, so this is slower than
+        /// [`get_descendants`](Self::get_descendants).
+        get_descendants_ordered<C: FromIterator<(Cid, u32)>>(cid: &Cid, order: Traversal) -> Result<C>;
+
+        /// Get the blocks that link directly to a cid
+        ///
+        /// Only blocks whose data is in the store are known to link to anything.
+        get_referrers<C: FromIterator<Cid>>(cid: &Cid) -> Result<C>;
+
+        /// Get all blocks from which a cid is reachable, not including the cid itself
+        ///
+        /// Together with [`reverse_alias`](Self::reverse_alias), this tells what still
+        /// references a block.
+        get_ancestors<C: FromIterator<Cid>>(cid: &Cid) -> Result<C>;
+
+        /// Get the direct children of a block
+        ///
+        /// Returns `None` if the store does not have the data for this cid.
+        get_links<C: FromIterator<Cid>>(cid: &Cid, mode: LinkMode) -> Result<Option<C>>;
+
+        /// Given a root of a dag, gives the cids up to `max_depth` links below it which we do
+        /// not have data for
+        ///
+        /// With a depth of 0 only the root itself is checked. This is for syncing a dag a few
+        /// levels at a time without traversing all of it on every call.
+        get_missing_blocks_to_depth<C: FromIterator<Cid>>(cid: &Cid, max_depth: u32) -> Result<C>;
+
+        /// Given several roots, gives all cids reachable from any of them which we do not have
+        /// data for
+        ///
+        /// This is a single traversal, so blocks shared between the dags are visited and
+        /// returned only once.
+        get_missing_blocks_many<C: FromIterator<Cid>>(cids: &[Cid]) -> Result<C>;
+
+        /// Given several roots, gives the cids reachable from them which we do not have data
+        /// for, in the order they should be fetched
+        ///
+        /// This is meant for driving a fetcher like bitswap: blocks reachable from earlier
+        /// roots come first, and for each root the blocks closest to it, whose links reveal
+        /// the rest of the dag, come before deeper ones. See [`wantlist`](Self::wantlist) for
+        /// also being notified when the blocks arrive.
+        wanted_blocks<C: FromIterator<Cid>>(roots: &[Cid]) -> Result<C>;
+
+        /// Given a root of a dag, gives the next `limit` cids which we do not have data for
+        ///
+        /// The missing cids are returned in ascending order, starting after `after`, which is
+        /// the last cid of the previous chunk or `None` to start from the beginning; a chunk
+        /// shorter than `limit` is the last one. Each call is a short read transaction, so the
+        /// blocks can be fetched and put in between. Blocks discovered to be missing during a
+        /// pass may sort before the current position, so a fetcher should start over until the
+        /// first chunk comes back empty.
+        get_missing_blocks_chunked<C: FromIterator<Cid>>(cid: &Cid, limit: usize, after: Option<&Cid>) -> Result<C>;
+
+        /// Count the cids of a dag which we do not have data for
+        ///
+        /// This is the length of [`get_missing_blocks`](Self::get_missing_blocks), without
+        /// reading the cids.
+        missing_block_count(cid: &Cid) -> Result<u64>;
+
+        /// Check whether the store has the data for all blocks of a dag
+        ///
+        /// This is equivalent to [`get_missing_blocks`](Self::get_missing_blocks) returning
+        /// nothing, but stops at the first missing block instead of listing all of them.
+        is_complete(cid: &Cid) -> Result<bool>;
+
         /// Given a root of a dag, gives all cids which we do not have data for.
         get_missing_blocks<C: FromIterator<Cid>>(cid: &Cid) -> Result<C>;
 
-        /// list all aliases
-        aliases<C: FromIterator<(Vec<u8>, Cid)>>() -> Result<C>;
+        /// Get all cids that the store knows about, up to a limit
+        ///
+        /// The limited variants stop reading from the database once the limit is hit, so they
+        /// are safe to use on stores or dags of any size.
+        get_known_cids_limited<C: FromIterator<Cid>>(limit: Limit) -> Result<Limited<C>>;
+
+        /// Get `limit` of the cids that the store knows about, starting at `cursor`
+        ///
+        /// Pass 0 for the first page and [`Page::next`] for the following ones. The cids are
+        /// walked in the order in which the store learned about them, each page in its own short
+        /// transaction, so this can list a store of any size. Cids added while paging are
+        /// included in a later page. A `limit` of 0 returns no cids, but still tells whether
+        /// there are more after `cursor`.
+        get_known_cids_page<C: FromIterator<Cid>>(cursor: u64, limit: usize) -> Result<Page<C>>;
 
-        /// Put a block
+        /// Get the cids that the store knows about whose binary form starts with `prefix`, up
+        /// to a limit
         ///
-        /// This will only be completed once the transaction is successfully committed.
-        put_block(block: Block<S>, pin: Option<&mut TempPin>) -> Result<()>;
+        /// This is a range scan on the cid index, so it is fast for any store size as long as the
+        /// prefix is selective. The cids are returned in ascending order of their binary form.
+        /// A truncated cid in text form needs to be decoded to bytes by the caller; with base32,
+        /// only the complete 8-character groups of the string can be decoded exactly.
+        find_cids_with_prefix<C: FromIterator<Cid>>(prefix: &[u8], limit: Limit) -> Result<Limited<C>>;
+
+        /// Get all cids for which the store has blocks, up to a limit
+        get_block_cids_limited<C: FromIterator<Cid>>(limit: Limit) -> Result<Limited<C>>;
+
+        /// Get descendants of a cid, up to a limit
+        get_descendants_limited<C: FromIterator<Cid>>(cid: &Cid, limit: Limit) -> Result<Limited<C>>;
+
+        /// Given a root of a dag, gives the cids which we do not have data for, up to a limit
+        get_missing_blocks_limited<C: FromIterator<Cid>>(cid: &Cid, limit: Limit) -> Result<Limited<C>>;
+
+        /// list all aliases, ordered by name
+        aliases<C: FromIterator<(Vec<u8>, Cid)>>() -> Result<C>;
 
         /// Get a block
         get_block(cid: &Cid) -> Result<Option<Vec<u8>>>;
 
+        /// Get up to `limit` blocks in the order in which they were added, starting after `cursor`
+        ///
+        /// Returns the blocks together with the cursor to pass next time, which stays the same
+        /// if there are no new blocks. Pass 0 to start from the beginning. The cursor can be
+        /// persisted, which allows replicating a store incrementally by polling for everything
+        /// added since the last call. Blocks that are deleted by gc and added again show up
+        /// again.
+        get_blocks_since<C: FromIterator<(Cid, Vec<u8>)>>(cursor: u64, limit: usize) -> Result<(C, u64)>;
+
+        /// Get up to `limit` entries of the change log with a sequence number greater than `seq`
+        ///
+        /// Every put of new data, gc or purge deletion and alias change is logged in the same
+        /// transaction as the change itself, so a consumer that remembers the last sequence number
+        /// it has seen can catch up on everything that happened since, even across restarts. The
+        /// log starts with the blocks and aliases that existed when it was created. Pass 0 to
+        /// start from the beginning.
+        ///
+        /// The log grows with every change and is never pruned automatically, see
+        /// [`truncate_changes`](Self::truncate_changes).
+        changes_since<C: FromIterator<Change>>(seq: u64, limit: usize) -> Result<C>;
+
+        /// Delete the entries of the change log with a sequence number less than `seq`
+        ///
+        /// Meant to be called with the oldest sequence number any consumer still needs, since
+        /// consumers that are further behind miss the deleted changes. Sequence numbers are not
+        /// reused, so [`changes_since`](Self::changes_since) keeps working for the rest. Returns
+        /// the number of deleted entries.
+        truncate_changes(seq: u64) -> Result<usize>;
+
+        /// Get the size and number of links of a block without reading its data
+        ///
+        /// Returns `None` if the cid is not known to the store.
+        block_stat(cid: &Cid) -> Result<Option<BlockStat>>;
+
+        /// Get the cids of all blocks with the given codec
+        cids_by_codec<C: FromIterator<Cid>>(codec: u64) -> Result<C>;
+
+        /// Get the number and size of the blocks per codec, ordered by codec
+        codec_stats<C: FromIterator<CodecStats>>() -> Result<C>;
+
+        /// Get the number and total size of the blocks reachable from a cid, including itself
+        ///
+        /// Blocks shared within the dag are counted once. This traverses the whole dag, so it
+        /// takes time proportional to its size.
+        reachable_size(cid: &Cid) -> Result<DagStats>;
+
+        /// Get the cids and sizes of the `n` largest blocks, largest first
+        ///
+        /// This scans all blocks, but does not read their data.
+        largest_blocks<C: FromIterator<(Cid, u64)>>(n: usize) -> Result<C>;
+
+        /// Get a block by the multihash of its data, together with the cid under which it is
+        /// stored
+        ///
+        /// This finds a block regardless of the cid version and codec it was stored under, e.g.
+        /// the v1 equivalent of a v0 cid. If the same data is stored under several cids, the
+        /// oldest one is returned.
+        get_block_by_multihash(multihash: &Multihash) -> Result<Option<(Cid, Vec<u8>)>>;
+
+        /// Get a block, writing its data into `buf`
+        ///
+        /// This avoids allocating a new buffer for every read on hot paths; the contents of
+        /// `buf` are replaced. Use [`block_stat`](Self::block_stat) to learn the size up front.
+        /// Returns false if the store does not have the data for this cid.
+        get_block_into(cid: &Cid, buf: &mut Vec<u8>) -> Result<bool>;
+
         /// Get the stats for the store
         ///
         /// The stats are kept up to date, so this is fast.
         get_store_stats() -> Result<StoreStats>;
+
+        /// Get the id of the store, a random UUID generated when the store was created
+        ///
+        /// The id stays the same when the store is reopened, so it can be used to tell stores
+        /// apart in replication or backup catalogs. A copy of the file, e.g. a backup, has the
+        /// same id as the original.
+        store_id() -> Result<String>;
+
+        /// Get block, cid, orphan, alias and temp pin counts as of a single point in time
+        ///
+        /// Unlike [`get_store_stats`](Self::get_store_stats) this scans the cids, so it takes time
+        /// proportional to the size of the store.
+        get_store_summary() -> Result<StoreSummary>;
+
+        /// Check that the links stored for a block match those decoded from its data
+        ///
+        /// This catches blocks that were put with incorrectly computed links. Returns `None` if
+        /// the store does not have the data for this cid.
+        verify_links(cid: &Cid) -> Result<Option<LinkDiff>>;
+
+        /// Check the links of `n` randomly chosen blocks, see [`verify_links`](Self::verify_links)
+        ///
+        /// Only the blocks with mismatching links are returned.
+        verify_links_sample<C: FromIterator<(Cid, LinkDiff)>>(n: usize) -> Result<C>;
+
+        /// Replace the links stored for a block
+        ///
+        /// This is for repairing blocks that were stored with wrong links; passing incomplete
+        /// links makes gc collect blocks that are still reachable. Returns false if the store
+        /// does not have the data for this cid.
+        set_links(cid: &Cid, links: &[Cid]) -> Result<bool>;
+
+        /// Record the links of a block whose data is not here yet
+        ///
+        /// [`get_missing_blocks`](Self::get_missing_blocks) follows these links, so a dag can be
+        /// planned before its blocks arrive. They are replaced by the links decoded from the data
+        /// once it is put, and they do not protect anything from gc, so the cid should be pinned.
+        /// Returns false if the store already has the data.
+        add_links_only(cid: &Cid, links: &[Cid]) -> Result<bool>;
+
+        /// Decode the links of a stored block again and store them, see
+        /// [`set_links`](Self::set_links)
+        ///
+        /// Returns false if the store does not have the data for this cid.
+        reindex_links(cid: &Cid) -> Result<bool>;
+
+        /// Count the cids that have neither data nor anything referencing them
+        ///
+        /// These are removed by [`delete_orphaned`](Self::delete_orphaned) and as part of GC.
+        count_orphaned() -> Result<u64>;
     }
 
-    pub fn put_blocks<I>(&mut self, blocks: I, mut pin: Option<&mut TempPin>) -> Result<()>
+    /// Fetch the missing blocks of a dag on demand until it is complete
+    ///
+    /// This allows lazily materializing a dag of which only the root is known, e.g. from the
+    /// [`CarIndex`] of a large CAR file. `fetch` is called for every missing cid; its data are
+    /// checked against the cid before being stored, using the hashers of the [`Config`], and
+    /// returning `None` leaves that part of the dag missing. The fetched blocks are only
+    /// protected from gc while this runs, so the root should be aliased beforehand.
+    ///
+    /// Returns the number of blocks fetched.
+    pub fn hydrate<F>(&mut self, root: &Cid, mut fetch: F) -> Result<usize>
     where
-        I: IntoIterator<Item = Block<S>>,
+        F: FnMut(&Cid) -> Result<Option<Vec<u8>>>,
     {
+        let mut pin = self.temp_pin();
+        let mut unavailable = HashSet::new();
+        let mut fetched = 0;
+        loop {
+            let missing = self
+                .get_missing_blocks::<Vec<_>>(root)?
+                .into_iter()
+                .filter(|cid| !unavailable.contains(cid))
+                .collect::<Vec<_>>();
+            if missing.is_empty() {
+                return Ok(fetched);
+            }
+            for cid in missing {
+                match fetch(&cid)? {
+                    Some(data) => {
+                        verify_hash::<S>(&self.config.hashers, &cid, &data)?;
+                        let block = Block::<S>::new_unchecked(cid, data);
+                        self.put_block(block, Some(&mut pin))?;
+                        fetched += 1;
+                    }
+                    None => {
+                        unavailable.insert(cid);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Open the data of a block for reading it in chunks
+    ///
+    /// Unlike [`get_block`](Self::get_block), this does not load the whole block into memory,
+    /// which helps with streaming large blocks to the network. Like a [`Snapshot`], the reader
+    /// keeps a read transaction open until it is dropped, so it sees the block as it was when
+    /// opened even if other connections delete it meanwhile, and the WAL cannot be checkpointed
+    /// past it. Returns `None` if the store does not have the data for this cid.
+    pub fn open_block_reader(&mut self, cid: &Cid) -> Result<Option<BlockReader<'_>>> {
         let mut txn = self.transaction();
-        for block in blocks {
-            #[allow(clippy::needless_option_as_deref)]
-            txn.put_block(block, pin.as_deref_mut())?;
+        let id = txn.get_block_id(cid)?;
+        txn.commit()?;
+        match id {
+            Some(id) => Ok(Some(BlockReader {
+                blob: open_block_blob(&self.conn, id)?,
+            })),
+            None => Ok(None),
+        }
+    }
+
+    /// Iterate over the cids and data of all blocks in the store
+    ///
+    /// The blocks are read in batches, each in its own short transaction, so writes from other
+    /// connections are not held up. Blocks added while iterating may or may not be included, and
+    /// blocks deleted while iterating may still be returned from the current batch. The order
+    /// is unspecified. After an error, the iterator ends.
+    pub fn iter_blocks(&mut self) -> BlockIter<'_, S> {
+        BlockIter {
+            store: self,
+            after: 0,
+            batch: VecDeque::new(),
+            done: false,
+        }
+    }
+
+    /// Iterate over the blocks of the dag below `root` that match `selector`
+    ///
+    /// The blocks are visited depth first in the order of their links, as in a CAR file, and
+    /// each is read in its own short transaction. Blocks that are not in the store are skipped.
+    /// After an error, the iterator ends.
+    pub fn export(&mut self, root: &Cid, selector: Selector) -> Export<'_, S> {
+        Export::new(self, *root, selector)
+    }
+
+    /// Stage blocks in memory, to be written in a single transaction or thrown away
+    ///
+    /// Reads on the overlay fall through to the store for blocks that are not staged. This is
+    /// useful for speculative operations like validating an incoming dag before accepting it.
+    pub fn overlay(&mut self) -> Overlay<'_, S> {
+        Overlay::new(self)
+    }
+
+    /// Put several blocks in a single transaction
+    ///
+    /// If anything fails, none of the blocks are written. If a temp pin is given, all blocks are
+    /// added to it, so a dag can be written in several batches without gc collecting the parts
+    /// written so far.
+    pub fn put_blocks<I>(&mut self, blocks: I, pin: Option<&mut TempPin>) -> Result<()>
+    where
+        I: IntoIterator<Item = Block<S>>,
+    {
+        let blocks = blocks.into_iter().collect();
+        self.write_blocks(blocks, pin, |store, blocks, pin| {
+            let mut txn = store.transaction();
+            txn.put_blocks(blocks, pin)?;
+            txn.commit()
+        })?;
+        self.maybe_truncate_wal()
+    }
+
+    /// Put a block
+    ///
+    /// This will only be completed once the transaction is successfully committed.
+    pub fn put_block(&mut self, block: Block<S>, pin: Option<&mut TempPin>) -> Result<()> {
+        self.put_blocks(std::iter::once(block), pin)
+    }
+
+    /// run `write`, retrying it once after gc if the disk is full and
+    /// [`with_gc_on_disk_full`](Config::with_gc_on_disk_full) is set
+    fn write_blocks<F>(
+        &mut self,
+        blocks: Vec<Block<S>>,
+        mut pin: Option<&mut TempPin>,
+        write: F,
+    ) -> Result<()>
+    where
+        F: Fn(&mut Self, Vec<Block<S>>, Option<&mut TempPin>) -> Result<()>,
+    {
+        let (min_blocks, max_duration) = match self.config.gc_on_disk_full {
+            Some(gc) => gc,
+            None => return write(self, blocks, pin),
+        };
+        #[allow(clippy::needless_option_as_deref)]
+        match write(self, blocks.clone(), pin.as_deref_mut()) {
+            Err(BlockStoreError::DiskFull(msg)) => {
+                tracing::info!(operation = msg, "disk full, running gc before retrying");
+                self.incremental_gc(min_blocks, max_duration)?;
+                write(self, blocks, pin)
+            }
+            res => res,
         }
-        txn.commit()
     }
 }
@@ -0,0 +1,121 @@
+use hashlink::LinkedHashMap;
+use libipld::Cid;
+use parking_lot::Mutex;
+use std::{
+    fmt,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+/// the cids recently found to have no data, see
+/// [`with_missing_cache`](crate::Config::with_missing_cache)
+///
+/// Shared by all handles to the same database file within this process, like the block cache.
+#[derive(Clone, Default)]
+pub(crate) struct MissingCache(Option<Arc<Mutex<Missing>>>);
+
+struct Missing {
+    // oldest first, so expired entries are at the front
+    entries: LinkedHashMap<Cid, Instant>,
+    capacity: usize,
+    ttl: Duration,
+    // bumped whenever blocks are written, see `generation`
+    generation: u64,
+}
+
+impl fmt::Debug for MissingCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut f = f.debug_struct("MissingCache");
+        if let Some(missing) = &self.0 {
+            let missing = missing.lock();
+            f.field("cids", &missing.entries.len())
+                .field("capacity", &missing.capacity)
+                .field("ttl", &missing.ttl);
+        }
+        f.finish()
+    }
+}
+
+impl MissingCache {
+    /// a cache of at most `capacity` cids, each remembered for `ttl`, or none at all for 0
+    pub(crate) fn new(capacity: usize, ttl: Duration) -> Self {
+        Self((capacity > 0 && ttl > Duration::ZERO).then(|| {
+            Arc::new(Mutex::new(Missing {
+                entries: LinkedHashMap::new(),
+                capacity,
+                ttl,
+                generation: 0,
+            }))
+        }))
+    }
+
+    /// whether `cid` was found to have no data less than the ttl ago
+    pub(crate) fn contains(&self, cid: &Cid) -> bool {
+        let mut missing = match &self.0 {
+            Some(missing) => missing.lock(),
+            None => return false,
+        };
+        match missing.entries.get(cid) {
+            Some(expires) if *expires > Instant::now() => true,
+            Some(_) => {
+                missing.entries.remove(cid);
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// to be taken before looking for a block, and passed to [`insert`](Self::insert)
+    ///
+    /// This way a block written while it was looked for is not remembered as missing.
+    pub(crate) fn generation(&self) -> u64 {
+        self.0
+            .as_ref()
+            .map(|m| m.lock().generation)
+            .unwrap_or_default()
+    }
+
+    /// remember that `cid` has no data, unless blocks were written since `generation`
+    pub(crate) fn insert(&self, cid: Cid, generation: u64) {
+        let mut missing = match &self.0 {
+            Some(missing) => missing.lock(),
+            None => return,
+        };
+        if missing.generation != generation {
+            return;
+        }
+        let now = Instant::now();
+        let expires = now + missing.ttl;
+        missing.entries.remove(&cid);
+        missing.entries.insert(cid, expires);
+        while missing.entries.len() > missing.capacity
+            || missing
+                .entries
+                .front()
+                .map_or(false, |(_, expires)| *expires <= now)
+        {
+            missing.entries.pop_front();
+        }
+    }
+
+    /// forget about cids whose data has been written, after the write was committed
+    pub(crate) fn remove<'a>(&self, cids: impl IntoIterator<Item = &'a Cid>) {
+        let mut missing = match &self.0 {
+            Some(missing) => missing.lock(),
+            None => return,
+        };
+        missing.generation += 1;
+        for cid in cids {
+            missing.entries.remove(cid);
+        }
+    }
+
+    /// forget everything, e.g. after the database file was replaced
+    pub(crate) fn clear(&self) {
+        if let Some(missing) = &self.0 {
+            let mut missing = missing.lock();
+            missing.generation += 1;
+            missing.entries.clear();
+        }
+    }
+}
@@ -0,0 +1,127 @@
+use crate::{BlockStore, Result};
+use fnv::{FnvHashMap, FnvHashSet};
+use libipld::{codec::References, store::StoreParams, Block, Cid, Ipld};
+use std::fmt;
+
+/// Blocks staged in memory on top of a store, see [`overlay`](BlockStore::overlay)
+///
+/// Reads see the staged blocks as well as those in the store. Nothing is written until
+/// [`commit`](Self::commit); dropping the overlay discards the staged blocks.
+pub struct Overlay<'a, S> {
+    store: &'a mut BlockStore<S>,
+    staged: FnvHashMap<Cid, Block<S>>,
+    bytes: u64,
+}
+
+impl<'a, S> fmt::Debug for Overlay<'a, S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Overlay")
+            .field("staged", &self.staged.len())
+            .field("bytes", &self.bytes)
+            .finish()
+    }
+}
+
+impl<'a, S> Overlay<'a, S> {
+    pub(crate) fn new(store: &'a mut BlockStore<S>) -> Self {
+        Self {
+            store,
+            staged: FnvHashMap::default(),
+            bytes: 0,
+        }
+    }
+
+    /// Number of staged blocks
+    pub fn staged(&self) -> usize {
+        self.staged.len()
+    }
+
+    /// Total size of the staged blocks
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    /// Throw away the staged blocks, leaving the store as it was
+    pub fn discard(self) {}
+}
+
+impl<'a, S> Overlay<'a, S>
+where
+    S: StoreParams,
+    Ipld: References<S::Codecs>,
+{
+    /// Stage a block; blocks that are staged already are ignored
+    pub fn put_block(&mut self, block: Block<S>) {
+        let len = block.data().len() as u64;
+        if self.staged.insert(*block.cid(), block).is_none() {
+            self.bytes += len;
+        }
+    }
+
+    /// Whether the block is staged or in the store
+    pub fn has_block(&mut self, cid: &Cid) -> Result<bool> {
+        if self.staged.contains_key(cid) {
+            return Ok(true);
+        }
+        self.store.has_block(cid)
+    }
+
+    /// Get a staged block, or the block from the store
+    pub fn get_block(&mut self, cid: &Cid) -> Result<Option<Vec<u8>>> {
+        if let Some(block) = self.staged.get(cid) {
+            return Ok(Some(block.data().to_vec()));
+        }
+        self.store.get_block(cid)
+    }
+
+    /// The cids below `root` that are neither staged nor in the store, in no particular order
+    ///
+    /// This is empty once the staged blocks complete the dag, e.g. for checking an incoming dag
+    /// before committing it.
+    pub fn missing_blocks(&mut self, root: &Cid) -> Result<Vec<Cid>> {
+        let mut missing = FnvHashSet::default();
+        let mut visited = FnvHashSet::default();
+        let mut pending = vec![*root];
+        while let Some(cid) = pending.pop() {
+            if !visited.insert(cid) {
+                continue;
+            }
+            if let Some(block) = self.staged.get(&cid) {
+                block.references(&mut pending)?;
+                continue;
+            }
+            // the store knows the links of its blocks, and which ones it does not have
+            for cid in self.store.get_missing_blocks::<Vec<_>>(&cid)? {
+                if self.staged.contains_key(&cid) {
+                    pending.push(cid);
+                } else {
+                    missing.insert(cid);
+                }
+            }
+        }
+        Ok(missing.into_iter().collect())
+    }
+
+    /// Write the staged blocks in a single transaction
+    ///
+    /// The blocks are not pinned, see [`commit_with_alias`](Self::commit_with_alias).
+    pub fn commit(self) -> Result<()> {
+        self.write(None)
+    }
+
+    /// Write the staged blocks and set the alias `name` to `root` in a single transaction
+    pub fn commit_with_alias(self, name: impl AsRef<[u8]>, root: &Cid) -> Result<()> {
+        self.write(Some((name.as_ref(), root)))
+    }
+
+    fn write(self, alias: Option<(&[u8], &Cid)>) -> Result<()> {
+        let mut txn = self.store.write_transaction()?;
+        for block in self.staged.into_values() {
+            txn.put_block(block)?;
+        }
+        if let Some((name, root)) = alias {
+            txn.alias(name, Some(root))?;
+        }
+        txn.commit()
+    }
+}
@@ -0,0 +1,170 @@
+//! Checks of the sqlite features the store relies on, see [`self_test`]
+use crate::{error::Context, Result};
+use rusqlite::{blob::ZeroBlob, Connection, DatabaseName, ErrorCode, TransactionBehavior};
+use std::{
+    fmt,
+    io::{Read, Write},
+    path::Path,
+    time::{Duration, Instant},
+};
+
+/// The outcome of [`self_test`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SelfTestReport {
+    sqlite_version: String,
+    wal: bool,
+    recursive_cte: bool,
+    foreign_keys: bool,
+    blob_io: bool,
+    busy_timeout: bool,
+}
+
+impl SelfTestReport {
+    /// Version of the sqlite library in use
+    pub fn sqlite_version(&self) -> &str {
+        &self.sqlite_version
+    }
+
+    /// Whether the database can be switched to WAL mode
+    pub fn wal(&self) -> bool {
+        self.wal
+    }
+
+    /// Whether recursive common table expressions work, which are used for all dag traversals
+    pub fn recursive_cte(&self) -> bool {
+        self.recursive_cte
+    }
+
+    /// Whether foreign keys are enforced and cascading deletes work
+    pub fn foreign_keys(&self) -> bool {
+        self.foreign_keys
+    }
+
+    /// Whether large blobs are written and read back unchanged in chunks, as block readers do
+    pub fn blob_io(&self) -> bool {
+        self.blob_io
+    }
+
+    /// Whether a writer waits for the busy timeout and then fails with a busy error
+    pub fn busy_timeout(&self) -> bool {
+        self.busy_timeout
+    }
+
+    /// Whether all checks passed
+    pub fn is_ok(&self) -> bool {
+        self.wal && self.recursive_cte && self.foreign_keys && self.blob_io && self.busy_timeout
+    }
+}
+
+/// Exercise the sqlite features the store depends on against the sqlite library in use
+///
+/// This is meant to be run on unusual platforms before trusting the store with data. A scratch
+/// database is created in `dir`, replacing any left over from an earlier run, and removed
+/// afterwards. Failing checks are reported as `false` in the result; an error is only returned
+/// if the scratch database cannot be created.
+pub fn self_test(dir: impl AsRef<Path>) -> Result<SelfTestReport> {
+    let path = dir.as_ref().join("ipfs-sqlite-block-store-self-test");
+    // left over from an interrupted run, which would make the checks fail
+    remove(&path);
+    let res = run(&path);
+    remove(&path);
+    res
+}
+
+fn remove(path: &Path) {
+    for suffix in ["", "-wal", "-shm"] {
+        let mut name = path.as_os_str().to_owned();
+        name.push(suffix);
+        std::fs::remove_file(name).ok();
+    }
+}
+
+fn run(path: &Path) -> Result<SelfTestReport> {
+    let conn = Connection::open(path).ctx("opening self test DB")?;
+    Ok(SelfTestReport {
+        sqlite_version: rusqlite::version().to_owned(),
+        wal: check("WAL", wal(&conn)),
+        recursive_cte: check("recursive CTE", recursive_cte(&conn)),
+        foreign_keys: check("foreign keys", foreign_keys(&conn)),
+        blob_io: check("blob I/O", blob_io(&conn)),
+        busy_timeout: check("busy timeout", busy_timeout(conn, path)),
+    })
+}
+
+fn check<E: fmt::Display>(name: &str, res: std::result::Result<bool, E>) -> bool {
+    match res {
+        Ok(ok) => {
+            if !ok {
+                tracing::warn!("self test of {} failed", name);
+            }
+            ok
+        }
+        Err(e) => {
+            tracing::warn!("self test of {} failed: {}", name, e);
+            false
+        }
+    }
+}
+
+fn wal(conn: &Connection) -> rusqlite::Result<bool> {
+    let mode: String = conn.query_row("PRAGMA journal_mode = WAL", [], |row| row.get(0))?;
+    Ok(mode.eq_ignore_ascii_case("wal"))
+}
+
+fn recursive_cte(conn: &Connection) -> rusqlite::Result<bool> {
+    let sum: i64 = conn.query_row(
+        "WITH RECURSIVE r(x) AS (SELECT 1 UNION SELECT x + 1 FROM r WHERE x < 100) \
+        SELECT SUM(x) FROM r",
+        [],
+        |row| row.get(0),
+    )?;
+    Ok(sum == 5050)
+}
+
+fn foreign_keys(conn: &Connection) -> rusqlite::Result<bool> {
+    conn.execute_batch(
+        "PRAGMA foreign_keys = ON; \
+        CREATE TABLE parent (id INTEGER PRIMARY KEY); \
+        CREATE TABLE child (id INTEGER NOT NULL REFERENCES parent(id) ON DELETE CASCADE); \
+        INSERT INTO parent VALUES (1); \
+        INSERT INTO child VALUES (1);",
+    )?;
+    let rejected = conn.execute("INSERT INTO child VALUES (2)", []).is_err();
+    conn.execute("DELETE FROM parent", [])?;
+    let children: i64 = conn.query_row("SELECT COUNT(*) FROM child", [], |row| row.get(0))?;
+    Ok(rejected && children == 0)
+}
+
+fn blob_io(conn: &Connection) -> anyhow::Result<bool> {
+    // larger than a page, so that overflow pages are used
+    let data = (0..1 << 20).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+    conn.execute_batch("CREATE TABLE blobs (data BLOB NOT NULL)")?;
+    conn.execute(
+        "INSERT INTO blobs VALUES (?)",
+        [ZeroBlob(data.len() as i32)],
+    )?;
+    let rowid = conn.last_insert_rowid();
+    // written and read in chunks through the incremental blob API, like block readers do
+    let mut blob = conn.blob_open(DatabaseName::Main, "blobs", "data", rowid, false)?;
+    for chunk in data.chunks(10_000) {
+        blob.write_all(chunk)?;
+    }
+    drop(blob);
+    let mut blob = conn.blob_open(DatabaseName::Main, "blobs", "data", rowid, true)?;
+    let mut read = Vec::new();
+    blob.read_to_end(&mut read)?;
+    Ok(read == data)
+}
+
+fn busy_timeout(mut conn: Connection, path: &Path) -> rusqlite::Result<bool> {
+    let timeout = Duration::from_millis(100);
+    let mut other = Connection::open(path)?;
+    other.busy_timeout(timeout)?;
+    let _lock = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    let t0 = Instant::now();
+    let res = other.transaction_with_behavior(TransactionBehavior::Immediate);
+    let busy = matches!(
+        res, Err(rusqlite::Error::SqliteFailure(e, _)) if e.code == ErrorCode::DatabaseBusy
+    );
+    Ok(busy && t0.elapsed() >= timeout)
+}
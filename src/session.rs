@@ -0,0 +1,110 @@
+//! Liveness of the sessions owning temp pins, see
+//! [`BlockStore::clear_stale_temp_pins`](crate::BlockStore::clear_stale_temp_pins)
+//!
+//! Every process writing to a store holds an exclusive lock on a small file next to the
+//! database for as long as it has the store open. A session whose lock can be taken belongs to
+//! a process that went away, so its temp pins can be removed. The locks are sqlite's own file
+//! locks, so they are released by the OS when a process dies, and are seen by the other
+//! connections of the same process as well.
+use crate::{error::Context, Result};
+use parking_lot::Mutex;
+use rusqlite::{Connection, ErrorCode, OpenFlags};
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+/// the lock held by a live session, released when dropped
+pub(crate) struct SessionLock {
+    conn: Mutex<Connection>,
+    path: PathBuf,
+}
+
+impl SessionLock {
+    /// take the lock for `session` of the store at `db`
+    pub(crate) fn acquire(db: &Path, session: i64) -> Result<Self> {
+        let path = lock_path(db, session);
+        let conn = Connection::open(&path).ctx("opening session lock")?;
+        // in rollback journal mode this takes the exclusive lock right away, and nothing is
+        // ever written, so there is no need for a journal
+        conn.execute_batch("PRAGMA journal_mode = OFF; BEGIN EXCLUSIVE")
+            .ctx("taking session lock")?;
+        Ok(Self {
+            conn: Mutex::new(conn),
+            path,
+        })
+    }
+}
+
+impl Drop for SessionLock {
+    fn drop(&mut self) {
+        // the lock file is only removed while still holding the lock, so a concurrent
+        // `is_live` either sees it locked or not at all
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            tracing::debug!("cannot remove session lock {}: {}", self.path.display(), e);
+        }
+        self.conn.lock().execute_batch("ROLLBACK").ok();
+    }
+}
+
+/// whether the process owning `session` of the store at `db` is still running
+///
+/// A session without a lock file is not live, which includes those of older versions.
+pub(crate) fn is_live(db: &Path, session: i64) -> Result<bool> {
+    let path = lock_path(db, session);
+    let conn = match Connection::open_with_flags(&path, OpenFlags::SQLITE_OPEN_READ_WRITE) {
+        Ok(conn) => conn,
+        Err(rusqlite::Error::SqliteFailure(e, _)) if e.code == ErrorCode::CannotOpen => {
+            return Ok(false)
+        }
+        Err(e) => return Err(e).ctx("opening session lock"),
+    };
+    conn.busy_timeout(std::time::Duration::ZERO)
+        .ctx("setting busy timeout")?;
+    match conn.execute_batch("PRAGMA journal_mode = OFF; BEGIN IMMEDIATE; ROLLBACK") {
+        Ok(()) => {
+            drop(conn);
+            match std::fs::remove_file(&path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                    tracing::debug!("cannot remove session lock {}: {}", path.display(), e);
+                }
+                _ => {}
+            }
+            Ok(false)
+        }
+        Err(rusqlite::Error::SqliteFailure(e, _)) if e.code == ErrorCode::DatabaseBusy => Ok(true),
+        Err(e) => Err(e).ctx("checking session lock"),
+    }
+}
+
+/// the sessions of the store at `db` that have a lock file, live or not
+pub(crate) fn lock_files(db: &Path) -> Result<Vec<i64>> {
+    let prefix = match db.file_name().and_then(|name| name.to_str()) {
+        Some(name) => format!("{}-session-", name),
+        None => return Ok(Vec::new()),
+    };
+    let dir = match db.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let entries = std::fs::read_dir(dir)
+        .map_err(|e| anyhow::Error::new(e).context("listing session locks"))?;
+    let mut sessions = Vec::new();
+    for entry in entries.flatten() {
+        let name = entry.file_name();
+        let session = name
+            .to_str()
+            .and_then(|name| name.strip_prefix(&prefix))
+            .and_then(|hex| u64::from_str_radix(hex, 16).ok());
+        if let Some(session) = session {
+            sessions.push(session as i64);
+        }
+    }
+    Ok(sessions)
+}
+
+fn lock_path(db: &Path, session: i64) -> PathBuf {
+    let mut name = db.as_os_str().to_owned();
+    name.push(format!("-session-{:016x}", session as u64));
+    name.into()
+}
@@ -0,0 +1,98 @@
+use crate::{BlockStore, Result, TempPin};
+use libipld::{codec::References, store::StoreParams, Block, Cid, Ipld};
+use parking_lot::Mutex;
+use std::fmt;
+
+/// A block store that can be shared between threads, e.g. behind an `Arc`
+///
+/// All writes go through a single connection guarded by a mutex, since sqlite only allows one
+/// writer at a time anyway. Reads use a pool of [additional
+/// connections](BlockStore::additional_connection), so they neither wait for writes nor for
+/// each other. For in-memory stores there is only one connection, so reads take the writer
+/// lock as well.
+pub struct SharedBlockStore<S> {
+    writer: Mutex<BlockStore<S>>,
+    readers: Option<Mutex<Vec<BlockStore<S>>>>,
+}
+
+impl<S> fmt::Debug for SharedBlockStore<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SharedBlockStore").finish()
+    }
+}
+
+impl<S> SharedBlockStore<S>
+where
+    S: StoreParams,
+    Ipld: References<S::Codecs>,
+{
+    /// Share `store`, which becomes the writer connection
+    pub fn new(store: BlockStore<S>) -> Self {
+        let readers = if store.db_path.is_memory() {
+            None
+        } else {
+            Some(Mutex::new(Vec::new()))
+        };
+        Self {
+            writer: Mutex::new(store),
+            readers,
+        }
+    }
+
+    /// Run `f` with exclusive access to the writer connection
+    pub fn write<T>(&self, f: impl FnOnce(&mut BlockStore<S>) -> T) -> T {
+        f(&mut self.writer.lock())
+    }
+
+    /// Run `f` on a connection that is not used by any other thread meanwhile
+    ///
+    /// `f` should only read, since writes on reader connections compete with the writer for
+    /// the lock and may fail with [`Busy`](crate::BlockStoreError::Busy).
+    pub fn read<T>(&self, f: impl FnOnce(&mut BlockStore<S>) -> Result<T>) -> Result<T> {
+        let readers = match &self.readers {
+            Some(readers) => readers,
+            None => return f(&mut self.writer.lock()),
+        };
+        let reader = readers.lock().pop();
+        let mut reader = match reader {
+            Some(reader) => reader,
+            None => self.writer.lock().additional_connection()?,
+        };
+        let res = f(&mut reader);
+        readers.lock().push(reader);
+        res
+    }
+
+    /// Get a temporary pin for safely adding blocks to the store
+    pub fn temp_pin(&self) -> TempPin {
+        self.writer.lock().temp_pin()
+    }
+
+    /// Get data for a block, see [`BlockStore::get_block`]
+    pub fn get_block(&self, cid: &Cid) -> Result<Option<Vec<u8>>> {
+        self.read(|store| store.get_block(cid))
+    }
+
+    /// Check whether the data for a block is present, see [`BlockStore::has_block`]
+    pub fn has_block(&self, cid: &Cid) -> Result<bool> {
+        self.read(|store| store.has_block(cid))
+    }
+
+    /// Put a block, see [`BlockStore::put_block`]
+    pub fn put_block(&self, block: Block<S>, pin: Option<&mut TempPin>) -> Result<()> {
+        self.write(|store| store.put_block(block, pin))
+    }
+
+    /// Put several blocks in a single transaction, see [`BlockStore::put_blocks`]
+    pub fn put_blocks<I>(&self, blocks: I, pin: Option<&mut TempPin>) -> Result<()>
+    where
+        I: IntoIterator<Item = Block<S>>,
+    {
+        self.write(|store| store.put_blocks(blocks, pin))
+    }
+
+    /// Close the reader connections and return the writer
+    pub fn into_inner(self) -> BlockStore<S> {
+        self.writer.into_inner()
+    }
+}
@@ -0,0 +1,140 @@
+use crate::{cidbytes::CidBytes, db::*, error::Context, BlockStore, Result, StoreStats};
+use libipld::{cid, Cid};
+use std::{collections::VecDeque, convert::TryFrom, fmt, iter::FromIterator};
+
+/// A read-only view of the store as of the moment it was taken, see
+/// [`snapshot`](BlockStore::snapshot)
+///
+/// This keeps a read transaction open on the connection it was taken from, so writes by other
+/// connections are not visible through it. Meanwhile the WAL cannot be checkpointed past the
+/// snapshot, so it should not be kept for longer than needed.
+pub struct Snapshot<'a> {
+    txn: rusqlite::Transaction<'a>,
+}
+
+impl<'a> fmt::Debug for Snapshot<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Snapshot").finish()
+    }
+}
+
+impl<'a> Snapshot<'a> {
+    pub(crate) fn new<S>(owner: &'a mut BlockStore<S>) -> Result<Self> {
+        let txn = owner.conn.transaction().ctx("beginning snapshot")?;
+        // a deferred transaction only takes its snapshot with the first read
+        get_store_stats(&txn)?;
+        Ok(Self { txn })
+    }
+
+    /// Get the block count and size as of the snapshot
+    pub fn get_store_stats(&self) -> Result<StoreStats> {
+        get_store_stats(&self.txn)
+    }
+
+    /// Check whether the data for a block was present
+    pub fn has_block(&self, cid: &Cid) -> Result<bool> {
+        has_block(&self.txn, CidBytes::try_from(cid)?)
+    }
+
+    /// Get a block
+    pub fn get_block(&self, cid: &Cid) -> Result<Option<Vec<u8>>> {
+        Ok(get_block(&self.txn, CidBytes::try_from(cid)?)?.map(|(_id, data)| data))
+    }
+
+    /// Resolve an alias
+    pub fn resolve(&self, name: &[u8]) -> Result<Option<Cid>> {
+        Ok(resolve::<CidBytes>(&self.txn, name)?
+            .map(|c| Cid::try_from(&c))
+            .transpose()?)
+    }
+
+    /// Get all aliases and their targets
+    pub fn aliases<C: FromIterator<(Vec<u8>, Cid)>>(&self) -> Result<C> {
+        let res: Vec<(Vec<u8>, CidBytes)> = aliases(&self.txn)?;
+        Ok(res
+            .into_iter()
+            .map(|(alias, cid)| Ok((alias, Cid::try_from(&cid)?)))
+            .collect::<cid::Result<C>>()?)
+    }
+
+    /// Get the cids of all blocks
+    pub fn get_block_cids<C: FromIterator<Cid>>(&self) -> Result<C> {
+        let res = get_block_cids::<CidBytes>(&self.txn)?;
+        Ok(res.iter().map(Cid::try_from).collect::<cid::Result<C>>()?)
+    }
+
+    /// Get the descendants of a cid
+    pub fn get_descendants<C: FromIterator<Cid>>(&self, cid: &Cid) -> Result<C> {
+        let res = get_descendants(&self.txn, CidBytes::try_from(cid)?)?;
+        Ok(res.iter().map(Cid::try_from).collect::<cid::Result<C>>()?)
+    }
+
+    /// Iterate over the cids and data of all blocks in the snapshot
+    ///
+    /// Blocks are read in batches, so memory use stays bounded even for huge stores. The order
+    /// is unspecified.
+    pub fn iter_blocks(&self) -> SnapshotBlocks<'_, 'a> {
+        SnapshotBlocks {
+            snapshot: self,
+            after: 0,
+            batch: VecDeque::new(),
+            done: false,
+        }
+    }
+}
+
+/// An iterator over the blocks of a [`Snapshot`]
+pub struct SnapshotBlocks<'s, 'a> {
+    snapshot: &'s Snapshot<'a>,
+    after: i64,
+    batch: VecDeque<(Cid, Vec<u8>)>,
+    done: bool,
+}
+
+impl<'s, 'a> fmt::Debug for SnapshotBlocks<'s, 'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SnapshotBlocks")
+            .field("after", &self.after)
+            .field("done", &self.done)
+            .finish()
+    }
+}
+
+impl<'s, 'a> Iterator for SnapshotBlocks<'s, 'a> {
+    type Item = Result<(Cid, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        /// blocks in one batch, unless they are larger than `BATCH_BYTES` in total
+        const BATCH_BLOCKS: usize = 1000;
+        const BATCH_BYTES: usize = 16 << 20;
+        if self.batch.is_empty() && !self.done {
+            let res = get_blocks_page::<CidBytes>(
+                &self.snapshot.txn,
+                self.after,
+                BATCH_BLOCKS,
+                BATCH_BYTES,
+            )
+            .and_then(|blocks| {
+                blocks
+                    .into_iter()
+                    .map(|(id, cid, data)| Ok((id, Cid::try_from(&cid)?, data)))
+                    .collect::<Result<Vec<_>>>()
+            });
+            match res {
+                Ok(blocks) => {
+                    self.done = blocks.is_empty();
+                    if let Some((id, _, _)) = blocks.last() {
+                        self.after = *id;
+                    }
+                    self.batch
+                        .extend(blocks.into_iter().map(|(_, cid, data)| (cid, data)));
+                }
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+        self.batch.pop_front().map(Ok)
+    }
+}
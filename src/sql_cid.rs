@@ -0,0 +1,90 @@
+use libipld::Cid;
+use rusqlite::{
+    types::{FromSql, FromSqlError, FromSqlResult, ToSqlOutput, ValueRef},
+    ToSql,
+};
+use std::{convert::TryFrom, fmt, ops::Deref};
+
+/// The maximum length of a cid stored as a [`SqlCid`], in its binary form
+pub const MAX_SQL_CID_SIZE: usize = 64;
+
+/// A cid that can be used as a rusqlite parameter and column value, as a blob of its binary form
+///
+/// This is meant for tables of an application that refer to blocks in the store, e.g. in a
+/// database of its own. Cids longer than [`MAX_SQL_CID_SIZE`] bytes are rejected in both
+/// directions. The store itself does not use this type and accepts longer cids, as long as
+/// their digest fits into 64 bytes.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct SqlCid(pub Cid);
+
+impl fmt::Debug for SqlCid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&self.0, f)
+    }
+}
+
+impl fmt::Display for SqlCid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
+
+impl From<Cid> for SqlCid {
+    fn from(cid: Cid) -> Self {
+        Self(cid)
+    }
+}
+
+impl From<SqlCid> for Cid {
+    fn from(cid: SqlCid) -> Self {
+        cid.0
+    }
+}
+
+impl Deref for SqlCid {
+    type Target = Cid;
+
+    fn deref(&self) -> &Cid {
+        &self.0
+    }
+}
+
+/// a cid whose binary form exceeds [`MAX_SQL_CID_SIZE`]
+#[derive(Debug)]
+struct TooLong(usize);
+
+impl fmt::Display for TooLong {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "cid of {} bytes exceeds {} bytes",
+            self.0, MAX_SQL_CID_SIZE
+        )
+    }
+}
+
+impl std::error::Error for TooLong {}
+
+impl ToSql for SqlCid {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        let bytes = self.0.to_bytes();
+        if bytes.len() > MAX_SQL_CID_SIZE {
+            return Err(rusqlite::Error::ToSqlConversionFailure(Box::new(TooLong(
+                bytes.len(),
+            ))));
+        }
+        Ok(ToSqlOutput::from(bytes))
+    }
+}
+
+impl FromSql for SqlCid {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        let bytes = value.as_blob()?;
+        if bytes.len() > MAX_SQL_CID_SIZE {
+            return Err(FromSqlError::Other(Box::new(TooLong(bytes.len()))));
+        }
+        Cid::try_from(bytes)
+            .map(Self)
+            .map_err(|e| FromSqlError::Other(Box::new(e)))
+    }
+}
@@ -0,0 +1,283 @@
+//! Warm standby copies, see [`BlockStore::update_standby`](crate::BlockStore::update_standby)
+//!
+//! Only the pages that differ from the standby are written. To keep the standby consistent when
+//! an update is interrupted, the previous content of these pages is saved to a journal next to
+//! it first, which is rolled back by the next update or replay.
+use crate::{error::Context, Result};
+use rusqlite::{ffi, Connection};
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    marker::PhantomData,
+    os::raw::{c_int, c_void},
+    path::{Path, PathBuf},
+    ptr,
+};
+
+/// the first bytes of a standby journal
+const JOURNAL_MAGIC: [u8; 8] = *b"sbjrnl01";
+
+/// Write the pages of the main database of `conn` that differ from those of the standby at
+/// `path`, returning their number
+///
+/// This must be called within a read transaction that does not use the WAL, so that the
+/// database file does not change while it is read.
+pub(crate) fn ship(conn: &Connection, path: &Path) -> Result<u64> {
+    let page_size: u64 = conn
+        .query_row("PRAGMA page_size", [], |row| row.get(0))
+        .ctx("getting page size")?;
+    let primary = MainFile::new(conn)?;
+    let size = primary.size()?;
+    let mut standby = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .map_err(|e| io_error(e, "opening standby"))?;
+    let old_size = standby
+        .metadata()
+        .map_err(|e| io_error(e, "getting standby size"))?
+        .len();
+    let mut page = vec![0u8; page_size as usize];
+    let mut old = vec![0u8; page_size as usize];
+    let mut changed = Vec::new();
+    for offset in (0..size).step_by(page_size as usize) {
+        primary.read(&mut page, offset)?;
+        if offset + page_size <= old_size {
+            standby
+                .seek(SeekFrom::Start(offset))
+                .and_then(|_| standby.read_exact(&mut old))
+                .map_err(|e| io_error(e, "reading standby"))?;
+            if old == page {
+                continue;
+            }
+        }
+        changed.push(offset);
+    }
+    if changed.is_empty() && old_size == size {
+        return Ok(0);
+    }
+    write_journal(path, &mut standby, old_size, &changed, page_size)?;
+    for offset in &changed {
+        primary.read(&mut page, *offset)?;
+        standby
+            .seek(SeekFrom::Start(*offset))
+            .and_then(|_| standby.write_all(&page))
+            .map_err(|e| io_error(e, "writing standby"))?;
+    }
+    standby
+        .set_len(size)
+        .and_then(|_| standby.sync_all())
+        .map_err(|e| io_error(e, "syncing standby"))?;
+    remove_journal(path)?;
+    Ok(changed.len() as u64)
+}
+
+/// Roll back an interrupted update of the standby at `path`, if there is one
+pub(crate) fn recover(path: &Path) -> Result<()> {
+    let journal_file = journal_path(path);
+    let mut journal = match File::open(&journal_file) {
+        Ok(file) => io::BufReader::new(file),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(io_error(e, "opening standby journal")),
+    };
+    tracing::info!(
+        "rolling back interrupted standby update of {}",
+        path.display()
+    );
+    let mut magic = [0u8; 8];
+    journal
+        .read_exact(&mut magic)
+        .map_err(|e| io_error(e, "reading standby journal"))?;
+    if magic != JOURNAL_MAGIC {
+        return Err(anyhow::anyhow!("{} is not a standby journal", journal_file.display()).into());
+    }
+    let old_size = read_u64(&mut journal)?;
+    let mut standby = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .map_err(|e| io_error(e, "opening standby"))?;
+    let mut data = Vec::new();
+    loop {
+        let offset = match read_u64(&mut journal) {
+            Ok(offset) => offset,
+            // the journal is only renamed into place once complete, so this is its end
+            Err(_) => break,
+        };
+        let len = read_u64(&mut journal)?;
+        data.resize(len as usize, 0);
+        journal
+            .read_exact(&mut data)
+            .map_err(|e| io_error(e, "reading standby journal"))?;
+        standby
+            .seek(SeekFrom::Start(offset))
+            .and_then(|_| standby.write_all(&data))
+            .map_err(|e| io_error(e, "restoring standby"))?;
+    }
+    standby
+        .set_len(old_size)
+        .and_then(|_| standby.sync_all())
+        .map_err(|e| io_error(e, "syncing standby"))?;
+    remove_journal(path)
+}
+
+/// Copy the standby at `standby` to `path`, replacing the database there
+pub(crate) fn replay(standby: &Path, path: &Path) -> Result<()> {
+    recover(standby)?;
+    let tmp = sibling(path, "replay-tmp");
+    std::fs::copy(standby, &tmp).map_err(|e| io_error(e, "copying standby"))?;
+    File::open(&tmp)
+        .and_then(|file| file.sync_all())
+        .map_err(|e| io_error(e, "syncing standby copy"))?;
+    for suffix in ["wal", "shm"] {
+        match std::fs::remove_file(sibling(path, suffix)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                return Err(io_error(e, "removing stale WAL"))
+            }
+            _ => {}
+        }
+    }
+    std::fs::rename(&tmp, path).map_err(|e| io_error(e, "moving standby into place"))?;
+    sync_dir(path).map_err(|e| io_error(e, "syncing directory"))
+}
+
+/// save the current content of the `changed` pages of the standby
+///
+/// The journal is written under a temporary name and only renamed once it is complete and
+/// synced, so a journal that exists can always be rolled back.
+fn write_journal(
+    path: &Path,
+    standby: &mut File,
+    old_size: u64,
+    changed: &[u64],
+    page_size: u64,
+) -> Result<()> {
+    let tmp = sibling(path, "standby-journal-tmp");
+    let file = File::create(&tmp).map_err(|e| io_error(e, "creating standby journal"))?;
+    let mut journal = io::BufWriter::new(file);
+    let mut write = || -> io::Result<()> {
+        journal.write_all(&JOURNAL_MAGIC)?;
+        journal.write_all(&old_size.to_le_bytes())?;
+        let mut data = Vec::new();
+        for offset in changed.iter().copied().filter(|offset| *offset < old_size) {
+            data.resize(page_size.min(old_size - offset) as usize, 0);
+            standby.seek(SeekFrom::Start(offset))?;
+            standby.read_exact(&mut data)?;
+            journal.write_all(&offset.to_le_bytes())?;
+            journal.write_all(&(data.len() as u64).to_le_bytes())?;
+            journal.write_all(&data)?;
+        }
+        journal.flush()?;
+        journal.get_ref().sync_all()
+    };
+    write().map_err(|e| io_error(e, "writing standby journal"))?;
+    std::fs::rename(&tmp, journal_path(path))
+        .and_then(|_| sync_dir(path))
+        .map_err(|e| io_error(e, "moving standby journal into place"))
+}
+
+fn remove_journal(path: &Path) -> Result<()> {
+    std::fs::remove_file(journal_path(path))
+        .and_then(|_| sync_dir(path))
+        .map_err(|e| io_error(e, "removing standby journal"))
+}
+
+fn journal_path(path: &Path) -> PathBuf {
+    sibling(path, "standby-journal")
+}
+
+fn sibling(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push("-");
+    name.push(suffix);
+    name.into()
+}
+
+/// make a rename or removal in the directory of `path` durable
+#[cfg(unix)]
+fn sync_dir(path: &Path) -> io::Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+fn read_u64(r: &mut impl Read) -> Result<u64> {
+    let mut buf = [0u8; 8];
+    r.read_exact(&mut buf)
+        .map_err(|e| io_error(e, "reading standby journal"))?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+fn io_error(e: io::Error, msg: &'static str) -> crate::BlockStoreError {
+    anyhow::Error::new(e).context(msg).into()
+}
+
+/// the file of the main database of a connection, read through the handle of sqlite
+///
+/// Opening the file separately would not do, since closing that handle drops the posix locks
+/// that sqlite holds on the same file.
+struct MainFile<'a>(*mut ffi::sqlite3_file, PhantomData<&'a Connection>);
+
+impl<'a> MainFile<'a> {
+    fn new(conn: &'a Connection) -> Result<Self> {
+        let mut file: *mut ffi::sqlite3_file = ptr::null_mut();
+        // SAFETY: the handle is valid while `conn` is alive, and the file pointer stays valid as
+        // long as the database is attached, which is for the lifetime of `conn`
+        let rc = unsafe {
+            ffi::sqlite3_file_control(
+                conn.handle(),
+                b"main\0".as_ptr() as *const _,
+                ffi::SQLITE_FCNTL_FILE_POINTER,
+                &mut file as *mut *mut ffi::sqlite3_file as *mut c_void,
+            )
+        };
+        // in-memory databases have no file, or one without methods
+        if rc != ffi::SQLITE_OK || file.is_null() || unsafe { (*file).pMethods.is_null() } {
+            return Err(anyhow::anyhow!("the store has no database file").into());
+        }
+        Ok(Self(file, PhantomData))
+    }
+
+    fn size(&self) -> Result<u64> {
+        let mut size = 0i64;
+        // SAFETY: the file is open, see `new`
+        let rc = unsafe {
+            let size_fn = (*(*self.0).pMethods).xFileSize.expect("files have a size");
+            size_fn(self.0, &mut size)
+        };
+        check(rc, "getting database file size")?;
+        Ok(size as u64)
+    }
+
+    fn read(&self, buf: &mut [u8], offset: u64) -> Result<()> {
+        // SAFETY: the file is open, see `new`, and `buf` is valid for writes of its length
+        let rc = unsafe {
+            let read_fn = (*(*self.0).pMethods).xRead.expect("files can be read");
+            read_fn(
+                self.0,
+                buf.as_mut_ptr() as *mut c_void,
+                buf.len() as c_int,
+                offset as i64,
+            )
+        };
+        check(rc, "reading database file")
+    }
+}
+
+fn check(rc: c_int, msg: &'static str) -> Result<()> {
+    if rc == ffi::SQLITE_OK {
+        Ok(())
+    } else {
+        Err(rusqlite::Error::SqliteFailure(ffi::Error::new(rc), None)).ctx(msg)
+    }
+}
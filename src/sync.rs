@@ -0,0 +1,89 @@
+use crate::{BlockStore, Result, TempPin};
+use fnv::FnvHashSet;
+use libipld::{codec::References, store::StoreParams, Block, Cid, Ipld};
+
+/// the maximum number of blocks copied in one transaction by [`BlockStore::sync_from`]
+const SYNC_BATCH: usize = 1000;
+
+/// The blocks present in only one of two stores, see [`diff`](BlockStore::diff)
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StoreDiff {
+    pub(crate) only_in_self: Vec<Cid>,
+    pub(crate) only_in_other: Vec<Cid>,
+}
+
+impl StoreDiff {
+    /// Cids of the blocks that only the store `diff` was called on has data for, sorted
+    pub fn only_in_self(&self) -> &[Cid] {
+        &self.only_in_self
+    }
+
+    /// Cids of the blocks that only the other store has data for, sorted
+    pub fn only_in_other(&self) -> &[Cid] {
+        &self.only_in_other
+    }
+
+    /// Whether both stores have data for the same blocks
+    pub fn is_empty(&self) -> bool {
+        self.only_in_self.is_empty() && self.only_in_other.is_empty()
+    }
+}
+
+impl<S> BlockStore<S>
+where
+    S: StoreParams,
+    Ipld: References<S::Codecs>,
+{
+    /// Compare the blocks of this store with those of `other`
+    ///
+    /// Only the presence of block data is compared, not aliases or temp pins. Both sets of
+    /// cids are loaded into memory, so this is meant for migration and backup checks rather
+    /// than for frequent use on huge stores.
+    pub fn diff(&mut self, other: &mut BlockStore<S>) -> Result<StoreDiff> {
+        let ours = self.get_block_cids::<FnvHashSet<Cid>>()?;
+        let theirs = other.get_block_cids::<FnvHashSet<Cid>>()?;
+        let mut only_in_self = ours.difference(&theirs).copied().collect::<Vec<_>>();
+        let mut only_in_other = theirs.difference(&ours).copied().collect::<Vec<_>>();
+        only_in_self.sort_unstable();
+        only_in_other.sort_unstable();
+        Ok(StoreDiff {
+            only_in_self,
+            only_in_other,
+        })
+    }
+
+    /// Copy the blocks reachable from `roots` in `other` that are missing here
+    ///
+    /// Blocks that `other` does not have either are skipped, so the copied dags are only as
+    /// complete as they are in `other`. The blocks are written in batches, each in its own
+    /// transaction; pass a temp pin to keep gc from collecting them before they are aliased.
+    /// Returns the number of blocks copied.
+    pub fn sync_from(
+        &mut self,
+        other: &mut BlockStore<S>,
+        roots: &[Cid],
+        mut pin: Option<&mut TempPin>,
+    ) -> Result<u64> {
+        let mut seen = FnvHashSet::default();
+        let mut wanted = Vec::new();
+        for root in roots {
+            for cid in other.get_descendants::<Vec<Cid>>(root)? {
+                if seen.insert(cid) && !self.has_block(&cid)? {
+                    wanted.push(cid);
+                }
+            }
+        }
+        let mut copied = 0;
+        for chunk in wanted.chunks(SYNC_BATCH) {
+            let mut blocks = Vec::with_capacity(chunk.len());
+            for cid in chunk {
+                if let Some(data) = other.get_block(cid)? {
+                    blocks.push(Block::<S>::new_unchecked(*cid, data));
+                }
+            }
+            copied += blocks.len() as u64;
+            self.put_blocks(blocks, pin.as_deref_mut())?;
+        }
+        Ok(copied)
+    }
+}
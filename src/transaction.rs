@@ -1,21 +1,48 @@
 use crate::{
+    block_cache::BlockCache,
     cache::{BlockInfo, CacheTracker, WriteInfo},
     cidbytes::CidBytes,
+    counters::AtomicCounters,
     db::*,
-    Block, BlockStore, Result, StoreStats, TempPin,
+    error::Context,
+    events::Events,
+    missing_cache::MissingCache,
+    AliasInfo, Block, BlockStat, BlockStore, BlockStoreError, Change, CodecStats, DagStats,
+    Hashers, Limit, Limited, LinkDiff, LinkMode, Page, PinMode, Result, StoreEvent, StoreStats,
+    StoreSummary, TagStatsMap, TempPin, Traversal,
+};
+use fnv::{FnvHashMap, FnvHashSet};
+use libipld::{
+    cid,
+    codec::References,
+    error::{InvalidMultihash, UnsupportedMultihash},
+    multihash::{Multihash, MultihashDigest},
+    store::StoreParams,
+    Cid, Ipld,
 };
-use fnv::FnvHashSet;
-use libipld::{cid, codec::References, store::StoreParams, Cid, Ipld};
 use parking_lot::Mutex;
 use std::{
-    borrow::Cow, collections::HashSet, convert::TryFrom, iter::FromIterator, marker::PhantomData,
-    mem, sync::Arc,
+    borrow::Cow,
+    cell::RefCell,
+    collections::{HashSet, VecDeque},
+    convert::TryFrom,
+    iter::FromIterator,
+    marker::PhantomData,
+    mem,
+    rc::Rc,
+    sync::Arc,
+    time::{Duration, UNIX_EPOCH},
 };
 
 pub struct Transaction<'a, S> {
     inner: &'a mut rusqlite::Connection,
     info: TransactionInfo,
     expired_temp_pins: Arc<Mutex<Vec<i64>>>,
+    verify_hashes: Option<Hashers>,
+    link_multiplicity: bool,
+    session: i64,
+    block_cache: BlockCache,
+    ids: IdCache,
     _s: PhantomData<S>,
 }
 
@@ -24,10 +51,51 @@ struct TransactionInfo {
     accessed: Vec<BlockInfo>,
     committed: bool,
     tracker: Arc<dyn CacheTracker>,
+    tag: Option<(String, TagStatsMap)>,
+    events: Events,
+    counters: Arc<AtomicCounters>,
+    // alias changes, reported together with the written blocks once committed
+    alias_events: Vec<StoreEvent>,
+    missing_cache: MissingCache,
 }
 
 impl Drop for TransactionInfo {
     fn drop(&mut self) {
+        if let Some((tag, tag_stats)) = self.tag.take() {
+            let mut tag_stats = tag_stats.lock();
+            let stats = tag_stats.entry(tag).or_default();
+            stats.blocks_read += self.accessed.len() as u64;
+            stats.bytes_read += self
+                .accessed
+                .iter()
+                .map(|b| b.block_len() as u64)
+                .sum::<u64>();
+            if self.committed {
+                stats.blocks_written += self.written.len() as u64;
+                stats.bytes_written += self
+                    .written
+                    .iter()
+                    .map(|b| b.block_len() as u64)
+                    .sum::<u64>();
+            }
+        }
+        AtomicCounters::add(&self.counters.block_reads, self.accessed.len() as u64);
+        if self.committed {
+            // plain transactions do this after every write already, write transactions only here
+            self.missing_cache
+                .remove(self.written.iter().map(|b| b.cid()));
+            let existing = self.written.iter().filter(|b| b.block_exists()).count();
+            let new = self.written.len() - existing;
+            AtomicCounters::add(&self.counters.block_writes, new as u64);
+            AtomicCounters::add(&self.counters.dedup_hits, existing as u64);
+            let added = self
+                .written
+                .iter()
+                .filter(|b| !b.block_exists())
+                .map(|b| StoreEvent::BlockAdded(*b.cid()));
+            let aliases = mem::take(&mut self.alias_events);
+            self.events.emit(added.chain(aliases));
+        }
         if !self.accessed.is_empty() {
             let blocks = mem::take(&mut self.accessed);
             self.tracker.blocks_accessed(blocks);
@@ -40,12 +108,94 @@ impl Drop for TransactionInfo {
     }
 }
 
+fn alias_event(name: &[u8], link: Option<&Cid>) -> StoreEvent {
+    match link {
+        Some(cid) => StoreEvent::AliasSet(name.to_vec(), *cid),
+        None => StoreEvent::AliasRemoved(name.to_vec()),
+    }
+}
+
+fn link_diff<S>(cid: Cid, data: Vec<u8>, stored: &[CidBytes]) -> Result<LinkDiff>
+where
+    S: StoreParams,
+    Ipld: References<S::Codecs>,
+{
+    let block = Block::<S>::new_unchecked(cid, data);
+    let mut decoded = FnvHashSet::default();
+    block.references(&mut decoded)?;
+    let stored = stored
+        .iter()
+        .map(Cid::try_from)
+        .collect::<cid::Result<FnvHashSet<_>>>()?;
+    let mut diff = LinkDiff {
+        missing: decoded.difference(&stored).copied().collect(),
+        unexpected: stored.difference(&decoded).copied().collect(),
+    };
+    diff.missing.sort();
+    diff.unexpected.sort();
+    Ok(diff)
+}
+
+/// count how often each link occurs, or just deduplicate them if `multiplicity` is off
+fn count_links(links: &[Cid], multiplicity: bool) -> Result<Vec<(CidBytes, u32)>> {
+    let mut counts = FnvHashMap::<CidBytes, u32>::default();
+    for link in links {
+        let count = counts.entry(CidBytes::try_from(link)?).or_default();
+        *count = if multiplicity {
+            count.saturating_add(1)
+        } else {
+            1
+        };
+    }
+    Ok(counts.into_iter().collect())
+}
+
+/// Verify the hash if configured and compute the links to store for a block
+fn prepare_block<S>(
+    block: &Block<S>,
+    verify_hashes: Option<&Hashers>,
+    link_multiplicity: bool,
+) -> Result<(CidBytes, Vec<(CidBytes, u32)>)>
+where
+    S: StoreParams,
+    Ipld: References<S::Codecs>,
+{
+    if let Some(hashers) = verify_hashes {
+        verify_hash::<S>(hashers, block.cid(), block.data())?;
+    }
+    let cid_bytes = CidBytes::try_from(block.cid())?;
+    let mut links = Vec::new();
+    block.references(&mut links)?;
+    Ok((cid_bytes, count_links(&links, link_multiplicity)?))
+}
+
+/// check the hash of the data against the cid, using the registered hashers or those of `S`
+pub(crate) fn verify_hash<S: StoreParams>(hashers: &Hashers, cid: &Cid, data: &[u8]) -> Result<()> {
+    let code = cid.hash().code();
+    let digest = match hashers.digest(code, data) {
+        Some(digest) => digest,
+        None => S::Hashes::try_from(code)
+            .map_err(|_| anyhow::Error::new(UnsupportedMultihash(code)))?
+            .digest(data)
+            .digest()
+            .to_vec(),
+    };
+    // the digest in the cid may be truncated
+    let expected = cid.hash().digest();
+    if expected.is_empty() || !digest.starts_with(expected) {
+        return Err(anyhow::Error::new(InvalidMultihash(digest)).into());
+    }
+    Ok(())
+}
+
 impl<'a, S> Transaction<'a, S>
 where
     S: StoreParams,
     Ipld: References<S::Codecs>,
 {
     pub(crate) fn new(owner: &'a mut BlockStore<S>) -> Self {
+        let tag = owner.tag.clone().map(|tag| (tag, owner.tag_stats.clone()));
+        let verify_hashes = owner.verify_hashes();
         Self {
             inner: &mut owner.conn,
             info: TransactionInfo {
@@ -53,8 +203,18 @@ where
                 accessed: Vec::new(),
                 committed: false,
                 tracker: owner.config.cache_tracker.clone(),
+                tag,
+                events: owner.events.clone(),
+                counters: owner.counters.clone(),
+                alias_events: Vec::new(),
+                missing_cache: owner.missing_cache.clone(),
             },
             expired_temp_pins: owner.expired_temp_pins.clone(),
+            verify_hashes,
+            link_multiplicity: owner.config.link_multiplicity,
+            session: owner.session,
+            block_cache: owner.block_cache.clone(),
+            ids: IdCache::default(),
             _s: PhantomData,
         }
     }
@@ -65,14 +225,156 @@ where
         name: impl Into<Cow<'b, [u8]>>,
         link: Option<&'b Cid>,
     ) -> Result<()> {
+        let name = name.into().into_owned();
+        let event = alias_event(&name, link);
         let link: Option<CidBytes> = link.map(CidBytes::try_from).transpose()?;
+        in_txn(self.inner, None, true, move |txn| {
+            alias(txn, name.as_ref(), link.as_ref(), None)
+        })?;
+        self.info.events.emit(Some(event));
+        Ok(())
+    }
+
+    /// Set an alias with the given pin mode
+    pub fn alias_with_mode<'b>(
+        &mut self,
+        name: impl Into<Cow<'b, [u8]>>,
+        link: &'b Cid,
+        mode: PinMode,
+    ) -> Result<()> {
         let name = name.into().into_owned();
+        let event = StoreEvent::AliasSet(name.clone(), *link);
+        let link = CidBytes::try_from(link)?;
         in_txn(self.inner, None, true, move |txn| {
-            alias(txn, name.as_ref(), link.as_ref())
+            alias(txn, name.as_ref(), Some(&link), Some(mode))
         })?;
+        self.info.events.emit(Some(event));
         Ok(())
     }
 
+    /// Replace a temp pin with an alias on `root`
+    ///
+    /// The alias is set and the temp pin emptied atomically, so there is no window in which gc
+    /// could collect blocks protected by the temp pin. Only blocks reachable from `root` remain
+    /// protected afterwards.
+    pub fn promote_temp_pin<'b>(
+        &mut self,
+        pin: &mut TempPin,
+        name: impl Into<Cow<'b, [u8]>>,
+        root: &'b Cid,
+    ) -> Result<()> {
+        let name = name.into().into_owned();
+        let event = StoreEvent::AliasSet(name.clone(), *root);
+        let root = CidBytes::try_from(root)?;
+        let id = pin.id;
+        in_txn(self.inner, None, true, move |txn| {
+            alias(txn, name.as_ref(), Some(&root), None)?;
+            if id > 0 {
+                delete_temp_pin(txn, id)?;
+            }
+            Ok(())
+        })?;
+        pin.id = 0;
+        self.info.events.emit(Some(event));
+        Ok(())
+    }
+
+    /// Set or delete an alias, returning the cid it previously pointed to
+    ///
+    /// Reading the old value and writing the new one happen atomically, so this can be used to
+    /// detect concurrent updates of mutable pointers.
+    pub fn update_alias<'b>(
+        &mut self,
+        name: impl Into<Cow<'b, [u8]>>,
+        link: Option<&'b Cid>,
+    ) -> Result<Option<Cid>> {
+        let name = name.into().into_owned();
+        let event = alias_event(&name, link);
+        let link: Option<CidBytes> = link.map(CidBytes::try_from).transpose()?;
+        let old = in_txn(self.inner, None, true, move |txn| {
+            update_alias(txn, name.as_ref(), link.as_ref())
+        })?;
+        self.info.events.emit(Some(event));
+        Ok(old.as_ref().map(Cid::try_from).transpose()?)
+    }
+
+    /// Get the number and total size of the blocks pinned by an alias
+    pub fn alias_stats<'b>(&mut self, name: impl Into<Cow<'b, [u8]>>) -> Result<Option<DagStats>> {
+        let name = name.into().into_owned();
+        in_txn(self.inner, None, false, move |txn| {
+            alias_dag_stats(txn, &name)
+        })
+    }
+
+    /// Get the root, timestamps and metadata of an alias
+    pub fn alias_info<'b>(&mut self, name: impl Into<Cow<'b, [u8]>>) -> Result<Option<AliasInfo>> {
+        let name = name.into().into_owned();
+        let row = in_txn(self.inner, None, false, move |txn| {
+            alias_info::<CidBytes>(txn, &name)
+        })?;
+        let time = |secs: Option<i64>| {
+            secs.and_then(|s| u64::try_from(s).ok())
+                .map(|s| UNIX_EPOCH + Duration::from_secs(s))
+        };
+        row.map(|(cid, created, updated, metadata, max_depth)| {
+            Ok(AliasInfo {
+                cid: Cid::try_from(&cid)?,
+                created: time(created),
+                updated: time(updated),
+                metadata,
+                mode: match max_depth {
+                    None => PinMode::Recursive,
+                    Some(0) => PinMode::Direct,
+                    Some(depth) => PinMode::Depth(depth),
+                },
+            })
+        })
+        .transpose()
+    }
+
+    /// Attach application defined metadata to an existing alias
+    ///
+    /// Returns false if there is no such alias.
+    pub fn set_alias_metadata<'b>(
+        &mut self,
+        name: impl Into<Cow<'b, [u8]>>,
+        metadata: Option<&[u8]>,
+    ) -> Result<bool> {
+        let name = name.into().into_owned();
+        let metadata = metadata.map(<[u8]>::to_vec);
+        in_txn(self.inner, None, true, move |txn| {
+            set_alias_metadata(txn, &name, metadata.as_deref())
+        })
+    }
+
+    /// Atomically move an alias to a new name
+    ///
+    /// Fails with [`AliasExists`](crate::BlockStoreError::AliasExists) if `new` is already taken.
+    /// Returns false if there is no alias named `old`.
+    pub fn rename_alias<'b>(
+        &mut self,
+        old: impl Into<Cow<'b, [u8]>>,
+        new: impl Into<Cow<'b, [u8]>>,
+    ) -> Result<bool> {
+        let old = old.into().into_owned();
+        let new = new.into().into_owned();
+        let (renamed, root) = in_txn(self.inner, None, true, {
+            let (old, new) = (old.clone(), new.clone());
+            move |txn| {
+                let root = resolve::<CidBytes>(txn, &old)?;
+                Ok((rename_alias(txn, &old, &new)?, root))
+            }
+        })?;
+        if let (true, Some(root)) = (renamed, root) {
+            let root = Cid::try_from(&root)?;
+            self.info.events.emit(vec![
+                StoreEvent::AliasRemoved(old),
+                StoreEvent::AliasSet(new, root),
+            ]);
+        }
+        Ok(renamed)
+    }
+
     /// Returns the aliases referencing a cid.
     pub fn reverse_alias(&mut self, cid: &Cid) -> Result<Option<HashSet<Vec<u8>>>> {
         let cid = CidBytes::try_from(cid)?;
@@ -101,12 +403,44 @@ where
     pub fn extend_temp_pin(&mut self, pin: &mut TempPin, link: &Cid) -> Result<()> {
         let link = CidBytes::try_from(link)?;
         let id = pin.id;
+        let session = self.session;
         pin.id = in_txn(self.inner, None, true, move |txn| {
-            extend_temp_pin(txn, id, vec![link])
+            let new_id = extend_temp_pin(txn, id, vec![link])?;
+            if id == 0 {
+                set_temp_pin_session(txn, new_id, session)?;
+            }
+            Ok(new_id)
         })?;
         Ok(())
     }
 
+    /// Extend temp pin with several cids at once
+    ///
+    /// The cids don't need to be in the store yet, so this can protect blocks that are about to
+    /// be fetched.
+    pub fn extend_temp_pin_many(&mut self, pin: &mut TempPin, links: &[Cid]) -> Result<()> {
+        let links = links
+            .iter()
+            .map(CidBytes::try_from)
+            .collect::<cid::Result<Vec<_>>>()?;
+        let id = pin.id;
+        let session = self.session;
+        pin.id = in_txn(self.inner, None, true, move |txn| {
+            let new_id = extend_temp_pin(txn, id, links.clone())?;
+            if id == 0 && new_id > 0 {
+                set_temp_pin_session(txn, new_id, session)?;
+            }
+            Ok(new_id)
+        })?;
+        Ok(())
+    }
+
+    /// List the ids of all temp pins together with the number of blocks they pin
+    pub fn temp_pins<C: FromIterator<(i64, u64)>>(&mut self) -> Result<C> {
+        let res = in_txn(self.inner, None, false, temp_pins)?;
+        Ok(res.into_iter().collect())
+    }
+
     /// Checks if the store knows about the cid.
     ///
     /// Note that this does not necessarily mean that the store has the data for the cid.
@@ -117,8 +451,19 @@ where
 
     /// Checks if the store has the data for a cid
     pub fn has_block(&mut self, cid: &Cid) -> Result<bool> {
-        let cid = CidBytes::try_from(cid)?;
-        in_txn(self.inner, None, false, move |txn| has_block(txn, cid))
+        if self.info.missing_cache.contains(cid) {
+            AtomicCounters::add(&self.info.counters.missing_cache_hits, 1);
+            return Ok(false);
+        }
+        let generation = self.info.missing_cache.generation();
+        let cid_bytes = CidBytes::try_from(cid)?;
+        let res = in_txn(self.inner, None, false, move |txn| {
+            has_block(txn, cid_bytes)
+        })?;
+        if !res {
+            self.info.missing_cache.insert(*cid, generation);
+        }
+        Ok(res)
     }
 
     /// Get all cids that the store knows about
@@ -149,6 +494,258 @@ where
         Ok(res)
     }
 
+    /// Get descendants of a cid with their depth below it, in the given traversal order
+    ///
+    /// The children of a block are visited in the order in which they are linked from its data.
+    pub fn get_descendants_ordered<C: FromIterator<(Cid, u32)>>(
+        &mut self,
+        cid: &Cid,
+        order: Traversal,
+    ) -> Result<C> {
+        let root = *cid;
+        let res = in_txn(self.inner, None, false, move |txn| {
+            let mut res = Vec::new();
+            let mut visited = FnvHashSet::default();
+            let mut pending = VecDeque::new();
+            pending.push_back((root, 0u32));
+            while let Some((cid, depth)) = match order {
+                Traversal::BreadthFirst => pending.pop_front(),
+                Traversal::DepthFirst => pending.pop_back(),
+            } {
+                if !visited.insert(cid) {
+                    continue;
+                }
+                res.push((cid, depth));
+                let data = match get_block(txn, CidBytes::try_from(&cid)?)? {
+                    Some((_id, data)) => data,
+                    None => continue,
+                };
+                let mut links = Vec::new();
+                Block::<S>::new_unchecked(cid, data).references(&mut links)?;
+                let links = links.into_iter().filter(|link| !visited.contains(link));
+                match order {
+                    Traversal::BreadthFirst => pending.extend(links.map(|link| (link, depth + 1))),
+                    // reversed, so that the first link is popped first
+                    Traversal::DepthFirst => {
+                        let start = pending.len();
+                        pending.extend(links.map(|link| (link, depth + 1)));
+                        pending.make_contiguous()[start..].reverse();
+                    }
+                }
+            }
+            Ok(res)
+        })?;
+        Ok(res.into_iter().collect())
+    }
+
+    /// Get the blocks that link directly to a cid
+    pub fn get_referrers<C: FromIterator<Cid>>(&mut self, cid: &Cid) -> Result<C> {
+        let cid = CidBytes::try_from(cid)?;
+        let res = in_txn(self.inner, None, false, move |txn| get_referrers(txn, cid))?;
+        let res = res.iter().map(Cid::try_from).collect::<cid::Result<C>>()?;
+        Ok(res)
+    }
+
+    /// Get all blocks from which a cid is reachable, not including the cid itself
+    pub fn get_ancestors<C: FromIterator<Cid>>(&mut self, cid: &Cid) -> Result<C> {
+        let cid = CidBytes::try_from(cid)?;
+        let res = in_txn(self.inner, None, false, move |txn| get_ancestors(txn, cid))?;
+        let res = res.iter().map(Cid::try_from).collect::<cid::Result<C>>()?;
+        Ok(res)
+    }
+
+    /// Get the direct children of a block
+    ///
+    /// Returns `None` if the store does not have the data for this cid.
+    pub fn get_links<C: FromIterator<Cid>>(
+        &mut self,
+        cid: &Cid,
+        mode: LinkMode,
+    ) -> Result<Option<C>> {
+        let cid = CidBytes::try_from(cid)?;
+        let res = in_txn(self.inner, None, false, move |txn| {
+            get_links_counted(txn, cid)
+        })?;
+        res.map(|links| {
+            let mut res = Vec::with_capacity(links.len());
+            for (link, count) in links {
+                let link = Cid::try_from(&link)?;
+                let n = if mode == LinkMode::Multiset { count } else { 1 };
+                res.extend(std::iter::repeat(link).take(n as usize));
+            }
+            Ok(res.into_iter().collect())
+        })
+        .transpose()
+    }
+
+    /// Get all cids that the store knows about, up to a limit
+    pub fn get_known_cids_limited<C: FromIterator<Cid>>(
+        &mut self,
+        limit: Limit,
+    ) -> Result<Limited<C>> {
+        let (res, truncated) = in_txn(self.inner, None, false, move |txn| {
+            get_known_cids_limited::<CidBytes>(txn, limit)
+        })?;
+        let res = res.iter().map(Cid::try_from).collect::<cid::Result<C>>()?;
+        Ok(Limited::new(res, truncated))
+    }
+
+    /// Get `limit` of the cids that the store knows about, starting at `cursor`
+    ///
+    /// A `limit` of 0 returns no cids, but still tells whether there are more after `cursor`.
+    pub fn get_known_cids_page<C: FromIterator<Cid>>(
+        &mut self,
+        cursor: u64,
+        limit: usize,
+    ) -> Result<Page<C>> {
+        let after = i64::try_from(cursor).unwrap_or(i64::MAX);
+        // one more than requested, to tell whether there is a next page
+        let mut res = in_txn(self.inner, None, false, move |txn| {
+            get_known_cids_page::<CidBytes>(txn, after, limit.saturating_add(1))
+        })?;
+        let next = if res.len() > limit {
+            res.truncate(limit);
+            // an empty page with more to come continues where it started
+            Some(res.last().map_or(cursor, |(id, _)| *id as u64))
+        } else {
+            None
+        };
+        let items = res
+            .iter()
+            .map(|(_, cid)| Cid::try_from(cid))
+            .collect::<cid::Result<C>>()?;
+        Ok(Page::new(items, next))
+    }
+
+    /// Get the cids whose binary form starts with `prefix`, up to a limit
+    pub fn find_cids_with_prefix<C: FromIterator<Cid>>(
+        &mut self,
+        prefix: &[u8],
+        limit: Limit,
+    ) -> Result<Limited<C>> {
+        let prefix = prefix.to_vec();
+        let (res, truncated) = in_txn(self.inner, None, false, move |txn| {
+            find_cids_with_prefix::<CidBytes>(txn, &prefix, limit)
+        })?;
+        let res = res.iter().map(Cid::try_from).collect::<cid::Result<C>>()?;
+        Ok(Limited::new(res, truncated))
+    }
+
+    /// Get all cids for which the store has blocks, up to a limit
+    pub fn get_block_cids_limited<C: FromIterator<Cid>>(
+        &mut self,
+        limit: Limit,
+    ) -> Result<Limited<C>> {
+        let (res, truncated) = in_txn(self.inner, None, false, move |txn| {
+            get_block_cids_limited::<CidBytes>(txn, limit)
+        })?;
+        let res = res.iter().map(Cid::try_from).collect::<cid::Result<C>>()?;
+        Ok(Limited::new(res, truncated))
+    }
+
+    /// Get descendants of a cid, up to a limit
+    pub fn get_descendants_limited<C: FromIterator<Cid>>(
+        &mut self,
+        cid: &Cid,
+        limit: Limit,
+    ) -> Result<Limited<C>> {
+        let cid = CidBytes::try_from(cid)?;
+        let (res, truncated) = in_txn(self.inner, None, false, move |txn| {
+            get_descendants_limited(txn, cid, limit)
+        })?;
+        let res = res.iter().map(Cid::try_from).collect::<cid::Result<C>>()?;
+        Ok(Limited::new(res, truncated))
+    }
+
+    /// Given a root of a dag, gives the cids which we do not have data for, up to a limit
+    pub fn get_missing_blocks_limited<C: FromIterator<Cid>>(
+        &mut self,
+        cid: &Cid,
+        limit: Limit,
+    ) -> Result<Limited<C>> {
+        let cid = CidBytes::try_from(cid)?;
+        let (res, truncated) = in_txn(self.inner, None, false, move |txn| {
+            get_missing_blocks_limited(txn, cid, limit)
+        })?;
+        let res = res.iter().map(Cid::try_from).collect::<cid::Result<C>>()?;
+        Ok(Limited::new(res, truncated))
+    }
+
+    /// Given a root of a dag, gives the cids up to `max_depth` links below it which we do not
+    /// have data for
+    pub fn get_missing_blocks_to_depth<C: FromIterator<Cid>>(
+        &mut self,
+        cid: &Cid,
+        max_depth: u32,
+    ) -> Result<C> {
+        let cid = CidBytes::try_from(cid)?;
+        let res = in_txn(self.inner, None, false, move |txn| {
+            get_missing_blocks_to_depth(txn, cid, max_depth)
+        })?;
+        let res = res.iter().map(Cid::try_from).collect::<cid::Result<C>>()?;
+        Ok(res)
+    }
+
+    /// Given several roots, gives all cids reachable from any of them which we do not have data
+    /// for
+    pub fn get_missing_blocks_many<C: FromIterator<Cid>>(&mut self, cids: &[Cid]) -> Result<C> {
+        let cids = cids
+            .iter()
+            .map(CidBytes::try_from)
+            .collect::<cid::Result<Vec<_>>>()?;
+        let res = in_txn(self.inner, None, false, move |txn| {
+            get_missing_blocks_many(txn, cids.iter().copied())
+        })?;
+        let res = res.iter().map(Cid::try_from).collect::<cid::Result<C>>()?;
+        Ok(res)
+    }
+
+    /// Given several roots, gives the cids reachable from them which we do not have data for,
+    /// in the order they should be fetched
+    pub fn wanted_blocks<C: FromIterator<Cid>>(&mut self, roots: &[Cid]) -> Result<C> {
+        let roots = roots
+            .iter()
+            .map(CidBytes::try_from)
+            .collect::<cid::Result<Vec<_>>>()?;
+        let res = in_txn(self.inner, None, false, move |txn| {
+            wanted_blocks(txn, roots.iter().copied())
+        })?;
+        let res = res.iter().map(Cid::try_from).collect::<cid::Result<C>>()?;
+        Ok(res)
+    }
+
+    /// Given a root of a dag, gives the next `limit` cids which we do not have data for
+    ///
+    /// The cids are ordered, and only those after `after` are returned.
+    pub fn get_missing_blocks_chunked<C: FromIterator<Cid>>(
+        &mut self,
+        cid: &Cid,
+        limit: usize,
+        after: Option<&Cid>,
+    ) -> Result<C> {
+        let cid = CidBytes::try_from(cid)?;
+        let after = after.map(CidBytes::try_from).transpose()?;
+        let res = in_txn(self.inner, None, false, move |txn| {
+            get_missing_blocks_chunked(txn, cid, limit, after)
+        })?;
+        let res = res.iter().map(Cid::try_from).collect::<cid::Result<C>>()?;
+        Ok(res)
+    }
+
+    /// Count the cids of a dag which we do not have data for
+    pub fn missing_block_count(&mut self, cid: &Cid) -> Result<u64> {
+        let cid = CidBytes::try_from(cid)?;
+        in_txn(self.inner, None, false, move |txn| {
+            count_missing_blocks(txn, cid)
+        })
+    }
+
+    /// Check whether the store has the data for all blocks of a dag
+    pub fn is_complete(&mut self, cid: &Cid) -> Result<bool> {
+        let cid = CidBytes::try_from(cid)?;
+        in_txn(self.inner, None, false, move |txn| is_complete(txn, cid))
+    }
+
     /// Given a root of a dag, gives all cids which we do not have data for.
     pub fn get_missing_blocks<C: FromIterator<Cid>>(&mut self, cid: &Cid) -> Result<C> {
         let cid = CidBytes::try_from(cid)?;
@@ -162,10 +759,9 @@ where
         Ok(res)
     }
 
-    /// list all aliases
+    /// list all aliases, ordered by name
     pub fn aliases<C: FromIterator<(Vec<u8>, Cid)>>(&mut self) -> Result<C> {
-        let result: Vec<(Vec<u8>, CidBytes)> =
-            in_txn(self.inner, None, false, move |txn| aliases(txn))?;
+        let result: Vec<(Vec<u8>, CidBytes)> = in_txn(self.inner, None, false, aliases)?;
         let res = result
             .into_iter()
             .map(|(alias, cid)| {
@@ -178,42 +774,420 @@ where
 
     /// Put a block. This will only be completed once the transaction is successfully committed
     pub fn put_block(&mut self, block: Block<S>, pin: Option<&mut TempPin>) -> Result<()> {
-        let cid_bytes = CidBytes::try_from(block.cid())?;
-        let mut links = Vec::new();
-        block.references(&mut links)?;
-        let links = links
-            .iter()
-            .map(CidBytes::try_from)
-            .collect::<std::result::Result<FnvHashSet<_>, cid::Error>>()?;
+        let (cid_bytes, links) = self.prepare_block(&block)?;
         let id = pin.as_ref().map(|p| p.id);
         let cid = *block.cid();
         let len = block.data().len();
-        let (opt_id, res) = in_txn(self.inner, None, true, move |txn| {
-            put_block(txn, &cid_bytes, block.data(), links.iter().copied(), id)
+        let _span = tracing::debug_span!("put_block", %cid, size = len).entered();
+        let session = self.session;
+        let ids = self.ids.clone();
+        let (opt_id, res, ids) = in_txn(self.inner, None, true, move |txn| {
+            let mut ids = ids.begin(txn)?;
+            let (opt_id, res) = put_block(
+                txn,
+                &mut ids,
+                &cid_bytes,
+                block.cid(),
+                block.data(),
+                links.iter().copied(),
+                id,
+            )?;
+            if let (Some(0), Some(new_id)) = (id, opt_id) {
+                set_temp_pin_session(txn, new_id, session)?;
+            }
+            Ok((opt_id, res, ids))
         })?;
+        self.ids.update(ids);
+        // the block is visible to other connections as soon as in_txn returns
+        self.info.missing_cache.remove(std::iter::once(&cid));
         if let (Some(id), Some(pin)) = (opt_id, pin) {
             pin.id = id;
         }
+        tracing::trace!(exists = res.block_exists, "block put");
         let write_info = WriteInfo::new(BlockInfo::new(res.id, &cid, len), res.block_exists);
         self.info.written.push(write_info);
         Ok(())
     }
 
+    /// Put several blocks in a single transaction
+    ///
+    /// If anything fails, none of the blocks are written. If a temp pin is given, all blocks are
+    /// added to it, so a dag can be written in several batches without gc collecting the parts
+    /// written so far.
+    pub fn put_blocks<I>(&mut self, blocks: I, pin: Option<&mut TempPin>) -> Result<()>
+    where
+        I: IntoIterator<Item = Block<S>>,
+    {
+        self.put_all(blocks, pin, None)
+    }
+
+    /// Add a batch of blocks and set an alias on `root`
+    ///
+    /// Blocks and alias are written in a single transaction, so the new DAG is never visible
+    /// without being pinned. If anything fails, nothing is written.
+    pub fn add_tree<'b, I>(
+        &mut self,
+        name: impl Into<Cow<'b, [u8]>>,
+        root: &'b Cid,
+        blocks: I,
+    ) -> Result<()>
+    where
+        I: IntoIterator<Item = Block<S>>,
+    {
+        let name = name.into().into_owned();
+        let event = StoreEvent::AliasSet(name.clone(), *root);
+        let root = CidBytes::try_from(root)?;
+        self.put_all(blocks, None, Some((name, root)))?;
+        self.info.alias_events.push(event);
+        Ok(())
+    }
+
+    fn put_all<I>(
+        &mut self,
+        blocks: I,
+        pin: Option<&mut TempPin>,
+        alias_to: Option<(Vec<u8>, CidBytes)>,
+    ) -> Result<()>
+    where
+        I: IntoIterator<Item = Block<S>>,
+    {
+        let blocks = blocks
+            .into_iter()
+            .map(|block| {
+                let (cid_bytes, links) = self.prepare_block(&block)?;
+                Ok((block, cid_bytes, links))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let sizes = blocks
+            .iter()
+            .map(|(block, _, _)| (*block.cid(), block.data().len()))
+            .collect::<Vec<_>>();
+        let id = pin.as_ref().map(|p| p.id);
+        let session = self.session;
+        let ids = self.ids.clone();
+        let (opt_id, results, ids) = in_txn(self.inner, None, true, move |txn| {
+            let mut ids = ids.begin(txn)?;
+            let mut opt_id = id;
+            let mut results = Vec::with_capacity(blocks.len());
+            for (block, cid_bytes, links) in &blocks {
+                let (new_id, res) = put_block(
+                    txn,
+                    &mut ids,
+                    cid_bytes,
+                    block.cid(),
+                    block.data(),
+                    links.iter().copied(),
+                    opt_id,
+                )?;
+                opt_id = new_id;
+                results.push(res);
+            }
+            if let (Some(0), Some(new_id)) = (id, opt_id) {
+                set_temp_pin_session(txn, new_id, session)?;
+            }
+            if let Some((name, root)) = &alias_to {
+                alias(txn, name.as_ref(), Some(root), None)?;
+            }
+            Ok((opt_id, results, ids))
+        })?;
+        self.ids.update(ids);
+        self.info
+            .missing_cache
+            .remove(sizes.iter().map(|(cid, _)| cid));
+        if let (Some(id), Some(pin)) = (opt_id, pin) {
+            pin.id = id;
+        }
+        for ((cid, len), res) in sizes.iter().zip(results) {
+            let info = BlockInfo::new(res.id, cid, *len);
+            self.info
+                .written
+                .push(WriteInfo::new(info, res.block_exists));
+        }
+        Ok(())
+    }
+
+    fn prepare_block(&self, block: &Block<S>) -> Result<(CidBytes, Vec<(CidBytes, u32)>)> {
+        prepare_block(block, self.verify_hashes.as_ref(), self.link_multiplicity)
+    }
+
+    /// Replace the links stored for a block
+    ///
+    /// Normally the links are decoded from the block data when it is put, so this is only needed
+    /// to repair blocks that were stored with wrong links, e.g. by an older version with a
+    /// buggy codec. Gc relies on the links, so passing incomplete links here makes gc collect
+    /// blocks that are still reachable from the data. Returns false if the store does not have
+    /// the data for this cid.
+    pub fn set_links(&mut self, cid: &Cid, links: &[Cid]) -> Result<bool> {
+        let cid = CidBytes::try_from(cid)?;
+        let links = count_links(links, self.link_multiplicity)?;
+        in_txn(self.inner, None, true, move |txn| {
+            set_links(txn, &cid, links.iter().copied())
+        })
+    }
+
+    /// Record the links of a block whose data is not here yet
+    ///
+    /// This lets [`get_missing_blocks`](Self::get_missing_blocks) look past blocks that have
+    /// not been fetched, e.g. when a sync protocol learns the shape of a dag from a manifest.
+    /// Once the data is put, the links decoded from it replace the recorded ones. Recorded links
+    /// do not protect anything from gc, and they are dropped along with the cid unless it is
+    /// pinned. Returns false, without recording anything, if the store already has the data.
+    pub fn add_links_only(&mut self, cid: &Cid, links: &[Cid]) -> Result<bool> {
+        let cid = CidBytes::try_from(cid)?;
+        let links = links
+            .iter()
+            .map(CidBytes::try_from)
+            .collect::<cid::Result<Vec<_>>>()?;
+        in_txn(self.inner, None, true, move |txn| {
+            add_links_only(txn, &cid, links.iter().copied())
+        })
+    }
+
+    /// Decode the links of a stored block again and replace the stored links with them
+    ///
+    /// See [`verify_links`](Self::verify_links) for finding blocks that need this. Returns false
+    /// if the store does not have the data for this cid.
+    pub fn reindex_links(&mut self, cid: &Cid) -> Result<bool> {
+        let cid_bytes = CidBytes::try_from(cid)?;
+        let data = in_txn(self.inner, None, false, move |txn| {
+            Ok(get_block(txn, cid_bytes)?.map(|(_id, data)| data))
+        })?;
+        let block = match data {
+            Some(data) => Block::<S>::new_unchecked(*cid, data),
+            None => return Ok(false),
+        };
+        let mut links = Vec::new();
+        block.references(&mut links)?;
+        self.set_links(cid, &links)
+    }
+
     /// Get a block
     pub fn get_block(&mut self, cid: &Cid) -> Result<Option<Vec<u8>>> {
+        let _span = tracing::trace_span!("get_block", %cid).entered();
+        if let Some((id, data)) = self.block_cache.get(cid) {
+            AtomicCounters::add(&self.info.counters.block_cache_hits, 1);
+            self.info.accessed.push(BlockInfo::new(id, cid, data.len()));
+            return Ok(Some(data.to_vec()));
+        }
         let cid1 = *cid;
+        let generation = self.block_cache.generation();
         let response = in_txn(self.inner, None, false, move |txn| {
-            get_block(txn, &CidBytes::try_from(&cid1)?)
+            get_block(txn, CidBytes::try_from(&cid1)?)
         })?;
+        if let Some((id, data)) = &response {
+            self.block_cache.insert(*cid, *id, data, generation);
+        }
+        tracing::trace!(
+            size = response.as_ref().map(|(_, data)| data.len()),
+            "block read"
+        );
         if let Some(info) = response
             .as_ref()
             .map(|(id, data)| BlockInfo::new(*id, cid, data.len()))
         {
             self.info.accessed.push(info);
+        } else {
+            AtomicCounters::add(&self.info.counters.read_misses, 1);
         }
         Ok(response.map(|(_id, data)| data))
     }
 
+    /// Get the size and number of links of a block without reading its data
+    pub fn block_stat(&mut self, cid: &Cid) -> Result<Option<BlockStat>> {
+        let cid = CidBytes::try_from(cid)?;
+        in_txn(self.inner, None, false, move |txn| block_stat(txn, cid))
+    }
+
+    /// Get the number and total size of the blocks reachable from a cid, including itself
+    pub fn reachable_size(&mut self, cid: &Cid) -> Result<DagStats> {
+        let cid = CidBytes::try_from(cid)?;
+        in_txn(self.inner, None, false, move |txn| dag_stats(txn, cid))
+    }
+
+    /// Get the cids of all blocks with the given codec
+    pub fn cids_by_codec<C: FromIterator<Cid>>(&mut self, codec: u64) -> Result<C> {
+        let res = in_txn(self.inner, None, false, move |txn| {
+            cids_by_codec::<CidBytes>(txn, codec)
+        })?;
+        let res = res.iter().map(Cid::try_from).collect::<cid::Result<C>>()?;
+        Ok(res)
+    }
+
+    /// Get the number and size of the blocks per codec, ordered by codec
+    pub fn codec_stats<C: FromIterator<CodecStats>>(&mut self) -> Result<C> {
+        let res = in_txn(self.inner, None, false, codec_stats)?;
+        Ok(res.into_iter().collect())
+    }
+
+    /// Get the cids and sizes of the `n` largest blocks, largest first
+    pub fn largest_blocks<C: FromIterator<(Cid, u64)>>(&mut self, n: usize) -> Result<C> {
+        let res = in_txn(self.inner, None, false, move |txn| {
+            largest_blocks::<CidBytes>(txn, n)
+        })?;
+        res.iter()
+            .map(|(cid, size)| Ok((Cid::try_from(cid)?, *size)))
+            .collect()
+    }
+
+    /// Get up to `limit` blocks in the order in which they were added, starting after `cursor`
+    pub fn get_blocks_since<C: FromIterator<(Cid, Vec<u8>)>>(
+        &mut self,
+        cursor: u64,
+        limit: usize,
+    ) -> Result<(C, u64)> {
+        let after = i64::try_from(cursor).unwrap_or(i64::MAX);
+        let res = in_txn(self.inner, None, false, move |txn| {
+            get_blocks_since::<CidBytes>(txn, after, limit)
+        })?;
+        let next = res.last().map_or(cursor, |(seq, _, _, _)| *seq as u64);
+        // a bulk scan, so the blocks are not reported as accessed
+        let blocks = res
+            .into_iter()
+            .map(|(_, _, cid, data)| Ok((Cid::try_from(&cid)?, data)))
+            .collect::<Result<C>>()?;
+        Ok((blocks, next))
+    }
+
+    /// Get up to `limit` entries of the change log with a sequence number greater than `seq`
+    pub fn changes_since<C: FromIterator<Change>>(&mut self, seq: u64, limit: usize) -> Result<C> {
+        let after = i64::try_from(seq).unwrap_or(i64::MAX);
+        let res = in_txn(self.inner, None, false, move |txn| {
+            changes_since::<CidBytes>(txn, after, limit)
+        })?;
+        res.into_iter()
+            .map(|(seq, op, cid, name, time)| {
+                let cid = cid.as_ref().map(Cid::try_from).transpose()?;
+                let event = match (op, cid, name) {
+                    (CHANGE_PUT, Some(cid), _) => StoreEvent::BlockAdded(cid),
+                    (CHANGE_DELETE, Some(cid), _) => StoreEvent::BlockRemoved(cid),
+                    (CHANGE_ALIAS_SET, Some(cid), Some(name)) => StoreEvent::AliasSet(name, cid),
+                    (CHANGE_ALIAS_REMOVED, _, Some(name)) => StoreEvent::AliasRemoved(name),
+                    _ => {
+                        return Err(BlockStoreError::Other(anyhow::anyhow!(
+                            "invalid change log entry {}",
+                            seq
+                        )))
+                    }
+                };
+                Ok(Change {
+                    seq: seq as u64,
+                    time: UNIX_EPOCH + Duration::from_secs(time as u64),
+                    event,
+                })
+            })
+            .collect()
+    }
+
+    /// Delete the entries of the change log with a sequence number less than `seq`
+    pub fn truncate_changes(&mut self, seq: u64) -> Result<usize> {
+        let before = i64::try_from(seq).unwrap_or(i64::MAX);
+        in_txn(self.inner, None, true, move |txn| {
+            truncate_changes(txn, before)
+        })
+    }
+
+    /// Get a batch of blocks with an id greater than `after`, see [`BlockIter`](crate::BlockIter)
+    pub(crate) fn get_blocks_after(
+        &mut self,
+        after: i64,
+        max_blocks: usize,
+        max_bytes: usize,
+    ) -> Result<Vec<(i64, Cid, Vec<u8>)>> {
+        let res = in_txn(self.inner, None, false, move |txn| {
+            get_blocks_page::<CidBytes>(txn, after, max_blocks, max_bytes)
+        })?;
+        // a bulk scan, so the blocks are not reported as accessed
+        res.into_iter()
+            .map(|(id, cid, data)| Ok((id, Cid::try_from(&cid)?, data)))
+            .collect()
+    }
+
+    /// Get the id of a block for reading it incrementally, see
+    /// [`open_block_reader`](crate::BlockStore::open_block_reader)
+    pub(crate) fn get_block_id(&mut self, cid: &Cid) -> Result<Option<i64>> {
+        let cid_bytes = CidBytes::try_from(cid)?;
+        let res = in_txn(self.inner, None, false, move |txn| {
+            get_block_id(txn, cid_bytes)
+        })?;
+        Ok(res.map(|(id, len)| {
+            self.info.accessed.push(BlockInfo::new(id, cid, len));
+            id
+        }))
+    }
+
+    /// Get a block by the multihash of its data, together with the cid under which it is stored
+    pub fn get_block_by_multihash(
+        &mut self,
+        multihash: &Multihash,
+    ) -> Result<Option<(Cid, Vec<u8>)>> {
+        let multihash = multihash.to_bytes();
+        let res = in_txn(self.inner, None, false, move |txn| {
+            get_block_by_multihash::<CidBytes>(txn, &multihash)
+        })?;
+        res.map(|(id, cid, data)| {
+            let cid = Cid::try_from(&cid)?;
+            self.info
+                .accessed
+                .push(BlockInfo::new(id, &cid, data.len()));
+            Ok((cid, data))
+        })
+        .transpose()
+    }
+
+    /// Get a block, writing its data into `buf`
+    ///
+    /// The contents of `buf` are replaced, so its allocation can be reused across calls.
+    /// Returns false, leaving `buf` empty, if the store does not have the data for this cid.
+    pub fn get_block_into(&mut self, cid: &Cid, buf: &mut Vec<u8>) -> Result<bool> {
+        let cid_bytes = CidBytes::try_from(cid)?;
+        // the closure must not borrow, so the buffer is lent to it
+        let cell = Rc::new(RefCell::new(mem::take(buf)));
+        let cell2 = cell.clone();
+        let res = in_txn(self.inner, None, false, move |txn| {
+            get_block_into(txn, cid_bytes, &mut cell2.borrow_mut())
+        });
+        *buf = cell.take();
+        if let Some(id) = res? {
+            self.info.accessed.push(BlockInfo::new(id, cid, buf.len()));
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    /// Check that the links stored for a block match those decoded from its data
+    ///
+    /// Returns `None` if the store does not have the data for this cid.
+    pub fn verify_links(&mut self, cid: &Cid) -> Result<Option<LinkDiff>> {
+        let cid_bytes = CidBytes::try_from(cid)?;
+        let response = in_txn(self.inner, None, false, move |txn| {
+            get_block(txn, cid_bytes)?
+                .map(|(id, data)| Ok((data, get_links::<CidBytes>(txn, id)?)))
+                .transpose()
+        })?;
+        response
+            .map(|(data, links)| link_diff::<S>(*cid, data, &links))
+            .transpose()
+    }
+
+    /// Check the links of `n` randomly chosen blocks, see [`verify_links`](Self::verify_links)
+    ///
+    /// Only the blocks with mismatching links are returned.
+    pub fn verify_links_sample<C: FromIterator<(Cid, LinkDiff)>>(&mut self, n: usize) -> Result<C> {
+        let blocks = in_txn(self.inner, None, false, move |txn| {
+            sample_blocks::<CidBytes>(txn, n)
+        })?;
+        let mut res = Vec::new();
+        for (cid, data, links) in blocks {
+            let cid = Cid::try_from(&cid)?;
+            let diff = link_diff::<S>(cid, data, &links)?;
+            if !diff.is_empty() {
+                res.push((cid, diff));
+            }
+        }
+        Ok(res.into_iter().collect())
+    }
+
     /// Get the stats for the store.
     ///
     /// The stats are kept up to date, so this is fast.
@@ -221,9 +1195,195 @@ where
         in_txn(self.inner, None, false, get_store_stats)
     }
 
+    /// Get the id generated when the store was created
+    pub fn store_id(&mut self) -> Result<String> {
+        in_txn(self.inner, None, false, store_id)
+    }
+
+    /// Get block, cid, orphan, alias and temp pin counts as of a single point in time
+    pub fn get_store_summary(&mut self) -> Result<StoreSummary> {
+        in_txn(self.inner, None, false, get_store_summary)
+    }
+
+    /// Count the cids that have neither data nor anything referencing them
+    pub fn count_orphaned(&mut self) -> Result<u64> {
+        in_txn(self.inner, None, false, count_orphaned)
+    }
+
     /// Commit and consume the transaction. Default is to not commit.
     pub fn commit(mut self) -> Result<()> {
         self.info.committed = true;
         Ok(())
     }
 }
+
+/// A write transaction whose changes become visible atomically on [`commit`](Self::commit)
+///
+/// Unlike [`Transaction`], which runs every operation in its own sqlite transaction, this holds
+/// the write lock of the database from [`write_transaction`](BlockStore::write_transaction)
+/// until it is committed or dropped, so other writers wait for it. Dropping it without
+/// committing rolls back all changes.
+pub struct WriteTransaction<'a, S> {
+    txn: rusqlite::Transaction<'a>,
+    info: TransactionInfo,
+    verify_hashes: Option<Hashers>,
+    link_multiplicity: bool,
+    // nesting depth of savepoints, used for naming them
+    savepoints: usize,
+    // the cids resolved so far, dropped when a savepoint is rolled back
+    ids: Ids,
+    _s: PhantomData<S>,
+}
+
+impl<'a, S> WriteTransaction<'a, S>
+where
+    S: StoreParams,
+    Ipld: References<S::Codecs>,
+{
+    pub(crate) fn new(owner: &'a mut BlockStore<S>) -> Result<Self> {
+        let tag = owner.tag.clone().map(|tag| (tag, owner.tag_stats.clone()));
+        let info = TransactionInfo {
+            written: Vec::new(),
+            accessed: Vec::new(),
+            committed: false,
+            tracker: owner.config.cache_tracker.clone(),
+            tag,
+            events: owner.events.clone(),
+            counters: owner.counters.clone(),
+            alias_events: Vec::new(),
+            missing_cache: owner.missing_cache.clone(),
+        };
+        let verify_hashes = owner.verify_hashes();
+        let txn = owner
+            .conn
+            .transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)
+            .ctx("beginning write transaction")?;
+        Ok(Self {
+            txn,
+            info,
+            verify_hashes,
+            link_multiplicity: owner.config.link_multiplicity,
+            savepoints: 0,
+            ids: Ids::uncached(),
+            _s: PhantomData,
+        })
+    }
+
+    /// Run `f` within a savepoint, undoing only the changes made by `f` if it fails
+    ///
+    /// The error is returned and the transaction can be continued, e.g. to skip a bad block in
+    /// a batch without losing the rest. Savepoints can be nested.
+    pub fn savepoint<T>(&mut self, f: impl FnOnce(&mut Self) -> Result<T>) -> Result<T> {
+        let name = format!("write_txn_{}", self.savepoints);
+        let written = self.info.written.len();
+        let alias_events = self.info.alias_events.len();
+        self.txn
+            .execute_batch(&format!("SAVEPOINT {}", name))
+            .ctx("creating savepoint")?;
+        self.savepoints += 1;
+        let res = f(self);
+        self.savepoints -= 1;
+        match res {
+            Ok(value) => {
+                self.txn
+                    .execute_batch(&format!("RELEASE {}", name))
+                    .ctx("releasing savepoint")?;
+                Ok(value)
+            }
+            Err(e) => {
+                self.txn
+                    .execute_batch(&format!("ROLLBACK TO {0}; RELEASE {0}", name))
+                    .ctx("rolling back to savepoint")?;
+                self.info.written.truncate(written);
+                self.info.alias_events.truncate(alias_events);
+                self.ids = Ids::uncached();
+                Err(e)
+            }
+        }
+    }
+
+    /// Put a block
+    ///
+    /// Temp pins cannot be used here, since their ids would be lost on rollback; blocks should
+    /// be pinned by setting an alias within the same transaction instead.
+    pub fn put_block(&mut self, block: Block<S>) -> Result<()> {
+        let (cid_bytes, links) =
+            prepare_block(&block, self.verify_hashes.as_ref(), self.link_multiplicity)?;
+        let (_, res) = put_block(
+            &self.txn,
+            &mut self.ids,
+            &cid_bytes,
+            block.cid(),
+            block.data(),
+            links.iter().copied(),
+            None,
+        )?;
+        let info = BlockInfo::new(res.id, block.cid(), block.data().len());
+        self.info
+            .written
+            .push(WriteInfo::new(info, res.block_exists));
+        Ok(())
+    }
+
+    /// Set or delete an alias
+    pub fn alias<'b>(
+        &mut self,
+        name: impl Into<Cow<'b, [u8]>>,
+        link: Option<&'b Cid>,
+    ) -> Result<()> {
+        let name = name.into();
+        let event = alias_event(&name, link);
+        let link: Option<CidBytes> = link.map(CidBytes::try_from).transpose()?;
+        alias(&self.txn, name.as_ref(), link.as_ref(), None)?;
+        self.info.alias_events.push(event);
+        Ok(())
+    }
+
+    /// Set an alias with the given pin mode
+    pub fn alias_with_mode<'b>(
+        &mut self,
+        name: impl Into<Cow<'b, [u8]>>,
+        link: &'b Cid,
+        mode: PinMode,
+    ) -> Result<()> {
+        let name = name.into();
+        let event = StoreEvent::AliasSet(name.to_vec(), *link);
+        let link = CidBytes::try_from(link)?;
+        alias(&self.txn, name.as_ref(), Some(&link), Some(mode))?;
+        self.info.alias_events.push(event);
+        Ok(())
+    }
+
+    /// Resolve an alias, including changes made in this transaction
+    pub fn resolve<'b>(&mut self, name: impl Into<Cow<'b, [u8]>>) -> Result<Option<Cid>> {
+        resolve::<CidBytes>(&self.txn, name.into().as_ref())?
+            .map(|c| Cid::try_from(&c))
+            .transpose()
+            .map_err(Into::into)
+    }
+
+    /// Check whether the data for a block is present, including blocks put in this transaction
+    pub fn has_block(&mut self, cid: &Cid) -> Result<bool> {
+        has_block(&self.txn, CidBytes::try_from(cid)?)
+    }
+
+    /// Get a block, including blocks put in this transaction
+    pub fn get_block(&mut self, cid: &Cid) -> Result<Option<Vec<u8>>> {
+        let response = get_block(&self.txn, CidBytes::try_from(cid)?)?;
+        if let Some((id, data)) = &response {
+            self.info
+                .accessed
+                .push(BlockInfo::new(*id, cid, data.len()));
+        } else {
+            AtomicCounters::add(&self.info.counters.read_misses, 1);
+        }
+        Ok(response.map(|(_id, data)| data))
+    }
+
+    /// Commit all changes made in this transaction
+    pub fn commit(mut self) -> Result<()> {
+        self.txn.commit().ctx("committing write transaction")?;
+        self.info.committed = true;
+        Ok(())
+    }
+}
@@ -0,0 +1,115 @@
+use crate::{BlockStore, Result, StoreEvent};
+use fnv::FnvHashSet;
+use libipld::{codec::References, store::StoreParams, Cid, Ipld};
+use std::{
+    fmt,
+    sync::mpsc::Receiver,
+    time::{Duration, Instant},
+};
+
+/// The blocks a fetcher should get, and a way to wait for them, see
+/// [`wantlist`](BlockStore::wantlist)
+///
+/// Iterating yields each wanted cid once its block has been added to the store, through any
+/// connection within this process, and ends when all wanted blocks have arrived.
+pub struct Wantlist {
+    wanted: Vec<Cid>,
+    pending: FnvHashSet<Cid>,
+    events: Receiver<StoreEvent>,
+}
+
+impl fmt::Debug for Wantlist {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Wantlist")
+            .field("wanted", &self.wanted.len())
+            .field("pending", &self.pending.len())
+            .finish()
+    }
+}
+
+impl Wantlist {
+    /// The blocks that were missing when the wantlist was created, in the order they should be
+    /// fetched, followed by those added with [`want`](Self::want)
+    pub fn wanted(&self) -> &[Cid] {
+        &self.wanted
+    }
+
+    /// Whether the block for `cid` is wanted and has not arrived yet
+    pub fn is_pending(&self, cid: &Cid) -> bool {
+        self.pending.contains(cid)
+    }
+
+    /// Number of wanted blocks that have not arrived yet
+    pub fn remaining(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Also wait for `cids`, e.g. the links of a block that has just arrived
+    ///
+    /// The caller must make sure that the blocks are not in the store already, since they
+    /// would never arrive.
+    pub fn want(&mut self, cids: impl IntoIterator<Item = Cid>) {
+        for cid in cids {
+            if self.pending.insert(cid) {
+                self.wanted.push(cid);
+            }
+        }
+    }
+
+    /// Stop waiting for `cid`, returns false if it was not pending
+    pub fn cancel(&mut self, cid: &Cid) -> bool {
+        self.pending.remove(cid)
+    }
+
+    /// Wait at most `timeout` for the next wanted block to arrive
+    ///
+    /// Returns `None` on timeout or when no blocks are pending.
+    pub fn next_timeout(&mut self, timeout: Duration) -> Option<Cid> {
+        let deadline = Instant::now() + timeout;
+        while !self.pending.is_empty() {
+            let left = deadline.saturating_duration_since(Instant::now());
+            match self.events.recv_timeout(left) {
+                Ok(StoreEvent::BlockAdded(cid)) if self.pending.remove(&cid) => return Some(cid),
+                Ok(_) => {}
+                Err(_) => return None,
+            }
+        }
+        None
+    }
+}
+
+impl Iterator for Wantlist {
+    type Item = Cid;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.pending.is_empty() {
+            match self.events.recv().ok()? {
+                StoreEvent::BlockAdded(cid) if self.pending.remove(&cid) => return Some(cid),
+                _ => {}
+            }
+        }
+        None
+    }
+}
+
+impl<S> BlockStore<S>
+where
+    S: StoreParams,
+    Ipld: References<S::Codecs>,
+{
+    /// Get the blocks missing from the dags below `roots`, and get notified when they arrive
+    ///
+    /// The wanted blocks are those of [`wanted_blocks`](Self::wanted_blocks). The subscription
+    /// is made before looking for them, so no block put meanwhile goes unnoticed. Blocks that
+    /// are only discovered to be missing once their parents have arrived are not included;
+    /// add them with [`want`](Wantlist::want).
+    pub fn wantlist(&mut self, roots: &[Cid]) -> Result<Wantlist> {
+        let events = self.subscribe();
+        let wanted: Vec<Cid> = self.wanted_blocks(roots)?;
+        Ok(Wantlist {
+            pending: wanted.iter().copied().collect(),
+            wanted,
+            events,
+        })
+    }
+}
@@ -0,0 +1,98 @@
+//! Detection of long running statements, see [`Config::with_statement_watchdog`]
+//!
+//! [`Config::with_statement_watchdog`]: crate::Config::with_statement_watchdog
+use parking_lot::Mutex;
+use rusqlite::{ffi, Connection};
+use std::{
+    ffi::CStr,
+    os::raw::{c_int, c_uint, c_void},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+struct Running {
+    stmt: usize,
+    sql: String,
+    started: Instant,
+    warned: bool,
+}
+
+/// the statements currently running on one connection
+#[derive(Clone, Default)]
+pub(crate) struct Watchdog(Arc<Mutex<Vec<Running>>>);
+
+// the statements are only ever added and removed as a whole, so a panic can't leave them in an
+// inconsistent state
+impl std::panic::UnwindSafe for Watchdog {}
+impl std::panic::RefUnwindSafe for Watchdog {}
+
+impl Watchdog {
+    /// track statement starts and ends on `conn`
+    ///
+    /// The tracking stops when the connection is closed.
+    pub(crate) fn install(conn: &mut Connection) -> Self {
+        let watchdog = Self::default();
+        let ctx = Arc::into_raw(watchdog.0.clone()) as *mut c_void;
+        let mask = (ffi::SQLITE_TRACE_STMT | ffi::SQLITE_TRACE_PROFILE | ffi::SQLITE_TRACE_CLOSE)
+            as c_uint;
+        // SAFETY: the handle is valid while `conn` is alive, and `ctx` is a leaked reference
+        // count that is released by the callback when sqlite closes the connection
+        unsafe {
+            ffi::sqlite3_trace_v2(conn.handle(), mask, Some(on_trace), ctx);
+        }
+        watchdog
+    }
+
+    /// called from the progress handler, returns whether the running statement should be
+    /// interrupted
+    pub(crate) fn check(&self, threshold: Duration, kill: bool) -> bool {
+        let mut running = self.0.lock();
+        let mut exceeded = false;
+        for running in running.iter_mut() {
+            let elapsed = running.started.elapsed();
+            if elapsed < threshold {
+                continue;
+            }
+            exceeded = true;
+            if !running.warned {
+                running.warned = true;
+                tracing::warn!(
+                    ?elapsed,
+                    kill,
+                    "statement running for longer than {:?}: {}",
+                    threshold,
+                    running.sql
+                );
+            }
+        }
+        exceeded && kill
+    }
+}
+
+unsafe extern "C" fn on_trace(
+    event: c_uint,
+    ctx: *mut c_void,
+    p: *mut c_void,
+    x: *mut c_void,
+) -> c_int {
+    let running = ctx as *const Mutex<Vec<Running>>;
+    match event as c_int {
+        ffi::SQLITE_TRACE_STMT => {
+            // x is the sql of the statement, or a comment for statements run by triggers
+            let sql = CStr::from_ptr(x as *const _).to_string_lossy();
+            let mut running = (*running).lock();
+            if !sql.starts_with("--") && !running.iter().any(|r| r.stmt == p as usize) {
+                running.push(Running {
+                    stmt: p as usize,
+                    sql: sql.into_owned(),
+                    started: Instant::now(),
+                    warned: false,
+                });
+            }
+        }
+        ffi::SQLITE_TRACE_PROFILE => (*running).lock().retain(|r| r.stmt != p as usize),
+        ffi::SQLITE_TRACE_CLOSE => drop(Arc::from_raw(running)),
+        _ => {}
+    }
+    0
+}
@@ -0,0 +1,271 @@
+use crate::{BlockStore, BlockStoreError, Result};
+use libipld::{codec::References, store::StoreParams, Block, Ipld};
+use parking_lot::Mutex;
+use std::{
+    fmt,
+    sync::{
+        mpsc::{self, Receiver, SyncSender, TrySendError},
+        Arc,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+/// the maximum number of blocks written in one transaction by the committer
+pub(crate) const MAX_BATCH: usize = 1000;
+
+/// How far a block handed to [`WriteBuffer::put_block`] has made it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Acked {
+    /// The block is in the in-memory buffer and will be lost if the process dies before the
+    /// next commit; failures to write it are reported by a later call
+    Buffered,
+    /// The block has been committed to the database
+    ///
+    /// Whether that survives a power loss depends on the [`Synchronous`](crate::Synchronous)
+    /// setting of the store.
+    Durable,
+}
+
+enum Msg<S: StoreParams> {
+    Block(Block<S>, Option<SyncSender<Result<()>>>),
+    Flush(SyncSender<Result<()>>),
+}
+
+impl<S: StoreParams> Msg<S> {
+    /// whether somebody is waiting for this message to be committed
+    fn is_waited_for(&self) -> bool {
+        matches!(self, Msg::Block(_, Some(_)) | Msg::Flush(_))
+    }
+}
+
+/// A bounded in-memory buffer for absorbing bursts of block writes
+///
+/// Blocks are written by a background thread that owns the store and commits whatever has
+/// accumulated in a single transaction. This trades durability for latency: an acknowledged
+/// [`Buffered`](Acked::Buffered) block is not yet in the database, so it is neither visible to
+/// readers nor protected by any pins. Call [`flush`](Self::flush) to wait for all preceding
+/// writes to be committed.
+///
+/// When the buffer is full, [`put_block`](Self::put_block) waits until the block has been
+/// committed, so the buffer never grows beyond its capacity.
+pub struct WriteBuffer<S: StoreParams> {
+    sender: Option<SyncSender<Msg<S>>>,
+    error: Arc<Mutex<Option<BlockStoreError>>>,
+    thread: Option<JoinHandle<BlockStore<S>>>,
+}
+
+impl<S: StoreParams> fmt::Debug for WriteBuffer<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WriteBuffer").finish()
+    }
+}
+
+impl<S> WriteBuffer<S>
+where
+    S: StoreParams,
+    Ipld: References<S::Codecs>,
+{
+    /// Start a committer thread writing to `store`, buffering up to `capacity` blocks
+    ///
+    /// For file based stores, this is typically given an
+    /// [`additional_connection`](BlockStore::additional_connection).
+    pub fn new(store: BlockStore<S>, capacity: usize) -> Self {
+        Self::with_delay(store, capacity, Duration::ZERO)
+    }
+
+    /// Like [`new`](Self::new), but let the committer wait up to `max_delay` for more blocks
+    ///
+    /// Many small writes arriving within `max_delay` are then committed in one transaction
+    /// instead of one each, saving the cost of syncing the WAL for every block. A commit
+    /// happens early when the batch is full, when the buffer is full, or on [`flush`](Self::flush).
+    pub fn with_delay(store: BlockStore<S>, capacity: usize, max_delay: Duration) -> Self {
+        let (sender, receiver) = mpsc::sync_channel(capacity);
+        let error = Arc::new(Mutex::new(None));
+        let error2 = error.clone();
+        let thread = std::thread::spawn(move || commit_loop(store, receiver, error2, max_delay));
+        Self {
+            sender: Some(sender),
+            error,
+            thread: Some(thread),
+        }
+    }
+
+    /// Enqueue a block for writing
+    ///
+    /// Fails if a previous buffered write has failed; the block is not enqueued in that case.
+    pub fn put_block(&self, block: Block<S>) -> Result<Acked> {
+        self.take_error()?;
+        match self.sender().try_send(Msg::Block(block, None)) {
+            Ok(()) => Ok(Acked::Buffered),
+            Err(TrySendError::Full(Msg::Block(block, _))) => {
+                let (ack, done) = mpsc::sync_channel(1);
+                self.sender()
+                    .send(Msg::Block(block, Some(ack)))
+                    .map_err(|_| stopped())?;
+                done.recv().map_err(|_| stopped())??;
+                Ok(Acked::Durable)
+            }
+            Err(_) => Err(stopped()),
+        }
+    }
+
+    /// Wait until all blocks enqueued so far have been committed
+    ///
+    /// Returns the first error encountered by a buffered write since the last check.
+    pub fn flush(&self) -> Result<()> {
+        let (ack, done) = mpsc::sync_channel(1);
+        self.sender().send(Msg::Flush(ack)).map_err(|_| stopped())?;
+        let res = done.recv().map_err(|_| stopped())?;
+        self.take_error()?;
+        res
+    }
+
+    /// Commit all buffered blocks, stop the committer and return the store
+    pub fn into_inner(mut self) -> Result<BlockStore<S>> {
+        self.sender = None;
+        let store = self
+            .thread
+            .take()
+            .expect("thread is only taken here or in drop")
+            .join()
+            .map_err(|_| stopped())?;
+        self.take_error()?;
+        Ok(store)
+    }
+
+    fn sender(&self) -> &SyncSender<Msg<S>> {
+        self.sender
+            .as_ref()
+            .expect("sender is only removed when consuming self")
+    }
+
+    fn take_error(&self) -> Result<()> {
+        match self.error.lock().take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<S: StoreParams> Drop for WriteBuffer<S> {
+    fn drop(&mut self) {
+        // closing the channel makes the committer write the rest and stop
+        self.sender = None;
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                tracing::error!("write buffer committer panicked");
+            }
+        }
+        if let Some(e) = self.error.lock().take() {
+            tracing::error!("buffered write failed: {:#}", e);
+        }
+    }
+}
+
+fn stopped() -> BlockStoreError {
+    BlockStoreError::Other(anyhow::anyhow!("write buffer committer has stopped"))
+}
+
+fn commit_loop<S>(
+    mut store: BlockStore<S>,
+    receiver: Receiver<Msg<S>>,
+    error: Arc<Mutex<Option<BlockStoreError>>>,
+    max_delay: Duration,
+) -> BlockStore<S>
+where
+    S: StoreParams,
+    Ipld: References<S::Codecs>,
+{
+    while let Ok(first) = receiver.recv() {
+        let deadline = Instant::now() + max_delay;
+        let mut urgent = first.is_waited_for();
+        let mut msgs = vec![first];
+        while msgs.len() < MAX_BATCH {
+            let msg = if urgent {
+                receiver.try_recv().ok()
+            } else {
+                // also ends early when all senders are gone
+                let timeout = deadline.saturating_duration_since(Instant::now());
+                receiver.recv_timeout(timeout).ok()
+            };
+            match msg {
+                Some(msg) => {
+                    urgent |= msg.is_waited_for();
+                    msgs.push(msg);
+                }
+                None => break,
+            }
+        }
+        let blocks = msgs
+            .iter()
+            .filter_map(|msg| match msg {
+                Msg::Block(block, _) => Some(block.clone()),
+                Msg::Flush(_) => None,
+            })
+            .collect();
+        tracing::debug!(msgs = msgs.len(), "committing write buffer");
+        let mut results = write_batch(&mut store, blocks).into_iter();
+        for msg in msgs {
+            match msg {
+                Msg::Block(_, ack) => {
+                    let res = results.next().expect("one result per block");
+                    match (ack, res) {
+                        (Some(ack), res) => {
+                            ack.send(res).ok();
+                        }
+                        (None, Err(e)) => {
+                            // nobody is waiting for this, so report it with the next call;
+                            // this happens before later acks, so a flush after it sees it
+                            error.lock().get_or_insert(e);
+                        }
+                        (None, Ok(())) => {}
+                    }
+                }
+                Msg::Flush(ack) => {
+                    ack.send(Ok(())).ok();
+                }
+            }
+        }
+    }
+    store
+}
+
+/// Write `blocks` in a single transaction, returning the result for each of them
+///
+/// If that fails, the blocks are written one by one instead, so that one bad block does not
+/// fail the others. Shared by the write buffer and the [`WriteHandle`](crate::WriteHandle).
+pub(crate) fn write_batch<S>(store: &mut BlockStore<S>, blocks: Vec<Block<S>>) -> Vec<Result<()>>
+where
+    S: StoreParams,
+    Ipld: References<S::Codecs>,
+{
+    match put_all(store, &blocks) {
+        Ok(()) => {
+            // the blocks are committed, so this must not fail them
+            if let Err(e) = store.maybe_truncate_wal() {
+                tracing::warn!("truncating the WAL failed: {:#}", e);
+            }
+            blocks.iter().map(|_| Ok(())).collect()
+        }
+        Err(e) => {
+            tracing::debug!("writing batch failed, retrying one by one: {:#}", e);
+            blocks
+                .into_iter()
+                .map(|block| store.put_block(block, None))
+                .collect()
+        }
+    }
+}
+
+fn put_all<S>(store: &mut BlockStore<S>, blocks: &[Block<S>]) -> Result<()>
+where
+    S: StoreParams,
+    Ipld: References<S::Codecs>,
+{
+    let mut txn = store.write_transaction()?;
+    for block in blocks {
+        txn.put_block(block.clone())?;
+    }
+    txn.commit()
+}
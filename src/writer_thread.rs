@@ -0,0 +1,154 @@
+use crate::{
+    write_buffer::{write_batch, MAX_BATCH},
+    BlockStore, BlockStoreError, Result,
+};
+use futures::channel::oneshot;
+use libipld::{codec::References, store::StoreParams, Block, Ipld};
+use std::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::mpsc::{self, Receiver, Sender},
+    task::{Context, Poll},
+};
+
+type Job<S> = Box<dyn FnOnce(&mut BlockStore<S>) + Send>;
+
+enum Command<S: StoreParams> {
+    Put(Block<S>, oneshot::Sender<Result<()>>),
+    Run(Job<S>),
+}
+
+/// A cheaply cloneable handle for sending writes to a dedicated writer thread
+///
+/// The thread owns the store and executes commands in the order in which they were sent, so
+/// writes from different handles never contend for the write lock. Consecutive
+/// [`put_block`](Self::put_block) commands are written in a single transaction.
+///
+/// The thread stops once all handles have been dropped and their commands have been executed.
+pub struct WriteHandle<S: StoreParams> {
+    sender: Sender<Command<S>>,
+}
+
+impl<S: StoreParams> Clone for WriteHandle<S> {
+    fn clone(&self) -> Self {
+        Self {
+            sender: self.sender.clone(),
+        }
+    }
+}
+
+impl<S: StoreParams> fmt::Debug for WriteHandle<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WriteHandle").finish()
+    }
+}
+
+/// The result of a command sent to the writer thread
+///
+/// This can be awaited or, outside of async code, [waited for](Self::wait).
+#[must_use = "dropping the result does not cancel the command"]
+pub struct Pending<T>(oneshot::Receiver<Result<T>>);
+
+impl<T> fmt::Debug for Pending<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Pending").finish()
+    }
+}
+
+impl<T> Pending<T> {
+    /// Block the current thread until the command has been executed
+    pub fn wait(self) -> Result<T> {
+        futures::executor::block_on(self)
+    }
+}
+
+impl<T> Future for Pending<T> {
+    type Output = Result<T>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.0)
+            .poll(cx)
+            .map(|res| res.unwrap_or_else(|_| Err(stopped())))
+    }
+}
+
+impl<S> WriteHandle<S>
+where
+    S: StoreParams,
+    Ipld: References<S::Codecs>,
+{
+    /// Start a writer thread owning `store` and return the first handle to it
+    pub fn spawn(store: BlockStore<S>) -> Self {
+        let (sender, receiver) = mpsc::channel();
+        std::thread::spawn(move || writer_loop(store, receiver));
+        Self { sender }
+    }
+
+    /// Put a block, batched with other puts that are queued at the same time
+    pub fn put_block(&self, block: Block<S>) -> Pending<()> {
+        let (ack, done) = oneshot::channel();
+        // if sending fails, ack is dropped and the result reports the stopped thread
+        self.sender.send(Command::Put(block, ack)).ok();
+        Pending(done)
+    }
+
+    /// Run `f` on the writer thread
+    ///
+    /// This allows arbitrary writes, e.g. setting an alias after putting the blocks of a dag.
+    /// `f` should not block for long, since it holds up all other writers.
+    pub fn run<T, F>(&self, f: F) -> Pending<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut BlockStore<S>) -> Result<T> + Send + 'static,
+    {
+        let (ack, done) = oneshot::channel();
+        let job: Job<S> = Box::new(move |store| {
+            ack.send(f(store)).ok();
+        });
+        self.sender.send(Command::Run(job)).ok();
+        Pending(done)
+    }
+}
+
+fn stopped() -> BlockStoreError {
+    BlockStoreError::Other(anyhow::anyhow!("writer thread has stopped"))
+}
+
+fn writer_loop<S>(mut store: BlockStore<S>, receiver: Receiver<Command<S>>)
+where
+    S: StoreParams,
+    Ipld: References<S::Codecs>,
+{
+    let mut next = receiver.recv().ok();
+    while let Some(command) = next.take() {
+        match command {
+            Command::Run(job) => job(&mut store),
+            Command::Put(block, ack) => {
+                let mut blocks = vec![block];
+                let mut acks = vec![ack];
+                while blocks.len() < MAX_BATCH {
+                    match receiver.try_recv() {
+                        Ok(Command::Put(block, ack)) => {
+                            blocks.push(block);
+                            acks.push(ack);
+                        }
+                        // executed after this batch, to keep the order
+                        Ok(command) => {
+                            next = Some(command);
+                            break;
+                        }
+                        Err(_) => break,
+                    }
+                }
+                tracing::debug!(blocks = blocks.len(), "writer thread committing puts");
+                for (ack, res) in acks.into_iter().zip(write_batch(&mut store, blocks)) {
+                    ack.send(res).ok();
+                }
+            }
+        }
+        if next.is_none() {
+            next = receiver.recv().ok();
+        }
+    }
+}
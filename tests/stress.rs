@@ -1,3 +1,5 @@
+// the DagCbor derive of Node relies on the never type falling back to ()
+#![allow(dependency_on_unit_never_type_fallback)]
 use ipfs_sqlite_block_store::{Config, DbPath};
 use itertools::Itertools;
 use libipld::{cbor::DagCborCodec, codec::Codec, Cid, DagCbor};
@@ -96,6 +98,7 @@ fn main() -> anyhow::Result<()> {
                     let mut cids = hashset! {cid};
                     cids.extend(root.links.iter().copied());
                     assert_eq!(store.get_descendants::<HashSet<_>>(&cid).unwrap(), cids);
+                    #[allow(clippy::explicit_auto_deref)]
                     for (idx, cid) in root.links.iter().enumerate() {
                         let b: Node = DagCborCodec
                             .decode(store.get_block(cid).unwrap().unwrap().as_slice())